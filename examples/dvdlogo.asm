@@ -40,30 +40,30 @@ reg xpos = x12
 reg ypos = x11
 
 // each sprite has twelve parameters
-// cursprite = SPRITES + 12*coreid
+// cursprite = SPRITES + $SPRITE.SIZE*coreid
 crid cursprite
-muli cursprite, cursprite, 12
+muli cursprite, cursprite, $SPRITE.SIZE
 addi cursprite, cursprite, SPRITES
 
 // read sprite out of vrom (ycoord >= 256)
 li x1, 256
-store x1, cursprite, 1
+store x1, cursprite[$SPRITE.SRC_Y]
 
-// cursprite[2], [6] = LOGOW
+// source and on-screen width = LOGOW
 li x1, LOGOW
-store x1, cursprite, 2
-store x1, cursprite, 6
+store x1, cursprite[$SPRITE.SRC_W]
+store x1, cursprite[$SPRITE.W]
 
-// cursprite[3], [7] = LOGOH
+// source and on-screen height = LOGOH
 li x1, LOGOH
-store x1, cursprite, 3
-store x1, cursprite, 7
+store x1, cursprite[$SPRITE.SRC_H]
+store x1, cursprite[$SPRITE.H]
 
 // fg color = coreid * 80 + 60
 crid x1
 muli x1, x1, 80
 addi x1, x1, 60
-store x1, cursprite, 8
+store x1, cursprite[$SPRITE.FG_COLOR]
 
 // dtheta = (coreid + 1) * 0.0123
 crid dtheta
@@ -81,8 +81,8 @@ muli ypos, ypos, 40
 addi ypos, ypos, 96
 subi ypos, ypos, HALFH
 
-store xpos, cursprite, 4
-store ypos, cursprite, 5
+store xpos, cursprite[$SPRITE.X]
+store ypos, cursprite[$SPRITE.Y]
 
 // yield with condition 1 means 'yield until next frame'
 yield zero, 1
@@ -0,0 +1,304 @@
+use super::{is_cycle, strongly_connected, Finding, OpGraph};
+use crate::ops::{op_name, Op};
+use crate::parser::Program;
+use crate::symbols::Symbols;
+use crate::vm::YIELD_NOW;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::vec::Vec;
+
+// Worst-case op counts between yields. Works on single ops rather than
+// blocks so a yield in the middle of a block still splits the count.
+//
+// Only `yield rd, 1` and `yield rd, 2` end a coroutine's frame: `yield rd, 0`
+// hands over to the next coroutine in the same frame, so it doesn't count.
+// A call to a routine that can yield is treated as a yield too, since
+// "jal ra, WAIT_FRAME" in a main loop is how most programs wait. That can
+// miss a hang in a routine that only yields sometimes, but flagging every
+// such loop would bury the real problems.
+//
+// Costs are None when a path can go around a loop without yielding, which
+// is also what gets reported as a spin.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopReport {
+    // label (or label+offset) of the first op in the loop
+    pub name: String,
+    pub start: usize,
+    // last pc in the loop
+    pub end: usize,
+    // whether a path can go around forever without yielding
+    pub spins: bool,
+    // worst case from one yield in the loop to the next, if it yields at all
+    pub worst: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Budget {
+    // from each entry (pc 0, spawn targets) to the first yield
+    pub entries: Vec<(usize, Option<u64>)>,
+    pub loops: Vec<LoopReport>,
+    // ops from each label until it yields, returns or halts
+    pub labels: Vec<(String, Option<u64>)>,
+}
+
+fn frame_yield(op: &Op) -> bool {
+    op_name(op.op.opcode) == Some("yield") && op.imm as i64 != YIELD_NOW
+}
+
+fn describe_pc(symbols: &Symbols, pc: usize) -> String {
+    match symbols.label_at(pc) {
+        Some((label, 0)) => label.to_string(),
+        Some((label, offset)) => format!("{}+{}", label, offset),
+        None => format!("pc {}", pc),
+    }
+}
+
+impl Budget {
+    pub fn new(program: &Program) -> Budget {
+        let ops = &program.ops;
+        let symbols = Symbols::from_program(program);
+        let OpGraph {
+            within, callees, ..
+        } = OpGraph::new(ops);
+
+        // routines that can reach a yield before returning
+        let mut graph: Vec<Vec<usize>> = within.clone();
+        for (pc, callee) in callees.iter().enumerate() {
+            graph[pc].extend(callee);
+        }
+        let may_yield = |entry: usize| {
+            let mut seen: BTreeSet<usize> = BTreeSet::new();
+            let mut todo = vec![entry];
+            while let Some(pc) = todo.pop() {
+                if seen.insert(pc) {
+                    if frame_yield(&ops[pc]) {
+                        return true;
+                    }
+                    todo.extend(graph[pc].iter().copied());
+                }
+            }
+            false
+        };
+        let yielding: BTreeSet<usize> = callees
+            .iter()
+            .flatten()
+            .copied()
+            .filter(|c| may_yield(*c))
+            .collect();
+        let cuts: Vec<bool> = (0..ops.len())
+            .map(|pc| frame_yield(&ops[pc]) || callees[pc].is_some_and(|c| yielding.contains(&c)))
+            .collect();
+
+        // the same graph, stopping at yields; callees come first in the
+        // component order so their costs are known at the call
+        let mut cut: Vec<Vec<usize>> = Vec::new();
+        for pc in 0..ops.len() {
+            let mut succs = if cuts[pc] {
+                Vec::new()
+            } else {
+                within[pc].clone()
+            };
+            succs.extend(callees[pc].filter(|_| !frame_yield(&ops[pc])));
+            cut.push(succs);
+        }
+        let mut cost: Vec<Option<u64>> = vec![None; ops.len()];
+        let mut spinning = vec![false; ops.len()];
+        for component in strongly_connected(&cut) {
+            if is_cycle(&component, &cut) {
+                for pc in component {
+                    spinning[pc] = true;
+                }
+                continue;
+            }
+            let pc = component[0];
+            let call = match callees[pc] {
+                Some(callee) if !frame_yield(&ops[pc]) => cost[callee],
+                _ => Some(0),
+            };
+            let rest = if cuts[pc] {
+                Some(0)
+            } else {
+                within[pc]
+                    .iter()
+                    .try_fold(0, |worst: u64, succ| cost[*succ].map(|c| worst.max(c)))
+            };
+            cost[pc] = call.zip(rest).map(|(call, rest)| 1 + call + rest);
+        }
+
+        let entries = OpGraph::entries(ops);
+
+        // loops as written, ignoring yields and calls
+        let mut loops: Vec<LoopReport> = Vec::new();
+        for component in strongly_connected(&within) {
+            if !is_cycle(&component, &within) {
+                continue;
+            }
+            let start = component[0];
+            let yields_in_loop: Vec<usize> =
+                component.iter().copied().filter(|pc| cuts[*pc]).collect();
+            let worst = if yields_in_loop.is_empty() {
+                None
+            } else {
+                // from just after each yield; calls to yielding routines
+                // also count what runs up to their first yield
+                component
+                    .iter()
+                    .filter(|pc| **pc + 1 < ops.len() && cuts[**pc])
+                    .map(|pc| cost[pc + 1])
+                    .try_fold(0, |worst: u64, c| c.map(|c| worst.max(c)))
+            };
+            loops.push(LoopReport {
+                name: describe_pc(&symbols, start),
+                start,
+                end: *component.last().unwrap(),
+                spins: yields_in_loop.is_empty() || component.iter().any(|pc| spinning[*pc]),
+                worst,
+            });
+        }
+        loops.sort_by_key(|l| l.start);
+
+        Budget {
+            entries: entries.into_iter().map(|pc| (pc, cost[pc])).collect(),
+            loops,
+            labels: symbols
+                .labels
+                .iter()
+                .filter(|(_, pc)| *pc < ops.len())
+                .map(|(name, pc)| (name.clone(), cost[*pc]))
+                .collect(),
+        }
+    }
+
+    // Loops that can hang, and anything over the clock between yields
+    pub fn findings(&self, clock: u64) -> Vec<Finding> {
+        let mut findings: Vec<Finding> = Vec::new();
+        for report in self.loops.iter() {
+            let message = match report.worst {
+                _ if report.spins => {
+                    format!("loop at {} can run forever without yielding", report.name)
+                }
+                Some(worst) if worst > clock => format!(
+                    "loop at {} can run {} ops between yields, over the {} op frame budget",
+                    report.name, worst, clock
+                ),
+                _ => continue,
+            };
+            findings.push(Finding {
+                pc: report.start,
+                message,
+            });
+        }
+        for (pc, cost) in self.entries.iter() {
+            if let Some(cost) = cost.filter(|cost| *cost > clock) {
+                findings.push(Finding {
+                    pc: *pc,
+                    message: format!(
+                        "up to {} ops run from pc {} before the first yield, over the {} op frame budget",
+                        cost, pc, clock
+                    ),
+                });
+            }
+        }
+        findings
+    }
+
+    pub fn to_text(&self, clock: u64) -> String {
+        let describe = |cost: &Option<u64>| match cost {
+            Some(cost) => format!("{} ops", cost),
+            None => "unbounded".to_string(),
+        };
+        let mut out = String::new();
+        let _ = writeln!(out, "Frame budget: {} ops per core per frame", clock);
+        let _ = writeln!(out, "\nEntries (to the first yield):");
+        for (pc, cost) in self.entries.iter() {
+            let _ = writeln!(out, "  pc {:<21} {}", pc, describe(cost));
+        }
+        let _ = writeln!(out, "\nLoops (between yields):");
+        for report in self.loops.iter() {
+            let cost = if report.spins {
+                "never yields".to_string()
+            } else {
+                describe(&report.worst)
+            };
+            let range = format!("pc {}..{}", report.start, report.end);
+            let _ = writeln!(out, "  {:<24} {:<14} {}", report.name, range, cost);
+        }
+        let _ = writeln!(out, "\nLabels (to yield, return or halt):");
+        for (name, cost) in self.labels.iter() {
+            let _ = writeln!(out, "  {:<24} {}", name, describe(cost));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::assemble;
+
+    #[test]
+    fn test_budget() {
+        let program = assemble(
+            "li x5, 0
+MAIN:
+addi x5, x5, 1
+jal ra, WAIT
+jal zero, MAIN
+WAIT:
+yield zero, 1
+jalr zero, ra, 0
+SPIN:
+yield zero, 0
+beq x6, zero, SPIN
+HOT:
+spawn zero, zero, SPIN
+jal zero, HOT
+",
+        )
+        .unwrap();
+        let budget = Budget::new(&program);
+        assert_eq!(budget.entries, vec![(0, Some(4)), (6, None)]);
+        let loops: Vec<(&str, usize, usize, bool, Option<u64>)> = budget
+            .loops
+            .iter()
+            .map(|l| (l.name.as_str(), l.start, l.end, l.spins, l.worst))
+            .collect();
+        assert_eq!(
+            loops,
+            vec![
+                ("MAIN", 1, 3, false, Some(4)),
+                ("SPIN", 6, 7, true, None),
+                ("HOT", 8, 9, true, None)
+            ]
+        );
+        assert_eq!(
+            budget.labels,
+            vec![
+                ("MAIN".to_string(), Some(3)),
+                ("WAIT".to_string(), Some(1)),
+                ("SPIN".to_string(), None),
+                ("HOT".to_string(), None)
+            ]
+        );
+
+        let findings = budget.findings(3);
+        let messages: Vec<&str> = findings.iter().map(|f| f.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "loop at MAIN can run 4 ops between yields, over the 3 op frame budget",
+                "loop at SPIN can run forever without yielding",
+                "loop at HOT can run forever without yielding",
+                "up to 4 ops run from pc 0 before the first yield, over the 3 op frame budget"
+            ]
+        );
+        assert_eq!(
+            findings[1].describe(&program),
+            "line 10: loop at SPIN can run forever without yielding"
+        );
+        assert!(budget
+            .to_text(100)
+            .contains("\n  MAIN                     pc 1..3        4 ops\n"));
+    }
+}
@@ -0,0 +1,325 @@
+use crate::ops::{op_info, op_name, Op};
+use crate::parser::Program;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use std::vec::Vec;
+
+// Control-flow graph over assembled ops. Blocks start at pc 0, at labels,
+// at targets of pc-relative immediates and after anything that transfers
+// control. Calls (jal with a link register) get an edge to the callee and
+// one to the op after the call, where the callee is expected to return.
+// jalr can go anywhere, so its block has no static successors.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    // into the next block without a jump
+    Fallthrough,
+    // a conditional branch that was taken
+    Taken,
+    // jal through zero
+    Jump,
+    Call,
+    // from a call site to the op after it
+    AfterCall,
+    // start of a new coroutine
+    Spawn,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    // block index
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+// How an op affects control flow. Targets are pcs, and are only given when
+// they're inside the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Next,
+    Branch(Option<usize>),
+    Jump(Option<usize>),
+    Call(Option<usize>),
+    Indirect,
+    Spawn(Option<usize>),
+    // aipc, which makes an address for a later jalr
+    AddressOf(Option<usize>),
+}
+
+impl Flow {
+    pub fn target(&self) -> Option<usize> {
+        match self {
+            Flow::Branch(t)
+            | Flow::Jump(t)
+            | Flow::Call(t)
+            | Flow::Spawn(t)
+            | Flow::AddressOf(t) => *t,
+            Flow::Next | Flow::Indirect => None,
+        }
+    }
+
+    // whether the op ends its basic block
+    pub fn ends_block(&self) -> bool {
+        !matches!(self, Flow::Next | Flow::AddressOf(_))
+    }
+}
+
+pub fn flow(ops: &[Op], pc: usize) -> Flow {
+    let op = &ops[pc];
+    let target = match op_info(op.op.opcode) {
+        Some(info) if info.rel() => {
+            let target = pc as f64 + op.imm;
+            if target.fract() == 0.0 && target >= 0.0 && (target as usize) < ops.len() {
+                Some(target as usize)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+    match op_name(op.op.opcode) {
+        Some("beq" | "bne" | "blt" | "bge") => Flow::Branch(target),
+        Some("jal") if op.op.rd == 0 => Flow::Jump(target),
+        Some("jal") => Flow::Call(target),
+        Some("jalr") => Flow::Indirect,
+        Some("spawn") => Flow::Spawn(target),
+        Some("aipc") => Flow::AddressOf(target),
+        _ => Flow::Next,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    // label at the start of the block, if any
+    pub label: Option<String>,
+    pub start: usize,
+    // exclusive
+    pub end: usize,
+    pub succs: Vec<Edge>,
+    pub preds: Vec<usize>,
+}
+
+impl Block {
+    pub fn last(&self) -> usize {
+        self.end - 1
+    }
+
+    // the label, or "pc N"
+    pub fn name(&self) -> String {
+        match &self.label {
+            Some(label) => label.clone(),
+            None => format!("pc {}", self.start),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+    // block index of every pc
+    block_of: Vec<usize>,
+}
+
+impl Cfg {
+    pub fn new(ops: &[Op]) -> Cfg {
+        Cfg::with_labels(ops, &HashMap::new())
+    }
+
+    pub fn from_program(program: &Program) -> Cfg {
+        Cfg::with_labels(&program.ops, &program.labels)
+    }
+
+    pub fn with_labels(ops: &[Op], labels: &HashMap<String, f64>) -> Cfg {
+        let mut names: HashMap<usize, &str> = HashMap::new();
+        for (name, pc) in labels.iter() {
+            let pc = *pc as usize;
+            if pc < ops.len() && names.get(&pc).map_or(true, |other| name.as_str() < *other) {
+                names.insert(pc, name);
+            }
+        }
+
+        let mut leaders: BTreeSet<usize> = names.keys().copied().collect();
+        if !ops.is_empty() {
+            leaders.insert(0);
+        }
+        for pc in 0..ops.len() {
+            let flow = flow(ops, pc);
+            if let Some(target) = flow.target() {
+                leaders.insert(target);
+            }
+            if flow.ends_block() && pc + 1 < ops.len() {
+                leaders.insert(pc + 1);
+            }
+        }
+
+        let starts: Vec<usize> = leaders.into_iter().collect();
+        let mut block_of = vec![0; ops.len()];
+        let mut blocks: Vec<Block> = Vec::new();
+        for (idx, start) in starts.iter().enumerate() {
+            let end = starts.get(idx + 1).copied().unwrap_or(ops.len());
+            block_of[*start..end].fill(idx);
+            blocks.push(Block {
+                label: names.get(start).map(|name| name.to_string()),
+                start: *start,
+                end,
+                succs: Vec::new(),
+                preds: Vec::new(),
+            });
+        }
+
+        for block in blocks.iter_mut() {
+            let last = block.last();
+            let next = (last + 1 < ops.len()).then_some(last + 1);
+            let edge = |pc: Option<usize>, kind| {
+                pc.map(|pc| Edge {
+                    to: block_of[pc],
+                    kind,
+                })
+            };
+            let succs: Vec<Option<Edge>> = match flow(ops, last) {
+                Flow::Next | Flow::AddressOf(_) => vec![edge(next, EdgeKind::Fallthrough)],
+                Flow::Branch(t) => {
+                    vec![edge(t, EdgeKind::Taken), edge(next, EdgeKind::Fallthrough)]
+                }
+                Flow::Jump(t) => vec![edge(t, EdgeKind::Jump)],
+                Flow::Call(t) => vec![edge(t, EdgeKind::Call), edge(next, EdgeKind::AfterCall)],
+                Flow::Spawn(t) => vec![edge(t, EdgeKind::Spawn), edge(next, EdgeKind::Fallthrough)],
+                Flow::Indirect => Vec::new(),
+            };
+            for edge in succs.into_iter().flatten() {
+                if !block.succs.contains(&edge) {
+                    block.succs.push(edge);
+                }
+            }
+        }
+        for idx in 0..blocks.len() {
+            for edge in blocks[idx].succs.clone() {
+                if !blocks[edge.to].preds.contains(&idx) {
+                    blocks[edge.to].preds.push(idx);
+                }
+            }
+        }
+
+        Cfg { blocks, block_of }
+    }
+
+    pub fn block_at(&self, pc: usize) -> Option<usize> {
+        self.block_of.get(pc).copied()
+    }
+
+    // Blocks reachable from the given ones, following every kind of edge
+    pub fn reachable(&self, from: &[usize]) -> BTreeSet<usize> {
+        let mut seen: BTreeSet<usize> = BTreeSet::new();
+        let mut todo: Vec<usize> = from.to_vec();
+        while let Some(idx) = todo.pop() {
+            if seen.insert(idx) {
+                todo.extend(self.blocks[idx].succs.iter().map(|edge| edge.to));
+            }
+        }
+        seen
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "digraph cfg {{");
+        let _ = writeln!(out, "  node [shape=box, fontname=\"monospace\"];");
+        for (idx, block) in self.blocks.iter().enumerate() {
+            let range = format!("pc {}..{}", block.start, block.last());
+            let text = match &block.label {
+                Some(label) => format!("{}\\n{}", label.replace('"', "\\\""), range),
+                None => range,
+            };
+            let _ = writeln!(out, "  b{} [label=\"{}\"];", idx, text);
+        }
+        for (idx, block) in self.blocks.iter().enumerate() {
+            for edge in block.succs.iter() {
+                let style = match edge.kind {
+                    EdgeKind::Fallthrough => "",
+                    EdgeKind::Taken => " [label=\"taken\"]",
+                    EdgeKind::Jump => " [style=bold]",
+                    EdgeKind::Call => " [label=\"call\", style=dashed]",
+                    EdgeKind::AfterCall => " [style=dotted]",
+                    EdgeKind::Spawn => " [label=\"spawn\", style=dashed, color=blue]",
+                };
+                let _ = writeln!(out, "  b{} -> b{}{};", idx, edge.to, style);
+            }
+        }
+        let _ = writeln!(out, "}}");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::assemble;
+
+    #[test]
+    fn test_cfg() {
+        let program = assemble(
+            "li x5, 3
+LOOP:
+jal ra, SUB
+subi x5, x5, 1
+bne x5, zero, LOOP
+spawn zero, zero, SUB
+jal zero, END
+SUB:
+addi x6, x6, 1
+jalr zero, ra, 0
+END:
+",
+        )
+        .unwrap();
+        let cfg = Cfg::from_program(&program);
+        let summary: Vec<(String, usize, usize)> = cfg
+            .blocks
+            .iter()
+            .map(|b| (b.name(), b.start, b.end))
+            .collect();
+        let expected = [
+            ("pc 0", 0, 1),
+            ("LOOP", 1, 2),
+            ("pc 2", 2, 4),
+            ("pc 4", 4, 5),
+            ("pc 5", 5, 6),
+            ("SUB", 6, 8),
+        ];
+        let expected: Vec<(String, usize, usize)> = expected
+            .iter()
+            .map(|(name, start, end)| (name.to_string(), *start, *end))
+            .collect();
+        assert_eq!(summary, expected);
+        let edges = |idx: usize| -> Vec<(usize, EdgeKind)> {
+            cfg.blocks[idx]
+                .succs
+                .iter()
+                .map(|e| (e.to, e.kind))
+                .collect()
+        };
+        assert_eq!(edges(0), vec![(1, EdgeKind::Fallthrough)]);
+        assert_eq!(
+            edges(1),
+            vec![(5, EdgeKind::Call), (2, EdgeKind::AfterCall)]
+        );
+        assert_eq!(
+            edges(2),
+            vec![(1, EdgeKind::Taken), (3, EdgeKind::Fallthrough)]
+        );
+        assert_eq!(
+            edges(3),
+            vec![(5, EdgeKind::Spawn), (4, EdgeKind::Fallthrough)]
+        );
+        // jumping to the very end just halts
+        assert_eq!(edges(4), vec![]);
+        assert_eq!(edges(5), vec![]);
+        assert_eq!(cfg.blocks[5].preds, vec![1, 3]);
+        assert_eq!(cfg.block_at(7), Some(5));
+        assert_eq!(cfg.reachable(&[2]).len(), 5);
+
+        let dot = cfg.to_dot();
+        assert!(dot.contains("  b5 [label=\"SUB\\npc 6..7\"];\n"));
+        assert!(dot.contains("  b0 [label=\"pc 0..0\"];\n"));
+        assert!(dot.contains("  b1 -> b5 [label=\"call\", style=dashed];\n"));
+    }
+}
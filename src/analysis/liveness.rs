@@ -0,0 +1,441 @@
+use super::cfg::{flow, Flow};
+use super::{Finding, OpGraph};
+use crate::ops::{op_info, op_name, Op, OpArg};
+use crate::parser::Program;
+use crate::symbols::Symbols;
+use std::collections::{BTreeSet, HashMap};
+use std::vec::Vec;
+
+// Register dataflow over single ops: which registers are surely written on
+// every path to an op, and which are still read after it. Used to warn
+// about reads of registers nothing has written yet, writes nothing reads,
+// and values a call overwrites while the caller still needs them.
+//
+// Routines are summarized by the registers they can write and the ones they
+// always write before returning. A register a routine always writes is taken
+// to be its result, so reading it after the call is fine. Where a return
+// (jalr) goes isn't known, so everything counts as read after it when
+// looking for dead writes, and nothing does when looking for clobbers.
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegSet([u64; 4]);
+
+impl RegSet {
+    pub fn full() -> RegSet {
+        RegSet([u64::MAX; 4])
+    }
+
+    pub fn insert(&mut self, reg: u8) {
+        self.0[reg as usize / 64] |= 1 << (reg % 64);
+    }
+
+    pub fn remove(&mut self, reg: u8) {
+        self.0[reg as usize / 64] &= !(1 << (reg % 64));
+    }
+
+    pub fn contains(&self, reg: u8) -> bool {
+        self.0[reg as usize / 64] & (1 << (reg % 64)) != 0
+    }
+
+    pub fn union(&self, other: &RegSet) -> RegSet {
+        RegSet(std::array::from_fn(|i| self.0[i] | other.0[i]))
+    }
+
+    pub fn intersection(&self, other: &RegSet) -> RegSet {
+        RegSet(std::array::from_fn(|i| self.0[i] & other.0[i]))
+    }
+
+    pub fn difference(&self, other: &RegSet) -> RegSet {
+        RegSet(std::array::from_fn(|i| self.0[i] & !other.0[i]))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=u8::MAX).filter(|reg| self.contains(*reg))
+    }
+}
+
+impl FromIterator<u8> for RegSet {
+    fn from_iter<I: IntoIterator<Item = u8>>(regs: I) -> RegSet {
+        let mut set = RegSet::default();
+        for reg in regs {
+            set.insert(reg);
+        }
+        set
+    }
+}
+
+// Registers an op reads. store and cas read rd rather than write it.
+pub fn reads(op: &Op) -> RegSet {
+    let Some(info) = op_info(op.op.opcode) else {
+        return RegSet::default();
+    };
+    let mut regs = RegSet::default();
+    for arg in info.args() {
+        match arg {
+            OpArg::Rs1 => regs.insert(op.op.rs1),
+            OpArg::Rs2 => regs.insert(op.op.rs2),
+            OpArg::Rd if matches!(op_name(op.op.opcode), Some("store" | "cas")) => {
+                regs.insert(op.op.rd)
+            }
+            _ => {}
+        }
+    }
+    regs.remove(0);
+    regs
+}
+
+// Registers an op writes. Writes to zero are dropped by the VM.
+pub fn writes(op: &Op) -> RegSet {
+    let Some(info) = op_info(op.op.opcode) else {
+        return RegSet::default();
+    };
+    let mut regs = RegSet::default();
+    let no_result = matches!(
+        op_name(op.op.opcode),
+        Some("store" | "smprm" | "srprm" | "swprm" | "sxprm")
+    );
+    if info.args().contains(&OpArg::Rd) && !no_result {
+        regs.insert(op.op.rd);
+    }
+    regs.remove(0);
+    regs
+}
+
+// Registers written on every path to each op, for the ops some path
+// reaches. With into_callees, calls also carry their state into the routine
+// called; without, only the ops reachable from the seeds are visited.
+fn defined(
+    ops: &[Op],
+    graph: &OpGraph,
+    seeds: &[(usize, RegSet)],
+    always_writes: &HashMap<usize, RegSet>,
+    into_callees: bool,
+) -> HashMap<usize, RegSet> {
+    fn meet(state: &mut HashMap<usize, RegSet>, todo: &mut Vec<usize>, pc: usize, regs: RegSet) {
+        let merged = match state.get(&pc) {
+            Some(old) => old.intersection(&regs),
+            None => regs,
+        };
+        if state.insert(pc, merged) != Some(merged) {
+            todo.push(pc);
+        }
+    }
+    let mut state: HashMap<usize, RegSet> = HashMap::new();
+    let mut todo: Vec<usize> = Vec::new();
+    for (pc, regs) in seeds {
+        meet(&mut state, &mut todo, *pc, *regs);
+    }
+    while let Some(pc) = todo.pop() {
+        let before = state[&pc];
+        let mut after = before.union(&writes(&ops[pc]));
+        if let Some(callee) = graph.callees[pc] {
+            if into_callees {
+                meet(&mut state, &mut todo, callee, after);
+            }
+            after = after.union(&always_writes[&callee]);
+        }
+        for succ in graph.within[pc].iter() {
+            meet(&mut state, &mut todo, *succ, after);
+        }
+    }
+    state
+}
+
+// What's read after a jalr: a fixed set, or whatever is read at the pcs it
+// can go to
+#[derive(Debug, Clone, Copy)]
+enum Returns<'a> {
+    Reading(RegSet),
+    To(&'a [usize]),
+}
+
+// Registers that may be read after each op. With through_calls a call continues into the routine called,
+// otherwise to the op after it, minus what the routine always writes.
+fn live(
+    ops: &[Op],
+    graph: &OpGraph,
+    always_writes: &HashMap<usize, RegSet>,
+    returned: Returns,
+    through_calls: bool,
+) -> Vec<RegSet> {
+    // where each op goes, and the ops that come back to each op when what's
+    // read there changes
+    let targets: Vec<usize> = match returned {
+        Returns::Reading(_) => Vec::new(),
+        Returns::To(targets) => targets
+            .iter()
+            .copied()
+            .filter(|target| *target < ops.len())
+            .collect(),
+    };
+    let succs: Vec<&[usize]> = (0..ops.len())
+        .map(|pc| match &graph.callees[pc] {
+            _ if graph.is_return(pc) => targets.as_slice(),
+            Some(callee) if through_calls => std::slice::from_ref(callee),
+            _ => graph.within[pc].as_slice(),
+        })
+        .collect();
+    let mut preds: Vec<Vec<usize>> = vec![Vec::new(); ops.len()];
+    for (pc, succs) in succs.iter().enumerate() {
+        for succ in succs.iter() {
+            preds[*succ].push(pc);
+        }
+    }
+
+    let mut live_in: Vec<RegSet> = vec![RegSet::default(); ops.len()];
+    let mut live_out: Vec<RegSet> = vec![RegSet::default(); ops.len()];
+    // popped last op first, so straight-line code settles in one sweep
+    let mut todo: Vec<usize> = (0..ops.len()).collect();
+    let mut queued: Vec<bool> = vec![true; ops.len()];
+    while let Some(pc) = todo.pop() {
+        queued[pc] = false;
+        let after = match returned {
+            Returns::Reading(regs) if graph.is_return(pc) => regs,
+            _ => succs[pc]
+                .iter()
+                .fold(RegSet::default(), |regs, succ| regs.union(&live_in[*succ])),
+        };
+        let mut killed = writes(&ops[pc]);
+        if let (Some(callee), false) = (graph.callees[pc], through_calls) {
+            killed = killed.union(&always_writes[&callee]);
+        }
+        let before = reads(&ops[pc]).union(&after.difference(&killed));
+        live_out[pc] = after;
+        if before != live_in[pc] {
+            live_in[pc] = before;
+            for pred in preds[pc].iter() {
+                if !queued[*pred] {
+                    queued[*pred] = true;
+                    todo.push(*pred);
+                }
+            }
+        }
+    }
+    live_out
+}
+
+// Registers that may be read after each op, given every pc a jalr can go
+// to: usually the ops after calls, and labels loaded into registers
+pub fn live_out(ops: &[Op], indirect: &[usize]) -> Vec<RegSet> {
+    let graph = OpGraph::new(ops);
+    live(ops, &graph, &HashMap::new(), Returns::To(indirect), true)
+}
+
+fn reg_name(names: &HashMap<u8, String>, reg: u8) -> String {
+    match names.get(&reg) {
+        Some(name) => name.clone(),
+        None => match reg {
+            1 => "ra".to_string(),
+            2 => "sp".to_string(),
+            3 => "gp".to_string(),
+            4 => "tp".to_string(),
+            _ => format!("x{}", reg),
+        },
+    }
+}
+
+pub fn findings(program: &Program) -> Vec<Finding> {
+    let ops = &program.ops;
+    let graph = OpGraph::new(ops);
+    let symbols = Symbols::from_program(program);
+    let mut names: HashMap<u8, String> = HashMap::new();
+    for (name, reg) in program.user_aliases() {
+        names
+            .entry(reg)
+            .and_modify(|names| *names += &format!("/{}", name))
+            .or_insert_with(|| name.to_string());
+    }
+
+    // routine summaries. Routines can call each other, so a routine is
+    // summarized again whenever the summary of one it calls changes.
+    let callees: BTreeSet<usize> = graph.callees.iter().flatten().copied().collect();
+    let mut may_write: HashMap<usize, RegSet> = HashMap::new();
+    let mut always_writes: HashMap<usize, RegSet> = HashMap::new();
+    for callee in callees.iter() {
+        may_write.insert(*callee, RegSet::default());
+        always_writes.insert(*callee, RegSet::full());
+    }
+    let mut callers: HashMap<usize, BTreeSet<usize>> = HashMap::new();
+    let mut todo: Vec<usize> = callees.iter().rev().copied().collect();
+    let mut queued: BTreeSet<usize> = callees.clone();
+    while let Some(callee) = todo.pop() {
+        queued.remove(&callee);
+        let state = defined(
+            ops,
+            &graph,
+            &[(callee, RegSet::default())],
+            &always_writes,
+            false,
+        );
+        let mut writing = RegSet::default();
+        let mut summary = RegSet::full();
+        for (pc, before) in state.iter() {
+            writing = writing.union(&writes(&ops[*pc]));
+            if let Some(inner) = graph.callees[*pc] {
+                writing = writing.union(&may_write[&inner]);
+                callers.entry(inner).or_default().insert(callee);
+            }
+            if graph.is_return(*pc) {
+                summary = summary.intersection(&before.union(&writes(&ops[*pc])));
+            }
+        }
+        if may_write[&callee] != writing || always_writes[&callee] != summary {
+            may_write.insert(callee, writing);
+            always_writes.insert(callee, summary);
+            for caller in callers.get(&callee).into_iter().flatten() {
+                if queued.insert(*caller) {
+                    todo.push(*caller);
+                }
+            }
+        }
+    }
+
+    let mut seeds: Vec<(usize, RegSet)> = Vec::new();
+    if !ops.is_empty() {
+        seeds.push((0, RegSet::default()));
+    }
+    for pc in 0..ops.len() {
+        if let Flow::Spawn(Some(target)) = flow(ops, pc) {
+            // the spawn argument arrives in tp
+            seeds.push((target, [4].into_iter().collect()));
+        }
+    }
+    let state = defined(ops, &graph, &seeds, &always_writes, true);
+    let needed = live(
+        ops,
+        &graph,
+        &always_writes,
+        Returns::Reading(RegSet::full()),
+        true,
+    );
+    let needed_here = live(
+        ops,
+        &graph,
+        &always_writes,
+        Returns::Reading(RegSet::default()),
+        false,
+    );
+
+    // a .func prologue stores the registers it saves whatever is in them
+    let saving: BTreeSet<usize> = program
+        .functions
+        .iter()
+        .flat_map(|f| f.start + 1..f.start + 1 + f.saves.len())
+        .collect();
+
+    let mut findings: Vec<Finding> = Vec::new();
+    for (pc, op) in ops.iter().enumerate() {
+        let Some(before) = state.get(&pc).copied() else {
+            continue;
+        };
+        let checked = match saving.contains(&pc) {
+            true => RegSet::default(),
+            false => reads(op),
+        };
+        for reg in checked.difference(&before).iter() {
+            findings.push(Finding {
+                pc,
+                message: format!("{} may be read before it is written", reg_name(&names, reg)),
+            });
+        }
+        if !matches!(flow(ops, pc), Flow::Call(_) | Flow::Indirect) {
+            for reg in writes(op).difference(&needed[pc]).iter() {
+                findings.push(Finding {
+                    pc,
+                    message: format!("value written to {} is never read", reg_name(&names, reg)),
+                });
+            }
+        }
+        if let Some(callee) = graph.callees[pc] {
+            let overwritten =
+                writes(op).union(&may_write[&callee].difference(&always_writes[&callee]));
+            let routine = match symbols.label_at(callee) {
+                Some((label, 0)) => label.to_string(),
+                _ => format!("pc {}", callee),
+            };
+            for reg in overwritten
+                .intersection(&before)
+                .intersection(&needed_here[pc])
+                .iter()
+            {
+                findings.push(Finding {
+                    pc,
+                    message: format!(
+                        "call to {} can overwrite {}, which is read after it",
+                        routine,
+                        reg_name(&names, reg)
+                    ),
+                });
+            }
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::assemble;
+
+    #[test]
+    fn test_liveness() {
+        let program = assemble(
+            "reg count = x5
+reg total = x6
+li count, 3
+li x7, 1
+li x7, 2
+LOOP:
+add total, total, x7
+jal ra, STEP
+jal ra, SQUARE
+subi count, count, 1
+bne count, zero, LOOP
+store total, zero, 0x300
+li x9, 1
+jal zero, END
+STEP:
+jal ra, SQUARE
+jalr zero, ra, 0
+SQUARE:
+mul x8, x7, x7
+beq x8, zero, SKIP
+li count, 0
+SKIP:
+jalr zero, ra, 0
+END:
+",
+        )
+        .unwrap();
+        let findings: Vec<(usize, String)> = findings(&program)
+            .into_iter()
+            .map(|f| (f.pc, f.message))
+            .collect();
+        let expected = [
+            (1, "value written to x7 is never read"),
+            (3, "total may be read before it is written"),
+            (
+                4,
+                "call to STEP can overwrite count, which is read after it",
+            ),
+            (
+                5,
+                "call to SQUARE can overwrite count, which is read after it",
+            ),
+            (9, "value written to x9 is never read"),
+            (
+                11,
+                "call to SQUARE can overwrite ra, which is read after it",
+            ),
+        ];
+        let expected: Vec<(usize, String)> = expected
+            .iter()
+            .map(|(pc, message)| (*pc, message.to_string()))
+            .collect();
+        assert_eq!(findings, expected);
+
+        let set: RegSet = [0, 5, 200].into_iter().collect();
+        assert_eq!(set.iter().collect::<Vec<u8>>(), vec![0, 5, 200]);
+        assert!(RegSet::full().difference(&set).contains(6));
+    }
+}
@@ -0,0 +1,498 @@
+use super::cfg::{flow, Flow};
+use super::liveness::{writes, RegSet};
+use super::{Finding, OpGraph};
+use crate::memmap::{memmap_name, IO_END};
+use crate::ops::{op_name, Op};
+use crate::parser::Program;
+use crate::symbols::Symbols;
+use crate::vm::MEMORY_SIZE;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::vec::Vec;
+
+// Which memory each part of a program touches, as far as it can be told
+// without running it. Register values are followed through straight-line
+// arithmetic (li, addi, add, ...) so that `store x1, x5, 2` after
+// `li x5, 0x300` is known to write 0x302. Anything computed from memory,
+// the core id or a loop counter is left unresolved.
+//
+// Routines here are pc 0, spawn targets and call targets, each covering the
+// ops it reaches without following calls. Branches with known outcomes
+// only follow the side they take, which also lets code be followed as core
+// 0 or as any other core.
+
+// What crid gives in the code being followed: other cores can't all be
+// told apart, but they're known not to be core 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoreId {
+    Any,
+    Zero,
+    NotZero,
+}
+
+// What's known about registers before an op
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Known {
+    pub values: BTreeMap<u8, f64>,
+    // registers holding a core id that isn't 0
+    core_ids: BTreeSet<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub pc: usize,
+    // None when it can't be worked out statically
+    pub address: Option<usize>,
+    pub write: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelAccesses {
+    pub name: String,
+    // inclusive address ranges
+    pub reads: Vec<(usize, usize)>,
+    pub writes: Vec<(usize, usize)>,
+    pub unresolved: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Routine {
+    pub name: String,
+    pub entry: usize,
+    // ops it runs itself
+    pub ops: BTreeSet<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryMap {
+    pub accesses: Vec<Access>,
+    pub labels: Vec<LabelAccesses>,
+    pub routines: Vec<Routine>,
+}
+
+fn value(known: &Known, reg: u8) -> Option<f64> {
+    match reg {
+        0 => Some(0.0),
+        _ => known.values.get(&reg).copied(),
+    }
+}
+
+// What an op writes to rd, if it only depends on known values
+fn evaluate(op: &Op, pc: usize, known: &Known, core: CoreId) -> Option<f64> {
+    let a = value(known, op.op.rs1);
+    let b = value(known, op.op.rs2);
+    let imm = op.imm;
+    match op_name(op.op.opcode)? {
+        "li" => Some(imm),
+        "aipc" => Some(pc as f64 + imm),
+        "jal" | "jalr" => Some(pc as f64 + 1.0),
+        "crid" if core == CoreId::Zero => Some(0.0),
+        "mv" => a,
+        "add" => Some(a? + b?),
+        "addi" => Some(a? + imm),
+        "sub" => Some(a? - b?),
+        "subi" => Some(a? - imm),
+        "mul" => Some(a? * b?),
+        "muli" => Some(a? * imm),
+        "lshi" => Some((a? as i64).wrapping_shl(imm as i64 as u32) as f64),
+        "rshi" => Some((a? as i64).wrapping_shr(imm as i64 as u32) as f64),
+        "andi" => Some(((a? as i64) & (imm as i64)) as f64),
+        "ori" => Some(((a? as i64) | (imm as i64)) as f64),
+        _ => None,
+    }
+}
+
+fn after(op: &Op, pc: usize, known: &Known, core: CoreId) -> Known {
+    let mut out = known.clone();
+    let written = writes(op);
+    for reg in written.iter() {
+        out.values.remove(&reg);
+        out.core_ids.remove(&reg);
+    }
+    if !written.contains(op.op.rd) {
+        return out;
+    }
+    if let Some(result) = evaluate(op, pc, known, core) {
+        out.values.insert(op.op.rd, result);
+    }
+    let copies_core_id = op_name(op.op.opcode) == Some("mv") && known.core_ids.contains(&op.op.rs1);
+    if (op_name(op.op.opcode) == Some("crid") && core == CoreId::NotZero) || copies_core_id {
+        out.core_ids.insert(op.op.rd);
+    }
+    out
+}
+
+// Whether a branch is taken, if that's known
+fn decide(op: &Op, known: &Known) -> Option<bool> {
+    let (rs1, rs2) = (op.op.rs1, op.op.rs2);
+    let equal = match (value(known, rs1), value(known, rs2)) {
+        (Some(a), Some(b)) => match op_name(op.op.opcode)? {
+            "blt" => return Some(a < b),
+            "bge" => return Some(a >= b),
+            _ => a == b,
+        },
+        (Some(0.0), None) if known.core_ids.contains(&rs2) => false,
+        (None, Some(0.0)) if known.core_ids.contains(&rs1) => false,
+        _ => return None,
+    };
+    match op_name(op.op.opcode)? {
+        "beq" => Some(equal),
+        "bne" => Some(!equal),
+        _ => None,
+    }
+}
+
+// What's known before each op that some path reaches, given what each
+// routine called can write (OpGraph::may_write). Branches that always go
+// one way only follow that way.
+pub fn known_values(
+    ops: &[Op],
+    graph: &OpGraph,
+    may_write: &HashMap<usize, RegSet>,
+    entries: &[usize],
+    core: CoreId,
+) -> BTreeMap<usize, Known> {
+    fn meet(state: &mut BTreeMap<usize, Known>, todo: &mut Vec<usize>, pc: usize, known: &Known) {
+        let merged = match state.get(&pc) {
+            Some(old) => Known {
+                values: old
+                    .values
+                    .iter()
+                    .filter(|(reg, value)| known.values.get(reg) == Some(value))
+                    .map(|(reg, value)| (*reg, *value))
+                    .collect(),
+                core_ids: old
+                    .core_ids
+                    .intersection(&known.core_ids)
+                    .copied()
+                    .collect(),
+            },
+            None => known.clone(),
+        };
+        if state.get(&pc) != Some(&merged) {
+            state.insert(pc, merged);
+            todo.push(pc);
+        }
+    }
+    let mut state: BTreeMap<usize, Known> = BTreeMap::new();
+    let mut todo: Vec<usize> = Vec::new();
+    for entry in entries {
+        meet(&mut state, &mut todo, *entry, &Known::default());
+    }
+    while let Some(pc) = todo.pop() {
+        let before = state[&pc].clone();
+        let mut out = after(&ops[pc], pc, &before, core);
+        if let Some(callee) = graph.callees[pc] {
+            meet(&mut state, &mut todo, callee, &out);
+            for reg in may_write[&callee].iter() {
+                out.values.remove(&reg);
+                out.core_ids.remove(&reg);
+            }
+        }
+        let succs: Vec<usize> = match (flow(ops, pc), decide(&ops[pc], &before)) {
+            (Flow::Branch(target), Some(true)) => target.into_iter().collect(),
+            (Flow::Branch(_), Some(false)) => {
+                (pc + 1 < ops.len()).then_some(pc + 1).into_iter().collect()
+            }
+            _ => graph.within[pc].clone(),
+        };
+        for succ in succs {
+            meet(&mut state, &mut todo, succ, &out);
+        }
+    }
+    state
+}
+
+fn address(value: Option<f64>) -> Option<usize> {
+    value
+        .filter(|v| *v >= 0.0 && *v < MEMORY_SIZE as f64)
+        .map(|v| v as usize)
+}
+
+// Loads, stores and cas, with their addresses where they're known
+pub fn accesses(ops: &[Op], known: &BTreeMap<usize, Known>) -> Vec<Access> {
+    let mut accesses: Vec<Access> = Vec::new();
+    for (pc, known) in known.iter() {
+        let (pc, op) = (*pc, &ops[*pc]);
+        let base = value(known, op.op.rs1);
+        let access = |address, write| Access { pc, address, write };
+        match op_name(op.op.opcode) {
+            Some("load") => accesses.push(access(address(base.map(|a| a + op.imm)), false)),
+            Some("store") => accesses.push(access(address(base.map(|a| a + op.imm)), true)),
+            Some("cas") => {
+                accesses.push(access(address(base), false));
+                accesses.push(access(address(base), true));
+            }
+            _ => {}
+        }
+    }
+    accesses
+}
+
+// Sorted addresses as inclusive runs
+pub fn ranges(addresses: &BTreeSet<usize>) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for addr in addresses.iter() {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == *addr => *end = *addr,
+            _ => ranges.push((*addr, *addr)),
+        }
+    }
+    ranges
+}
+
+pub fn describe_range((start, end): (usize, usize)) -> String {
+    if start != end {
+        return format!("{:#06x}-{:#06x}", start, end);
+    }
+    match memmap_name(start) {
+        Some(name) => format!("{:#06x} (${})", start, name),
+        None => format!("{:#06x}", start),
+    }
+}
+
+fn name_at(symbols: &Symbols, pc: usize) -> String {
+    match symbols.label_at(pc) {
+        Some((label, _)) => label.to_string(),
+        None => "(start)".to_string(),
+    }
+}
+
+impl MemoryMap {
+    pub fn new(program: &Program) -> MemoryMap {
+        let ops = &program.ops;
+        let graph = OpGraph::new(ops);
+        let symbols = Symbols::from_program(program);
+        let entries = OpGraph::entries(ops);
+        let may_write = graph.may_write(ops);
+        let known = known_values(ops, &graph, &may_write, &entries, CoreId::Any);
+        let accesses = accesses(ops, &known);
+
+        // accesses come in pc order, so each label's are together
+        let mut labels: Vec<LabelAccesses> = Vec::new();
+        let mut touched: Vec<(BTreeSet<usize>, BTreeSet<usize>)> = Vec::new();
+        for access in accesses.iter() {
+            let name = name_at(&symbols, access.pc);
+            if labels.last().map(|l| &l.name) != Some(&name) {
+                labels.push(LabelAccesses {
+                    name,
+                    reads: Vec::new(),
+                    writes: Vec::new(),
+                    unresolved: 0,
+                });
+                touched.push(Default::default());
+            }
+            let (reads, writes) = touched.last_mut().unwrap();
+            match (access.address, access.write) {
+                (Some(addr), false) => drop(reads.insert(addr)),
+                (Some(addr), true) => drop(writes.insert(addr)),
+                (None, _) => labels.last_mut().unwrap().unresolved += 1,
+            }
+        }
+        for (label, (reads, writes)) in labels.iter_mut().zip(touched) {
+            label.reads = ranges(&reads);
+            label.writes = ranges(&writes);
+        }
+
+        let mut entries = entries;
+        for callee in graph.callees.iter().flatten() {
+            if !entries.contains(callee) {
+                entries.push(*callee);
+            }
+        }
+        let routines = entries
+            .into_iter()
+            .map(|entry| {
+                let mut seen: BTreeSet<usize> = BTreeSet::new();
+                let mut todo = vec![entry];
+                while let Some(pc) = todo.pop() {
+                    if seen.insert(pc) {
+                        todo.extend(graph.within[pc].iter().copied());
+                    }
+                }
+                Routine {
+                    name: match symbols.label_at(entry) {
+                        Some((label, 0)) => label.to_string(),
+                        _ => format!("pc {}", entry),
+                    },
+                    entry,
+                    ops: seen,
+                }
+            })
+            .collect();
+
+        MemoryMap {
+            accesses,
+            labels,
+            routines,
+        }
+    }
+
+    // Stores to unnamed I/O addresses, and buffers more than one routine
+    // writes to
+    pub fn findings(&self) -> Vec<Finding> {
+        let mut findings: Vec<Finding> = Vec::new();
+        for access in self.accesses.iter().filter(|a| a.write) {
+            if let Some(addr) = access.address.filter(|addr| *addr < IO_END) {
+                if memmap_name(addr).is_none() {
+                    findings.push(Finding {
+                        pc: access.pc,
+                        message: format!(
+                            "store to {:#06x} in the I/O area doesn't match any $ name",
+                            addr
+                        ),
+                    });
+                }
+            }
+        }
+
+        // the routines writing each address, so that only routines writing
+        // the same place get compared
+        let mut owners: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (idx, routine) in self.routines.iter().enumerate() {
+            for pc in routine.ops.iter() {
+                owners.entry(*pc).or_default().push(idx);
+            }
+        }
+        let mut writers: BTreeMap<usize, Vec<(usize, usize)>> = BTreeMap::new();
+        for access in self.accesses.iter().filter(|a| a.write) {
+            let Some(addr) = access.address.filter(|addr| *addr >= IO_END) else {
+                continue;
+            };
+            for routine in owners.get(&access.pc).into_iter().flatten() {
+                writers.entry(addr).or_default().push((*routine, access.pc));
+            }
+        }
+
+        // ops shared by both routines don't count against either. Each pair
+        // gets the addresses they both write, with the second one's store.
+        let mut shared: BTreeMap<(usize, usize), BTreeMap<usize, usize>> = BTreeMap::new();
+        for (addr, writes) in writers.iter() {
+            let alone = |routine: usize, other: usize| {
+                writes
+                    .iter()
+                    .rev()
+                    .find(|(r, pc)| *r == routine && !self.routines[other].ops.contains(pc))
+                    .map(|(_, pc)| *pc)
+            };
+            let routines: BTreeSet<usize> = writes.iter().map(|(r, _)| *r).collect();
+            for first in routines.iter() {
+                for second in routines.range(first + 1..) {
+                    if let (Some(_), Some(pc)) = (alone(*first, *second), alone(*second, *first)) {
+                        shared
+                            .entry((*first, *second))
+                            .or_default()
+                            .insert(*addr, pc);
+                    }
+                }
+            }
+        }
+        for ((first, second), both) in shared.iter() {
+            let addresses: BTreeSet<usize> = both.keys().copied().collect();
+            for range in ranges(&addresses) {
+                findings.push(Finding {
+                    pc: both[&range.0],
+                    message: format!(
+                        "{} and {} both write {}",
+                        self.routines[*first].name,
+                        self.routines[*second].name,
+                        describe_range(range)
+                    ),
+                });
+            }
+        }
+        findings.sort_by_key(|f| f.pc);
+        findings
+    }
+
+    pub fn to_text(&self) -> String {
+        let list = |ranges: &[(usize, usize)]| {
+            let ranges: Vec<String> = ranges.iter().map(|r| describe_range(*r)).collect();
+            ranges.join(", ")
+        };
+        let mut out = String::new();
+        let _ = writeln!(out, "Memory accesses by label (static addresses only):");
+        for label in self.labels.iter() {
+            let _ = writeln!(out, "{}", label.name);
+            if !label.reads.is_empty() {
+                let _ = writeln!(out, "  reads   {}", list(&label.reads));
+            }
+            if !label.writes.is_empty() {
+                let _ = writeln!(out, "  writes  {}", list(&label.writes));
+            }
+            if label.unresolved > 0 {
+                let _ = writeln!(out, "  {} unresolved", label.unresolved);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::assemble;
+
+    #[test]
+    fn test_memory_map() {
+        let program = assemble(
+            "const BUF = 0x300
+li x5, BUF
+li x1, 4
+store x1, zero[$VIDEO_SPRITE_COUNT]
+store x1, zero, 0x40
+store x1, zero, 0x303
+jal ra, CLEAR
+LOOP:
+load x6, x5, 1
+store x6, x5, 2
+addi x5, x5, 1
+jal zero, LOOP
+CLEAR:
+li x7, BUF
+store zero, x7, 3
+store zero, x7, 4
+load x8, x9, 0
+jalr zero, ra, 0
+",
+        )
+        .unwrap();
+        let map = MemoryMap::new(&program);
+        let label = |name: &str, writes: Vec<(usize, usize)>, unresolved| LabelAccesses {
+            name: name.to_string(),
+            reads: Vec::new(),
+            writes,
+            unresolved,
+        };
+        assert_eq!(
+            map.labels,
+            vec![
+                label("(start)", vec![(10, 10), (0x40, 0x40), (0x303, 0x303)], 0),
+                label("LOOP", vec![], 2),
+                label("CLEAR", vec![(0x303, 0x304)], 1),
+            ]
+        );
+        let names: Vec<&str> = map.routines.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["pc 0", "CLEAR"]);
+
+        let findings: Vec<(usize, String)> = map
+            .findings()
+            .into_iter()
+            .map(|f| (f.pc, f.message))
+            .collect();
+        assert_eq!(
+            findings,
+            vec![
+                (
+                    3,
+                    "store to 0x0040 in the I/O area doesn't match any $ name".to_string()
+                ),
+                (11, "pc 0 and CLEAR both write 0x0303".to_string())
+            ]
+        );
+
+        let text = map.to_text();
+        assert!(text.contains("(start)\n  writes  0x000a ($VIDEO_SPRITE_COUNT), 0x0040, 0x0303\n"));
+        assert!(text.contains("CLEAR\n  writes  0x0303-0x0304\n  1 unresolved\n"));
+    }
+}
@@ -0,0 +1,214 @@
+use crate::ops::Op;
+use crate::parser::Program;
+use cfg::{flow, Flow};
+use liveness::{writes, RegSet};
+use std::collections::{BTreeSet, HashMap};
+use std::vec::Vec;
+
+// Static analyses over assembled programs
+pub mod budget;
+pub mod cfg;
+pub mod liveness;
+pub mod memory;
+pub mod races;
+
+// Something an analysis wants the programmer to look at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    // op the finding is about
+    pub pc: usize,
+    pub message: String,
+}
+
+impl Finding {
+    // "line N: message", or just the message when the pc has no source
+    // line. Ops from included files also get their own file and line.
+    pub fn describe(&self, program: &Program) -> String {
+        let Some(line) = program.lines.get(self.pc) else {
+            return self.message.clone();
+        };
+        match program.locations.get(self.pc) {
+            Some(loc) if !loc.stack.is_empty() => format!(
+                "line {} ({}:{}): {}",
+                line + 1,
+                program.files[loc.file],
+                loc.line + 1,
+                self.message
+            ),
+            _ => format!("line {}: {}", line + 1, self.message),
+        }
+    }
+}
+
+// Ops and where control can go from each, for analyses that need finer
+// steps than basic blocks
+pub struct OpGraph {
+    // successors within the same routine and coroutine
+    pub within: Vec<Vec<usize>>,
+    // the routine each call goes to
+    pub callees: Vec<Option<usize>>,
+    returns: Vec<bool>,
+}
+
+impl OpGraph {
+    pub fn new(ops: &[Op]) -> OpGraph {
+        let mut graph = OpGraph {
+            within: Vec::new(),
+            callees: Vec::new(),
+            returns: Vec::new(),
+        };
+        for pc in 0..ops.len() {
+            let next: Option<usize> = (pc + 1 < ops.len()).then_some(pc + 1);
+            let (succs, callee): (Vec<Option<usize>>, Option<usize>) = match flow(ops, pc) {
+                Flow::Next | Flow::AddressOf(_) | Flow::Spawn(_) => (vec![next], None),
+                Flow::Branch(target) => (vec![target, next], None),
+                Flow::Jump(target) => (vec![target], None),
+                Flow::Call(callee) => (vec![next], callee),
+                Flow::Indirect => (Vec::new(), None),
+            };
+            let mut succs: Vec<usize> = succs.into_iter().flatten().collect();
+            succs.dedup();
+            graph.within.push(succs);
+            graph.callees.push(callee);
+            graph.returns.push(flow(ops, pc) == Flow::Indirect);
+        }
+        graph
+    }
+
+    // jalr, which is how routines return
+    pub fn is_return(&self, pc: usize) -> bool {
+        self.returns[pc]
+    }
+
+    // pc 0 and every spawn target
+    pub fn entries(ops: &[Op]) -> Vec<usize> {
+        let mut entries: Vec<usize> = if ops.is_empty() { Vec::new() } else { vec![0] };
+        let mut seen: BTreeSet<usize> = entries.iter().copied().collect();
+        for pc in 0..ops.len() {
+            if let Flow::Spawn(Some(target)) = flow(ops, pc) {
+                if seen.insert(target) {
+                    entries.push(target);
+                }
+            }
+        }
+        entries
+    }
+
+    // Registers each routine called can write, including in the routines
+    // it calls. Every routine's own ops are walked once, then what callees
+    // write is passed up to their callers until nothing changes.
+    pub fn may_write(&self, ops: &[Op]) -> HashMap<usize, RegSet> {
+        let callees: BTreeSet<usize> = self.callees.iter().flatten().copied().collect();
+        let mut may_write: HashMap<usize, RegSet> = HashMap::new();
+        let mut callers: HashMap<usize, BTreeSet<usize>> = HashMap::new();
+        for callee in callees.iter() {
+            let mut regs = RegSet::default();
+            let mut seen: BTreeSet<usize> = BTreeSet::new();
+            let mut todo = vec![*callee];
+            while let Some(pc) = todo.pop() {
+                if seen.insert(pc) {
+                    regs = regs.union(&writes(&ops[pc]));
+                    if let Some(inner) = self.callees[pc] {
+                        callers.entry(inner).or_default().insert(*callee);
+                    }
+                    todo.extend(self.within[pc].iter().copied());
+                }
+            }
+            may_write.insert(*callee, regs);
+        }
+        let mut todo: Vec<usize> = callees.into_iter().collect();
+        while let Some(callee) = todo.pop() {
+            let regs = may_write[&callee];
+            for caller in callers.get(&callee).into_iter().flatten() {
+                let merged = may_write[caller].union(&regs);
+                if merged != may_write[caller] {
+                    may_write.insert(*caller, merged);
+                    todo.push(*caller);
+                }
+            }
+        }
+        may_write
+    }
+}
+
+// Strongly connected components of a graph over nodes 0..succs.len(), in
+// reverse topological order: each component comes after every component it
+// has edges into. Iterative Tarjan, since op graphs can be long chains.
+pub fn strongly_connected(succs: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    const UNSEEN: usize = usize::MAX;
+    let n = succs.len();
+    let mut index = vec![UNSEEN; n];
+    let mut low = vec![0; n];
+    let mut on_stack = vec![false; n];
+    let mut stack: Vec<usize> = Vec::new();
+    let mut components: Vec<Vec<usize>> = Vec::new();
+    let mut next = 0;
+
+    for root in 0..n {
+        if index[root] != UNSEEN {
+            continue;
+        }
+        // (node, next successor to look at)
+        let mut calls: Vec<(usize, usize)> = vec![(root, 0)];
+        index[root] = next;
+        low[root] = next;
+        next += 1;
+        stack.push(root);
+        on_stack[root] = true;
+
+        while let Some((node, child)) = calls.last_mut() {
+            let node = *node;
+            if let Some(&succ) = succs[node].get(*child) {
+                *child += 1;
+                if index[succ] == UNSEEN {
+                    index[succ] = next;
+                    low[succ] = next;
+                    next += 1;
+                    stack.push(succ);
+                    on_stack[succ] = true;
+                    calls.push((succ, 0));
+                } else if on_stack[succ] {
+                    low[node] = low[node].min(index[succ]);
+                }
+                continue;
+            }
+            calls.pop();
+            if let Some((parent, _)) = calls.last() {
+                low[*parent] = low[*parent].min(low[node]);
+            }
+            if low[node] == index[node] {
+                let mut component = Vec::new();
+                while let Some(member) = stack.pop() {
+                    on_stack[member] = false;
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                component.sort_unstable();
+                components.push(component);
+            }
+        }
+    }
+    components
+}
+
+// Whether a component loops: several nodes, or one with an edge to itself
+pub fn is_cycle(component: &[usize], succs: &[Vec<usize>]) -> bool {
+    component.len() > 1 || succs[component[0]].contains(&component[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strongly_connected() {
+        // 0 -> 1 <-> 2 -> 3, 4 -> 4
+        let succs = vec![vec![1], vec![2], vec![1, 3], vec![], vec![4]];
+        let components = strongly_connected(&succs);
+        assert_eq!(components, vec![vec![3], vec![1, 2], vec![0], vec![4]]);
+        let cycles: Vec<bool> = components.iter().map(|c| is_cycle(c, &succs)).collect();
+        assert_eq!(cycles, vec![false, true, false, true]);
+    }
+}
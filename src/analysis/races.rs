@@ -0,0 +1,245 @@
+use super::cfg::{flow, Flow};
+use super::memory::{accesses, describe_range, known_values, CoreId};
+use super::{Finding, OpGraph};
+use crate::ops::{op_name, Op};
+use crate::parser::Program;
+use crate::symbols::Symbols;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::vec::Vec;
+
+// Memory written from more than one place that can run at once. Once a
+// program uses crcfg, code from pc 0 is followed twice: as core 0 and as
+// the other cores, which run alongside each other too. Every spawn target
+// is a coroutine of its own. Only addresses known statically are checked,
+// and writes through cas are taken to be deliberate.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Context {
+    pub name: String,
+    pub entry: usize,
+    // several copies of it can run at the same time
+    pub repeated: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Race {
+    // inclusive
+    pub range: (usize, usize),
+    // indexes into the contexts
+    pub writers: Vec<usize>,
+    // first plain store to the range
+    pub pc: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Write {
+    context: usize,
+    pc: usize,
+    cas: bool,
+}
+
+fn is_cas(op: &Op) -> bool {
+    op_name(op.op.opcode) == Some("cas")
+}
+
+pub fn races(program: &Program) -> (Vec<Context>, Vec<Race>) {
+    let ops = &program.ops;
+    if ops.is_empty() {
+        return (Vec::new(), Vec::new());
+    }
+    let graph = OpGraph::new(ops);
+    let symbols = Symbols::from_program(program);
+    let may_write = graph.may_write(ops);
+    let entries = OpGraph::entries(ops);
+    let everywhere = known_values(ops, &graph, &may_write, &entries, CoreId::Any);
+    let multicore = everywhere
+        .keys()
+        .any(|pc| op_name(ops[*pc].op.opcode) == Some("crcfg"));
+
+    let mut contexts: Vec<(Context, CoreId)> = Vec::new();
+    if multicore {
+        let core = |name: &str, repeated| Context {
+            name: name.to_string(),
+            entry: 0,
+            repeated,
+        };
+        contexts.push((core("core 0", false), CoreId::Zero));
+        contexts.push((core("cores 1+", true), CoreId::NotZero));
+    } else {
+        let main = Context {
+            name: "main".to_string(),
+            entry: 0,
+            repeated: false,
+        };
+        contexts.push((main, CoreId::Zero));
+    }
+    let mut known = Vec::new();
+    for (_, core) in contexts.iter() {
+        known.push(known_values(ops, &graph, &may_write, &[0], *core));
+    }
+    let mut spawned_by: HashMap<usize, Vec<usize>> = HashMap::new();
+    for pc in 0..ops.len() {
+        if let Flow::Spawn(Some(target)) = flow(ops, pc) {
+            spawned_by.entry(target).or_default().push(pc);
+        }
+    }
+    for entry in entries.into_iter().skip(1) {
+        let spawned_by = &spawned_by[&entry];
+        // spawned from several places, or by cores that run side by side
+        let repeated = spawned_by.len() > 1
+            || spawned_by.iter().any(|pc| {
+                contexts
+                    .iter()
+                    .zip(known.iter())
+                    .any(|((c, _), k)| c.repeated && k.contains_key(pc))
+            });
+        let name = match symbols.label_at(entry) {
+            Some((label, 0)) => label.to_string(),
+            _ => format!("pc {}", entry),
+        };
+        contexts.push((
+            Context {
+                name,
+                entry,
+                repeated,
+            },
+            CoreId::Any,
+        ));
+        known.push(known_values(ops, &graph, &may_write, &[entry], CoreId::Any));
+    }
+
+    let mut writes: BTreeMap<usize, Vec<Write>> = BTreeMap::new();
+    for (context, known) in known.iter().enumerate() {
+        for access in accesses(ops, known).into_iter().filter(|a| a.write) {
+            if let Some(addr) = access.address {
+                writes.entry(addr).or_default().push(Write {
+                    context,
+                    pc: access.pc,
+                    cas: is_cas(&ops[access.pc]),
+                });
+            }
+        }
+    }
+
+    let mut races: Vec<Race> = Vec::new();
+    for (addr, writes) in writes.iter() {
+        let writers: BTreeSet<usize> = writes.iter().map(|w| w.context).collect();
+        let shared = writers.len() > 1 || writers.iter().any(|c| contexts[*c].0.repeated);
+        let Some(store) = writes.iter().filter(|w| !w.cas).map(|w| w.pc).min() else {
+            continue;
+        };
+        if !shared {
+            continue;
+        }
+        let writers: Vec<usize> = writers.into_iter().collect();
+        match races.last_mut() {
+            Some(race) if race.range.1 + 1 == *addr && race.writers == writers => {
+                race.range.1 = *addr;
+                race.pc = race.pc.min(store);
+            }
+            _ => races.push(Race {
+                range: (*addr, *addr),
+                writers,
+                pc: store,
+            }),
+        }
+    }
+    (contexts.into_iter().map(|(c, _)| c).collect(), races)
+}
+
+pub fn findings(program: &Program) -> Vec<Finding> {
+    let (contexts, races) = races(program);
+    let mut findings: Vec<Finding> = races
+        .iter()
+        .map(|race| {
+            let names: Vec<&str> = race
+                .writers
+                .iter()
+                .map(|c| contexts[*c].name.as_str())
+                .collect();
+            let writers = match names.as_slice() {
+                [only] => format!("several copies of {} at once", only),
+                _ => names.join(" and "),
+            };
+            Finding {
+                pc: race.pc,
+                message: format!(
+                    "{} is written by {} without cas",
+                    describe_range(race.range),
+                    writers
+                ),
+            }
+        })
+        .collect();
+    findings.sort_by_key(|f| f.pc);
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::assemble;
+
+    #[test]
+    fn test_races() {
+        let program = assemble(
+            "li x1, 3
+crcfg zero, x1
+crid x1
+bne zero, x1, OTHERS
+li x2, 1
+store x2, zero[$VIDEO_ENABLE]
+store x2, zero, 0x300
+spawn zero, zero, WORKER
+OTHERS:
+store x2, zero, 0x400
+li x5, 0x500
+li x6, 1
+cas x7, x5, x6
+jal zero, END
+WORKER:
+store x2, zero, 0x300
+store x2, zero, 0x301
+yield zero, 2
+END:
+",
+        )
+        .unwrap();
+        let (contexts, races) = races(&program);
+        let contexts: Vec<(&str, usize, bool)> = contexts
+            .iter()
+            .map(|c| (c.name.as_str(), c.entry, c.repeated))
+            .collect();
+        assert_eq!(
+            contexts,
+            vec![
+                ("core 0", 0, false),
+                ("cores 1+", 0, true),
+                ("WORKER", 13, false)
+            ]
+        );
+        assert_eq!(
+            races,
+            vec![
+                Race {
+                    range: (0x300, 0x300),
+                    writers: vec![0, 2],
+                    pc: 6
+                },
+                Race {
+                    range: (0x400, 0x400),
+                    writers: vec![0, 1],
+                    pc: 8
+                },
+            ]
+        );
+        let messages: Vec<String> = findings(&program).into_iter().map(|f| f.message).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "0x0300 is written by core 0 and WORKER without cas",
+                "0x0400 is written by core 0 and cores 1+ without cas"
+            ]
+        );
+    }
+}
@@ -1,5 +1,8 @@
-use crate::compression::compress_bytes;
-use crate::ops::Op;
+use crate::compression::{compress_bytes, decompress_bytes};
+use crate::ops::{COp, Op};
+use crate::sourcemap::SourceMap;
+use crate::symbols::Symbols;
+use std::fmt;
 use std::vec::Vec;
 
 use prost::Message;
@@ -14,6 +17,35 @@ const OPREGSIZE: usize = 4;
 const OPIMMSIZE: usize = 8;
 // 3 * u32
 const SIZESIZE: usize = 12;
+// magic + uncompressed size + compressed size
+const CART_MAGIC: &[u8] = b"ECJRV006";
+const HEADERSIZE: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CartErr {
+    BadMagic,
+    Truncated,
+    Decompress(String),
+    Decode(String),
+}
+
+impl fmt::Display for CartErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartErr::BadMagic => write!(f, "Not an ECJR cartridge (bad magic)"),
+            CartErr::Truncated => write!(f, "Cartridge data is truncated"),
+            CartErr::Decompress(s) => write!(f, "Failed to decompress cartridge: {}", s),
+            CartErr::Decode(s) => write!(f, "Failed to decode cartridge: {}", s),
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, CartErr> {
+    match data.get(offset..offset + 4) {
+        Some(bytes) => Ok(u32::from_le_bytes(bytes.try_into().unwrap())),
+        None => Err(CartErr::Truncated),
+    }
+}
 
 // The program serialization format is:
 // [4 bytes: LE u32 of opcount]
@@ -59,10 +91,72 @@ pub fn serialize_ops(oplist: &[Op]) -> Vec<u8> {
     data
 }
 
+// Inverse of serialize_ops
+pub fn deserialize_ops(data: &[u8]) -> Result<Vec<Op>, CartErr> {
+    let opcount = read_u32(data, 0)? as usize;
+    let reg_offset = read_u32(data, 4)? as usize;
+    let imm_offset = read_u32(data, 8)? as usize;
+    let regs = data
+        .get(reg_offset..reg_offset + opcount * OPREGSIZE)
+        .ok_or(CartErr::Truncated)?;
+    let imms = data
+        .get(imm_offset..imm_offset + opcount * OPIMMSIZE)
+        .ok_or(CartErr::Truncated)?;
+
+    let ops = regs
+        .chunks_exact(OPREGSIZE)
+        .zip(imms.chunks_exact(OPIMMSIZE))
+        .map(|(reg, imm)| Op {
+            op: COp {
+                opcode: reg[0],
+                rd: reg[1],
+                rs1: reg[2],
+                rs2: reg[3],
+            },
+            imm: f64::from_le_bytes(imm.try_into().unwrap()),
+        })
+        .collect();
+    Ok(ops)
+}
+
+pub fn is_cartridge(data: &[u8]) -> bool {
+    data.starts_with(CART_MAGIC)
+}
+
+// Size of the cartridge body before and after compression (the same when
+// it's stored uncompressed)
+pub fn body_sizes(data: &[u8]) -> Result<(usize, usize), CartErr> {
+    if !is_cartridge(data) {
+        return Err(CartErr::BadMagic);
+    }
+    let uncompressed_size = read_u32(data, CART_MAGIC.len())? as usize;
+    match read_u32(data, CART_MAGIC.len() + 4)? as usize {
+        0 => Ok((uncompressed_size, uncompressed_size)),
+        compressed_size => Ok((uncompressed_size, compressed_size)),
+    }
+}
+
+pub fn unpack_cartridge(data: &[u8]) -> Result<cart::Cartridge, CartErr> {
+    if !is_cartridge(data) {
+        return Err(CartErr::BadMagic);
+    }
+    let compressed_size = read_u32(data, CART_MAGIC.len() + 4)? as usize;
+    let body = &data[HEADERSIZE.min(data.len())..];
+    let body = if compressed_size > 0 {
+        let compressed = body.get(..compressed_size).ok_or(CartErr::Truncated)?;
+        decompress_bytes(compressed).map_err(|e| CartErr::Decompress(e.to_string()))?
+    } else {
+        body.to_vec()
+    };
+    cart::Cartridge::decode(body.as_slice()).map_err(|e| CartErr::Decode(e.to_string()))
+}
+
 pub fn pack_cartridge(
     metadata: Option<String>,
     videorom: Option<Vec<u8>>,
     program: &[Op],
+    source_map: Option<&SourceMap>,
+    symbols: Option<&Symbols>,
     compress: bool,
 ) -> Vec<u8> {
     let metadata = metadata.unwrap_or_else(|| "{}".to_string());
@@ -71,6 +165,8 @@ pub fn pack_cartridge(
         metadata,
         videorom,
         program: serialize_ops(program),
+        source_map: source_map.map(|map| map.to_proto()),
+        symbols: symbols.map(|symbols| symbols.to_proto()),
     };
 
     let mut serialized_body = Vec::with_capacity(cartridge_body.encoded_len());
@@ -86,11 +182,49 @@ pub fn pack_cartridge(
         (0u32, serialized_body)
     };
 
-    let mut final_data: Vec<u8> = Vec::with_capacity(16 + final_body.len());
-    final_data.extend_from_slice("ECJRV006".as_bytes());
+    let mut final_data: Vec<u8> = Vec::with_capacity(HEADERSIZE + final_body.len());
+    final_data.extend_from_slice(CART_MAGIC);
     final_data.extend_from_slice(&uncompressed_size.to_le_bytes());
     final_data.extend_from_slice(&compressed_size.to_le_bytes());
     final_data.extend(final_body);
 
     final_data
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{assemble_file, parse};
+
+    #[test]
+    fn test_roundtrip() {
+        let ops = parse("li x1, 3.5\nadd x2, x1, x1\njal zero, -2\n").unwrap();
+        assert_eq!(deserialize_ops(&serialize_ops(&ops)), Ok(ops.clone()));
+
+        for compress in [false, true] {
+            let data = pack_cartridge(None, Some(vec![1, 2, 3]), &ops, None, None, compress);
+            let cart = unpack_cartridge(&data).unwrap();
+            assert_eq!(cart.metadata, "{}");
+            assert_eq!(cart.source_map, None);
+            assert_eq!(cart.symbols, None);
+            assert_eq!(cart.videorom, vec![1, 2, 3]);
+            let (_, stored) = body_sizes(&data).unwrap();
+            assert_eq!(stored + HEADERSIZE, data.len());
+            assert_eq!(deserialize_ops(&cart.program), Ok(ops.clone()));
+        }
+
+        let program = assemble_file("nop\nEND:\nnop\n", "x.asm").unwrap();
+        let map = SourceMap::from_program(&program);
+        let symbols = Symbols::from_program(&program);
+        let data = pack_cartridge(None, None, &program.ops, Some(&map), Some(&symbols), true);
+        let cart = unpack_cartridge(&data).unwrap();
+        assert_eq!(
+            cart.source_map.map(|m| SourceMap::from_proto(&m)),
+            Some(map)
+        );
+        assert_eq!(cart.symbols.map(|s| Symbols::from_proto(&s)), Some(symbols));
+
+        assert_eq!(unpack_cartridge(b"nope"), Err(CartErr::BadMagic));
+        assert_eq!(deserialize_ops(&[1, 0, 0]), Err(CartErr::Truncated));
+    }
+}
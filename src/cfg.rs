@@ -0,0 +1,66 @@
+use asmjr::analysis::cfg::{Cfg, EdgeKind};
+use asmjr::parser;
+use clap::Args;
+use std::fs::read_to_string;
+
+#[derive(Args, Debug)]
+pub struct CfgArgs {
+    /// Assembly source file
+    #[clap(value_parser)]
+    source: String,
+
+    /// Print Graphviz dot instead of a block list
+    #[clap(long, action)]
+    dot: bool,
+}
+
+fn kind_name(kind: EdgeKind) -> &'static str {
+    match kind {
+        EdgeKind::Fallthrough => "fallthrough",
+        EdgeKind::Taken => "taken",
+        EdgeKind::Jump => "jump",
+        EdgeKind::Call => "call",
+        EdgeKind::AfterCall => "after call",
+        EdgeKind::Spawn => "spawn",
+    }
+}
+
+pub fn cfg(args: CfgArgs) -> i32 {
+    let program = match read_to_string(&args.source) {
+        Ok(src) => parser::assemble_file(&src, &args.source).map_err(|e| e.to_string()),
+        Err(e) => Err(format!("Failed to read {}: {}", args.source, e)),
+    };
+    let program = match program {
+        Ok(program) => program,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+
+    let cfg = Cfg::from_program(&program);
+    if args.dot {
+        print!("{}", cfg.to_dot());
+        return 0;
+    }
+    for block in cfg.blocks.iter() {
+        let succs: Vec<String> = block
+            .succs
+            .iter()
+            .map(|edge| format!("{} ({})", cfg.blocks[edge.to].name(), kind_name(edge.kind)))
+            .collect();
+        let succs = if succs.is_empty() {
+            "-".to_string()
+        } else {
+            succs.join(", ")
+        };
+        println!(
+            "{} [pc {}..{}] -> {}",
+            block.name(),
+            block.start,
+            block.last(),
+            succs
+        );
+    }
+    0
+}
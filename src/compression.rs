@@ -1,4 +1,4 @@
-use zstd::stream::copy_encode;
+use zstd::stream::{copy_decode, copy_encode};
 
 const COMPRESSION_LEVEL: i32 = 18;
 
@@ -7,3 +7,9 @@ pub fn compress_bytes(src: &Vec<u8>) -> Vec<u8> {
     copy_encode(src.as_slice(), &mut dest, COMPRESSION_LEVEL).unwrap();
     dest
 }
+
+pub fn decompress_bytes(src: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut dest: Vec<u8> = Vec::new();
+    copy_decode(src, &mut dest)?;
+    Ok(dest)
+}
@@ -0,0 +1,373 @@
+use crate::ops::{Op, OpErr};
+use std::vec::Vec;
+
+// Structured control flow: .if/.else/.endif, .while/.endwhile and
+// .loop/.endloop, with .break and .continue for the innermost loop. Each
+// directive turns into branch and jump ops, given as tokens so they're
+// assembled like any other op. Jumps back to the top of a loop know their
+// offset already; forward ones get 0 and are patched once the directive
+// that ends the block shows up, so the blocks need no labels of their own.
+
+// Comparisons .if and .while understand (cmp_op in the grammar), the
+// branch taken when one holds and whether its registers go the other way
+// round (a <= b is b >= a)
+const CONDITIONS: &[(&str, &str, bool)] = &[
+    ("<=", "bge", true),
+    (">=", "bge", false),
+    ("==", "beq", false),
+    ("!=", "bne", false),
+    ("<", "blt", false),
+    (">", "blt", true),
+];
+
+#[derive(Debug)]
+enum Block {
+    If {
+        // the branch skipping the "then" part, until an .else takes it
+        skip: Option<usize>,
+        // jumps to the .endif
+        exits: Vec<usize>,
+    },
+    While {
+        top: usize,
+        exits: Vec<usize>,
+    },
+    Loop {
+        counter: String,
+        top: usize,
+        exits: Vec<usize>,
+        // jumps to the counter decrement
+        continues: Vec<usize>,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct Control {
+    blocks: Vec<Block>,
+}
+
+// How many ops a control directive turns into, or None if it isn't one
+pub fn op_count(name: &str, args: &[&str]) -> Option<usize> {
+    match name {
+        "if" | "else" | "while" | "endwhile" | "break" | "continue" => Some(1),
+        "endif" => Some(0),
+        "loop" => Some(args.len().clamp(1, 2)),
+        "endloop" => Some(2),
+        _ => None,
+    }
+}
+
+// The branch to take when "a OP b" doesn't hold, as op name and registers.
+// The grammar splits a condition into its operands and comparison.
+fn negated(args: &[&str]) -> Result<[String; 3], OpErr> {
+    let invalid = || OpErr::InvalidBlock(format!("\"{}\" is not a comparison", args.join(" ")));
+    let [a, op, b] = args else {
+        return Err(invalid());
+    };
+    let (_, branch, swap) = CONDITIONS
+        .iter()
+        .find(|(cmp, _, _)| cmp == op)
+        .ok_or_else(invalid)?;
+    let (a, b) = if *swap { (b, a) } else { (a, b) };
+    let opposite = match *branch {
+        "blt" => "bge",
+        "bge" => "blt",
+        "beq" => "bne",
+        _ => "beq",
+    };
+    Ok([opposite.to_string(), a.to_string(), b.to_string()])
+}
+
+fn jump(from: usize, to: usize) -> Vec<String> {
+    let offset = to as i64 - from as i64;
+    vec!["jal".to_string(), "zero".to_string(), offset.to_string()]
+}
+
+impl Control {
+    // how many blocks are open
+    pub fn depth(&self) -> usize {
+        self.blocks.len()
+    }
+
+    // Ops for a control directive at pc, as tokens. Forward jumps from
+    // earlier directives that now know their target are patched in ops.
+    pub fn lower(
+        &mut self,
+        name: &str,
+        args: &[&str],
+        pc: usize,
+        ops: &mut [Op],
+    ) -> Result<Vec<Vec<String>>, OpErr> {
+        let mismatched =
+            |what: &str| OpErr::InvalidBlock(format!("{} without a matching block", what));
+        let patch = |ops: &mut [Op], from: &[usize], to: usize| {
+            for at in from.iter() {
+                ops[*at].imm = to as f64 - *at as f64;
+            }
+        };
+        let no_args = |args: &[&str]| match args.len() {
+            0 => Ok(()),
+            n => Err(OpErr::InvalidArgumentCount(n, 0)),
+        };
+        match name {
+            "if" => {
+                let [branch, a, b] = negated(args)?;
+                self.blocks.push(Block::If {
+                    skip: Some(pc),
+                    exits: Vec::new(),
+                });
+                Ok(vec![vec![branch, a, b, "0".to_string()]])
+            }
+            "else" => {
+                no_args(args)?;
+                match self.blocks.last_mut() {
+                    Some(Block::If { skip, exits }) if skip.is_some() => {
+                        // the "then" part ends with a jump over the "else" part
+                        patch(ops, &[skip.take().unwrap()], pc + 1);
+                        exits.push(pc);
+                        Ok(vec![jump(pc, pc)])
+                    }
+                    _ => Err(mismatched(".else")),
+                }
+            }
+            "endif" => {
+                no_args(args)?;
+                match self.blocks.pop() {
+                    Some(Block::If { skip, exits }) => {
+                        patch(ops, &exits, pc);
+                        patch(ops, skip.as_slice(), pc);
+                        Ok(Vec::new())
+                    }
+                    _ => Err(mismatched(".endif")),
+                }
+            }
+            "while" => {
+                let [branch, a, b] = negated(args)?;
+                self.blocks.push(Block::While {
+                    top: pc,
+                    exits: vec![pc],
+                });
+                Ok(vec![vec![branch, a, b, "0".to_string()]])
+            }
+            "endwhile" => {
+                no_args(args)?;
+                match self.blocks.pop() {
+                    Some(Block::While { top, exits }) => {
+                        patch(ops, &exits, pc + 1);
+                        Ok(vec![jump(pc, top)])
+                    }
+                    _ => Err(mismatched(".endwhile")),
+                }
+            }
+            "loop" => {
+                // a bare number would be taken as register xN
+                if args
+                    .first()
+                    .is_some_and(|arg| arg.starts_with(|c: char| c.is_ascii_digit()))
+                {
+                    let msg = format!(".loop counts down a register, like .loop t0, {}", args[0]);
+                    return Err(OpErr::InvalidBlock(msg));
+                }
+                let (counter, start) = match args {
+                    [counter] => (counter.to_string(), Vec::new()),
+                    [counter, count] => (
+                        counter.to_string(),
+                        vec![vec![
+                            "li".to_string(),
+                            counter.to_string(),
+                            count.to_string(),
+                        ]],
+                    ),
+                    _ => return Err(OpErr::InvalidArgumentCount(args.len(), 2)),
+                };
+                let top = pc + start.len();
+                let mut lowered = start;
+                // leave once the counter isn't above zero, so negative and
+                // fractional counts end too
+                lowered.push(vec![
+                    "bge".to_string(),
+                    "zero".to_string(),
+                    counter.clone(),
+                    "0".to_string(),
+                ]);
+                self.blocks.push(Block::Loop {
+                    counter,
+                    top,
+                    exits: vec![top],
+                    continues: Vec::new(),
+                });
+                Ok(lowered)
+            }
+            "endloop" => {
+                no_args(args)?;
+                match self.blocks.pop() {
+                    Some(Block::Loop {
+                        counter,
+                        top,
+                        exits,
+                        continues,
+                    }) => {
+                        patch(ops, &continues, pc);
+                        patch(ops, &exits, pc + 2);
+                        let decrement = vec![
+                            "subi".to_string(),
+                            counter.clone(),
+                            counter,
+                            "1".to_string(),
+                        ];
+                        Ok(vec![decrement, jump(pc + 1, top)])
+                    }
+                    _ => Err(mismatched(".endloop")),
+                }
+            }
+            "break" | "continue" => {
+                no_args(args)?;
+                let innermost = self
+                    .blocks
+                    .iter_mut()
+                    .rev()
+                    .find(|block| !matches!(block, Block::If { .. }));
+                match (name, innermost) {
+                    ("break", Some(Block::While { exits, .. } | Block::Loop { exits, .. })) => {
+                        exits.push(pc);
+                        Ok(vec![jump(pc, pc)])
+                    }
+                    ("continue", Some(Block::While { top, .. })) => Ok(vec![jump(pc, *top)]),
+                    ("continue", Some(Block::Loop { continues, .. })) => {
+                        continues.push(pc);
+                        Ok(vec![jump(pc, pc)])
+                    }
+                    _ => Err(OpErr::InvalidBlock(format!(".{} outside of a loop", name))),
+                }
+            }
+            _ => Err(OpErr::InvalidDirective(name.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::assemble;
+    use crate::vm::Vm;
+
+    fn run(src: &str) -> Vm {
+        let program = assemble(src).unwrap();
+        let mut vm = Vm::new(program.ops.clone());
+        while !vm.is_halted() {
+            vm.run_frame().unwrap();
+        }
+        vm
+    }
+
+    #[test]
+    fn test_control() {
+        // sum of the odd numbers below 10, skipping 5, stopping past 7
+        let vm = run("li x5, 0
+li x6, 0
+li x7, 10
+.while x5 < x7
+addi x5, x5, 1
+andi x8, x5, 1
+.if x8 == zero
+.continue
+.endif
+li x9, 5
+.if x5 != x9
+add x6, x6, x5
+.else
+.continue
+.endif
+li x9, 7
+.if x5 >= x9
+.break
+.endif
+.endwhile
+store x6, zero, 0x300
+store x5, zero, 0x301
+");
+        assert_eq!(vm.memory[0x300], 1.0 + 3.0 + 7.0);
+        assert_eq!(vm.memory[0x301], 7.0);
+
+        // nested loops, counting down registers
+        let vm = run("li x5, 0
+.loop x6, 3
+.loop x7, 4
+.if x7 <= x6
+.continue
+.endif
+addi x5, x5, 1
+.endloop
+.endloop
+store x5, zero, 0x300
+");
+        assert_eq!(vm.memory[0x300], 1.0 + 2.0 + 3.0);
+
+        // counts of zero or less skip the body, and fractional ones round up
+        let vm = run("li x5, 0
+.loop x6, -2
+addi x5, x5, 1
+.endloop
+.loop x6, 0
+addi x5, x5, 1
+.endloop
+.loop x6, 2.5
+addi x5, x5, 10
+.endloop
+store x5, zero, 0x300
+");
+        assert_eq!(vm.memory[0x300], 30.0);
+
+        // labels after blocks are where they should be, and virtual
+        // registers work as loop counters
+        let program = assemble(
+            "jal ra, F
+jal zero, END
+.func F
+li %n, 0
+.loop %i, 5
+.if %i > %n
+addi %n, %n, 2
+.endif
+.endloop
+mv x5, %n
+ret
+.endfunc
+END:
+store x5, zero, 0x300
+",
+        )
+        .unwrap();
+        assert_eq!(program.labels["END"], 11.0);
+        let mut vm = Vm::new(program.ops.clone());
+        while !vm.is_halted() {
+            vm.run_frame().unwrap();
+        }
+        assert_eq!(vm.memory[0x300], 4.0);
+
+        // comments after a directive aren't part of it, and comparisons
+        // don't need spaces
+        let vm = run(".loop x5, 3      // three times
+.if x5<=x6      # never, x6 is 0
+.break          // would skip the store
+.endif
+addi x7, x7, 2
+.endloop
+store x7, zero, 0x300
+");
+        assert_eq!(vm.memory[0x300], 6.0);
+
+        for bad in [
+            ".if x5 < x6",
+            ".endif",
+            ".while x5 ~ x6\n.endwhile",
+            ".while x5 < x6 < x7\n.endwhile",
+            ".break",
+            ".loop 8\n.endloop",
+            ".if x5 < x6\n.endwhile",
+            ".if x5 < x6\n.else\n.else\n.endif",
+            ".func F\n.if x5 < x6\nret\n.endfunc\n.endif",
+        ] {
+            assert!(assemble(bad).is_err(), "{}", bad);
+        }
+    }
+}
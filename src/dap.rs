@@ -0,0 +1,705 @@
+use asmjr::debugger::{read_source, Debugger, Stop};
+use asmjr::ops::parse_immediate;
+use asmjr::vm::{CoState, Coroutine};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+
+// Debug Adapter Protocol server for ECJR assembly, speaking DAP over stdio.
+// Every coroutine on every core is a DAP thread. Execution is all-stop, so
+// stepping a thread steps its core, and the others keep their usual pace.
+
+// variablesReference of the global memmap scope; per-thread scopes are
+// derived from the thread id, like frame ids
+const MEMMAP_REF: i64 = 1;
+const REGISTERS_SCOPE: i64 = 2;
+const STACK_SCOPE: i64 = 3;
+// memory cells shown from sp upwards
+const STACK_CELLS: usize = 16;
+
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut body = vec![0; length?];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn number(value: f64) -> String {
+    value.to_string()
+}
+
+struct Server {
+    seq: i64,
+    dbg: Option<Debugger>,
+    program: PathBuf,
+    // 1-based source lines asked for, kept so they can be set before launch
+    breakpoint_lines: Vec<i64>,
+    stop_on_entry: bool,
+    configured: bool,
+    running: bool,
+    threads: HashMap<(usize, i64), i64>,
+}
+
+impl Server {
+    fn new() -> Server {
+        Server {
+            seq: 0,
+            dbg: None,
+            program: PathBuf::new(),
+            breakpoint_lines: Vec::new(),
+            stop_on_entry: false,
+            configured: false,
+            running: false,
+            threads: HashMap::new(),
+        }
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        let mut out = io::stdout().lock();
+        let _ = write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = out.flush();
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({"type": "event", "event": event, "body": body}));
+    }
+
+    fn dbg(&self) -> Result<&Debugger, String> {
+        self.dbg
+            .as_ref()
+            .ok_or_else(|| "No program launched".to_string())
+    }
+
+    fn dbg_mut(&mut self) -> Result<&mut Debugger, String> {
+        self.dbg
+            .as_mut()
+            .ok_or_else(|| "No program launched".to_string())
+    }
+
+    // Thread ids stay the same for as long as the coroutine exists
+    fn thread_id(&mut self, core: usize, coroutine: i64) -> i64 {
+        let next = self.threads.len() as i64 + 1;
+        *self.threads.entry((core, coroutine)).or_insert(next)
+    }
+
+    fn thread(&self, id: i64) -> Result<(usize, i64), String> {
+        self.threads
+            .iter()
+            .find(|(_, thread)| **thread == id)
+            .map(|(key, _)| *key)
+            .ok_or_else(|| format!("Unknown thread {}", id))
+    }
+
+    fn coroutine(&self, thread: i64) -> Result<&Coroutine, String> {
+        let (core, id) = self.thread(thread)?;
+        self.dbg()?
+            .vm
+            .cores
+            .get(core)
+            .and_then(|c| c.coroutines.iter().find(|co| co.id == id))
+            .ok_or_else(|| format!("Thread {} has exited", thread))
+    }
+
+    fn coroutine_mut(&mut self, thread: i64) -> Result<&mut Coroutine, String> {
+        let (core, id) = self.thread(thread)?;
+        self.dbg_mut()?
+            .vm
+            .cores
+            .get_mut(core)
+            .and_then(|c| c.coroutines.iter_mut().find(|co| co.id == id))
+            .ok_or_else(|| format!("Thread {} has exited", thread))
+    }
+
+    fn source(&self) -> Value {
+        let name = self
+            .program
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        json!({"name": name, "path": self.program.to_string_lossy()})
+    }
+
+    fn resolve_breakpoints(&mut self) -> Vec<Value> {
+        let lines = self.breakpoint_lines.clone();
+        let dbg = match self.dbg.as_mut() {
+            Some(dbg) => dbg,
+            None => {
+                return lines
+                    .iter()
+                    .map(|line| json!({"verified": false, "line": line}))
+                    .collect()
+            }
+        };
+        dbg.breakpoints.clear();
+        let mut result = Vec::new();
+        for line in lines {
+            match dbg.code_location(&line.to_string()) {
+                Ok(pc) => {
+                    dbg.breakpoints.insert(pc);
+                    let actual = dbg.line_of(pc).map(|l| l + 1).unwrap_or(line as usize);
+                    result.push(json!({"id": pc, "verified": true, "line": actual}));
+                }
+                Err(e) => result.push(json!({"verified": false, "line": line, "message": e})),
+            }
+        }
+        result
+    }
+
+    // The thread a stop happened on, for the stopped event
+    fn stop_thread(&mut self, stop: &Stop) -> i64 {
+        let (core, coroutine) = match (stop, self.dbg.as_ref()) {
+            (Stop::Breakpoint(core, co, _), _) => (*core, *co),
+            (Stop::Fault(e), _) => (e.core, e.coroutine),
+            (_, Some(dbg)) => (dbg.core, dbg.coroutine().map(|co| co.id).unwrap_or(0)),
+            (_, None) => (0, 0),
+        };
+        self.thread_id(core, coroutine)
+    }
+
+    fn stopped(&mut self, reason: &str, thread: i64, text: Option<String>) {
+        let mut body = json!({"reason": reason, "threadId": thread, "allThreadsStopped": true});
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body);
+    }
+
+    fn report(&mut self, stop: Stop) {
+        let thread = self.stop_thread(&stop);
+        match stop {
+            Stop::Step => self.stopped("step", thread, None),
+            Stop::Breakpoint(..) => self.stopped("breakpoint", thread, None),
+            Stop::Watchpoint(addr, old, new) => self.stopped(
+                "data breakpoint",
+                thread,
+                Some(format!("0x{:04x}: {} -> {}", addr, old, new)),
+            ),
+            Stop::Frame(_) | Stop::Limit(_) => self.stopped("pause", thread, None),
+            Stop::Fault(e) => {
+                let text = e.to_string();
+                self.event(
+                    "output",
+                    json!({"category": "stderr", "output": text.clone() + "\n"}),
+                );
+                self.stopped("exception", thread, Some(text));
+            }
+            Stop::Halted => {
+                self.event("exited", json!({"exitCode": 0}));
+                self.event("terminated", json!({}));
+            }
+        }
+    }
+
+    // Once launched and configured, the program starts
+    fn start(&mut self) {
+        if self.dbg.is_none() || !self.configured {
+            return;
+        }
+        if self.stop_on_entry {
+            let thread = self.thread_id(0, 0);
+            self.stopped("entry", thread, None);
+        } else {
+            self.running = true;
+        }
+    }
+
+    // Run one frame of a continue, so pause requests get a look in between
+    fn run_chunk(&mut self) {
+        let stop = match self.dbg.as_mut() {
+            Some(dbg) => dbg.frame(),
+            None => Stop::Halted,
+        };
+        if let Stop::Frame(_) = stop {
+            return;
+        }
+        self.running = false;
+        self.report(stop);
+    }
+
+    // Select the core of the thread to step, and report where it ended up
+    fn step(&mut self, args: &Value, step: fn(&mut Debugger) -> Stop) -> Result<Value, String> {
+        let (core, _) = self.thread(args["threadId"].as_i64().unwrap_or(0))?;
+        let dbg = self.dbg_mut()?;
+        dbg.core = core;
+        let stop = step(dbg);
+        self.report(stop);
+        Ok(json!({}))
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"]
+            .as_str()
+            .ok_or_else(|| "launch needs a program".to_string())?;
+        let src = read_source(program)?;
+        let mut dbg = Debugger::new(&src)?;
+        if let Some(clock) = args["clock"].as_u64() {
+            dbg.vm.set_clock(clock);
+        }
+        self.program = PathBuf::from(program);
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.dbg = Some(dbg);
+        self.resolve_breakpoints();
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["source"]["path"].as_str().unwrap_or_default();
+        let lines: Vec<i64> = args["breakpoints"]
+            .as_array()
+            .map(|bps| bps.iter().filter_map(|bp| bp["line"].as_i64()).collect())
+            .unwrap_or_default();
+        if self.dbg.is_some() && !same_file(Path::new(path), &self.program) {
+            let breakpoints: Vec<Value> = lines
+                .iter()
+                .map(|line| {
+                    json!({"verified": false, "line": line, "message": "Not part of the program"})
+                })
+                .collect();
+            return Ok(json!({ "breakpoints": breakpoints }));
+        }
+        self.breakpoint_lines = lines;
+        Ok(json!({"breakpoints": self.resolve_breakpoints()}))
+    }
+
+    fn threads(&mut self) -> Result<Value, String> {
+        let mut live = Vec::new();
+        for core in self.dbg()?.vm.cores.iter() {
+            for co in core.coroutines.iter() {
+                live.push((core.id, co.id, co.state));
+            }
+        }
+        let threads: Vec<Value> = live
+            .into_iter()
+            .map(|(core, id, state)| {
+                let suffix = if state == CoState::Halted {
+                    " (halted)"
+                } else {
+                    ""
+                };
+                json!({
+                    "id": self.thread_id(core, id),
+                    "name": format!("core {} coroutine {}{}", core, id, suffix),
+                })
+            })
+            .collect();
+        Ok(json!({ "threads": threads }))
+    }
+
+    fn frame(&self, id: i64, name: String, pc: usize) -> Value {
+        let dbg = self.dbg.as_ref().unwrap();
+        let line = dbg.line_of(pc).unwrap_or(dbg.source.len()) + 1;
+        json!({
+            "id": id,
+            "name": name,
+            "source": self.source(),
+            "line": line,
+            "column": 1,
+            "instructionPointerReference": pc.to_string(),
+        })
+    }
+
+    fn routine(&self, pc: usize) -> String {
+        match self.dbg.as_ref().and_then(|dbg| dbg.symbols.label_at(pc)) {
+            Some((label, 0)) => label.to_string(),
+            Some((label, offset)) => format!("{}+{}", label, offset),
+            None => format!("pc {}", pc),
+        }
+    }
+
+    // There's no real call stack, only the return address in ra, so this
+    // shows the current pc and where ra would return to
+    fn stack_trace(&self, args: &Value) -> Result<Value, String> {
+        let thread = args["threadId"].as_i64().unwrap_or(0);
+        let co = self.coroutine(thread)?;
+        let mut frames = vec![self.frame(thread * 10, self.routine(co.pc), co.pc)];
+        let ra = co.regs[1];
+        let len = self.dbg()?.program.ops.len();
+        if ra.fract() == 0.0 && ra >= 1.0 && (ra as usize) < len {
+            let name = format!("{} (ra)", self.routine(ra as usize));
+            frames.push(self.frame(thread * 10 + 1, name, ra as usize));
+        }
+        Ok(json!({"stackFrames": frames, "totalFrames": frames.len()}))
+    }
+
+    fn scopes(&self, args: &Value) -> Result<Value, String> {
+        let thread = args["frameId"].as_i64().unwrap_or(0) / 10;
+        Ok(json!({"scopes": [
+            {"name": "Registers", "variablesReference": thread * 10 + REGISTERS_SCOPE, "expensive": false},
+            {"name": "Stack", "variablesReference": thread * 10 + STACK_SCOPE, "expensive": false},
+            {"name": "Memory map", "variablesReference": MEMMAP_REF, "expensive": false},
+        ]}))
+    }
+
+    // $ memmap names by address, without the record layouts
+    fn memmap(&self) -> Result<Vec<(String, usize)>, String> {
+        let mut names: Vec<(String, usize)> = self
+            .dbg()?
+            .program
+            .constants
+            .iter()
+            .filter(|(name, _)| name.starts_with('$') && !name.contains('.'))
+            .map(|(name, addr)| (name.clone(), *addr as usize))
+            .collect();
+        names.sort_by_key(|(_, addr)| *addr);
+        Ok(names)
+    }
+
+    fn register_name(&self, reg: usize) -> String {
+        let dbg = self.dbg.as_ref().unwrap();
+        let aliases = dbg.register_aliases(reg as u8);
+        if aliases.is_empty() {
+            format!("x{}", reg)
+        } else {
+            format!("x{} ({})", reg, aliases.join(", "))
+        }
+    }
+
+    fn memory_variable(&self, name: String, addr: usize) -> Value {
+        let value = self.dbg.as_ref().unwrap().vm.memory[addr];
+        json!({
+            "name": name,
+            "value": number(value),
+            "memoryReference": addr.to_string(),
+            "variablesReference": 0,
+        })
+    }
+
+    fn variables(&self, args: &Value) -> Result<Value, String> {
+        let reference = args["variablesReference"].as_i64().unwrap_or(0);
+        let variables: Vec<Value> = if reference == MEMMAP_REF {
+            self.memmap()?
+                .into_iter()
+                .map(|(name, addr)| self.memory_variable(name, addr))
+                .collect()
+        } else {
+            let co = self.coroutine(reference / 10)?;
+            match reference % 10 {
+                REGISTERS_SCOPE => co
+                    .regs
+                    .iter()
+                    .enumerate()
+                    .map(|(reg, value)| {
+                        json!({
+                            "name": self.register_name(reg),
+                            "value": number(*value),
+                            "variablesReference": 0,
+                        })
+                    })
+                    .collect(),
+                STACK_SCOPE => {
+                    let sp = co.regs[2].max(0.0) as usize;
+                    let end = (sp + STACK_CELLS).min(self.dbg()?.vm.memory.len());
+                    (sp..end)
+                        .map(|addr| self.memory_variable(format!("sp+{}", addr - sp), addr))
+                        .collect()
+                }
+                _ => return Err(format!("Unknown variables reference {}", reference)),
+            }
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    // Registers are named like the variables list shows them, or by alias
+    fn variable_target(&self, reference: i64, name: &str) -> Result<Option<u8>, String> {
+        let name = name.split(" (").next().unwrap_or(name);
+        match reference % 10 {
+            REGISTERS_SCOPE if reference != MEMMAP_REF => {
+                let reg = self.dbg()?.register(name)?;
+                if reg == 0 {
+                    return Err("zero can't be changed".to_string());
+                }
+                Ok(Some(reg))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn address_of(&self, reference: i64, name: &str) -> Result<usize, String> {
+        if reference != MEMMAP_REF && reference % 10 == STACK_SCOPE {
+            let offset = name
+                .strip_prefix("sp+")
+                .and_then(|n| n.parse::<usize>().ok())
+                .ok_or_else(|| format!("Unknown stack slot {}", name))?;
+            let sp = self.coroutine(reference / 10)?.regs[2].max(0.0) as usize;
+            return Ok(sp + offset);
+        }
+        self.dbg()?.address(name)
+    }
+
+    fn set_variable(&mut self, args: &Value) -> Result<Value, String> {
+        let reference = args["variablesReference"].as_i64().unwrap_or(0);
+        let name = args["name"].as_str().unwrap_or_default();
+        let text = args["value"].as_str().unwrap_or_default();
+        let value = parse_immediate(text, 0, false, &self.dbg()?.program.constants)
+            .map_err(|e| e.to_string())?;
+        match self.variable_target(reference, name)? {
+            Some(reg) => self.coroutine_mut(reference / 10)?.regs[reg as usize] = value,
+            None => {
+                let addr = self.address_of(reference, name)?;
+                let dbg = self.dbg_mut()?;
+                dbg.vm.memory[addr] = value;
+                // not a change the program made
+                if dbg.watchpoints.contains_key(&addr) {
+                    dbg.watch(addr);
+                }
+            }
+        }
+        Ok(json!({ "value": number(value) }))
+    }
+
+    fn data_breakpoint_info(&self, args: &Value) -> Result<Value, String> {
+        let reference = args["variablesReference"].as_i64().unwrap_or(0);
+        let name = args["name"].as_str().unwrap_or_default();
+        let addr = match self.variable_target(reference, name) {
+            Ok(None) => self.address_of(reference, name).ok(),
+            _ => None,
+        };
+        Ok(match addr {
+            Some(addr) => json!({
+                "dataId": addr.to_string(),
+                "description": format!("{} (0x{:04x})", name, addr),
+                "accessTypes": ["write"],
+            }),
+            None => json!({"dataId": null, "description": "Only memory cells can be watched"}),
+        })
+    }
+
+    fn set_data_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let ids: Vec<String> = args["breakpoints"]
+            .as_array()
+            .map(|bps| {
+                bps.iter()
+                    .filter_map(|bp| bp["dataId"].as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let dbg = self.dbg_mut()?;
+        dbg.watchpoints.clear();
+        let mut result = Vec::new();
+        for id in ids {
+            match dbg.address(&id) {
+                Ok(addr) => {
+                    dbg.watch(addr);
+                    result.push(json!({"verified": true}));
+                }
+                Err(e) => result.push(json!({"verified": false, "message": e})),
+            }
+        }
+        Ok(json!({ "breakpoints": result }))
+    }
+
+    // Hovers and the debug console: registers by name or alias, otherwise a
+    // memory address
+    fn evaluate(&self, args: &Value) -> Result<Value, String> {
+        let expression = args["expression"].as_str().unwrap_or_default().trim();
+        let dbg = self.dbg()?;
+        let thread = match args["frameId"].as_i64() {
+            Some(frame) => frame / 10,
+            None => {
+                let id = dbg.coroutine().map(|co| co.id).unwrap_or(0);
+                *self.threads.get(&(dbg.core, id)).unwrap_or(&0)
+            }
+        };
+        if let Ok(reg) = dbg.register(expression) {
+            let value = self.coroutine(thread)?.regs[reg as usize];
+            return Ok(json!({"result": number(value), "variablesReference": 0}));
+        }
+        let inner = expression
+            .strip_prefix("mem[")
+            .and_then(|e| e.strip_suffix(']'))
+            .unwrap_or(expression);
+        let addr = dbg.address(inner)?;
+        Ok(json!({
+            "result": format!("mem[0x{:04x}] = {}", addr, number(dbg.vm.memory[addr])),
+            "memoryReference": addr.to_string(),
+            "variablesReference": 0,
+        }))
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response);
+    }
+
+    // Returns false once the client has disconnected
+    fn handle(&mut self, request: Value) -> bool {
+        let args = request["arguments"].clone();
+        let command = request["command"].as_str().unwrap_or_default().to_string();
+        match command.as_str() {
+            "initialize" => {
+                let capabilities = json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsSetVariable": true,
+                    "supportsEvaluateForHovers": true,
+                    "supportsDataBreakpoints": true,
+                    "supportsTerminateRequest": true,
+                });
+                self.respond(&request, Ok(capabilities));
+            }
+            "launch" => {
+                let result = self.launch(&args);
+                let launched = result.is_ok();
+                self.respond(&request, result);
+                if launched {
+                    self.event("initialized", json!({}));
+                    self.start();
+                }
+            }
+            "setBreakpoints" => {
+                let result = self.set_breakpoints(&args);
+                self.respond(&request, result);
+            }
+            "configurationDone" => {
+                self.configured = true;
+                self.respond(&request, Ok(json!({})));
+                self.start();
+            }
+            "threads" => {
+                let result = self.threads();
+                self.respond(&request, result);
+            }
+            "stackTrace" => {
+                let result = self.stack_trace(&args);
+                self.respond(&request, result);
+            }
+            "scopes" => {
+                let result = self.scopes(&args);
+                self.respond(&request, result);
+            }
+            "variables" => {
+                let result = self.variables(&args);
+                self.respond(&request, result);
+            }
+            "setVariable" => {
+                let result = self.set_variable(&args);
+                self.respond(&request, result);
+            }
+            "dataBreakpointInfo" => {
+                let result = self.data_breakpoint_info(&args);
+                self.respond(&request, result);
+            }
+            "setDataBreakpoints" => {
+                let result = self.set_data_breakpoints(&args);
+                self.respond(&request, result);
+            }
+            "evaluate" => {
+                let result = self.evaluate(&args);
+                self.respond(&request, result);
+            }
+            "continue" => {
+                let result = self.dbg().map(|_| json!({"allThreadsContinued": true}));
+                self.running = result.is_ok();
+                self.respond(&request, result);
+            }
+            "pause" => {
+                self.respond(&request, Ok(json!({})));
+                if self.running {
+                    self.running = false;
+                    let thread = self.stop_thread(&Stop::Step);
+                    self.stopped("pause", thread, None);
+                }
+            }
+            "next" | "stepIn" | "stepOut" => {
+                // the response has to go out before the stopped event
+                if let Err(e) = self.thread(args["threadId"].as_i64().unwrap_or(0)) {
+                    self.respond(&request, Err(e));
+                    return true;
+                }
+                self.respond(&request, Ok(json!({})));
+                let step: fn(&mut Debugger) -> Stop = match command.as_str() {
+                    "next" => Debugger::step_over,
+                    "stepIn" => Debugger::step,
+                    _ => Debugger::step_out,
+                };
+                if let Err(e) = self.step(&args, step) {
+                    self.event("output", json!({"category": "stderr", "output": e + "\n"}));
+                }
+            }
+            "terminate" => {
+                self.running = false;
+                self.respond(&request, Ok(json!({})));
+                self.event("terminated", json!({}));
+            }
+            "disconnect" => {
+                self.respond(&request, Ok(json!({})));
+                return false;
+            }
+            _ => self.respond(&request, Err(format!("Unsupported request {}", command))),
+        }
+        true
+    }
+}
+
+fn main() {
+    // requests are read on their own thread so a running program can be paused
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        while let Some(message) = read_message(&mut stdin) {
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut server = Server::new();
+    loop {
+        let message = if server.running {
+            match rx.try_recv() {
+                Ok(message) => Some(message),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match rx.recv() {
+                Ok(message) => Some(message),
+                Err(_) => break,
+            }
+        };
+        if let Some(message) = message {
+            if message["type"] == "request" && !server.handle(message) {
+                break;
+            }
+        }
+        if server.running {
+            server.run_chunk();
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use asmjr::debugger::{read_source, Debugger, Stop};
+use asmjr::ops::parse_immediate;
+use asmjr::vm::CoState;
+use clap::Args;
+use std::io::{self, BufRead, Write};
+
+#[derive(Args, Debug)]
+pub struct DebugArgs {
+    /// Assembly source file
+    #[clap(value_parser)]
+    source: String,
+}
+
+const HELP: &str = "Commands:
+  break LABEL|LINE     (b)  stop before the op at a label or source line
+  delete LABEL|LINE         remove a breakpoint
+  watch ADDR | unwatch ADDR stop when a memory cell changes
+  step                 (s)  execute one op on the selected core
+  next                 (n)  like step, but run over calls
+  finish                    run until the current routine returns
+  continue             (c)  run until something stops the machine
+  frame                (f)  run to the end of the current frame
+  print REG            (p)  show a register, by number or alias
+  set REG VALUE             change a register
+  regs                      show all non-zero registers
+  x ADDR [COUNT]            examine memory (numbers, constants, $ names)
+  core N                    select the core to step and inspect
+  cores                     list cores and their coroutines
+  list                 (l)  show source around the selected pc
+  quit                 (q)";
+
+fn state_name(state: CoState) -> &'static str {
+    match state {
+        CoState::Ready => "ready",
+        CoState::Frame => "waiting for frame",
+        CoState::Suspended => "suspended",
+        CoState::Halted => "halted",
+    }
+}
+
+fn print_location(dbg: &Debugger) {
+    match dbg.coroutine() {
+        Some(co) => println!(
+            "core {} coroutine {}, {}",
+            dbg.core,
+            co.id,
+            dbg.describe(co.pc)
+        ),
+        None => println!("core {} has nothing running", dbg.core),
+    }
+}
+
+fn print_stop(dbg: &Debugger, stop: Stop) {
+    match stop {
+        Stop::Step => {}
+        Stop::Breakpoint(core, co, _) => println!("Breakpoint on core {} coroutine {}", core, co),
+        Stop::Watchpoint(addr, old, new) => {
+            println!("Watchpoint 0x{:04x}: {} -> {}", addr, old, new)
+        }
+        Stop::Frame(stats) => println!("End of frame {} ({} ops)", stats.frame, stats.total()),
+        Stop::Halted => {
+            println!("Program halted.");
+            return;
+        }
+        Stop::Fault(e) => println!("{}", e),
+        Stop::Limit(frames) => println!("Still running after {} frames.", frames),
+    }
+    print_location(dbg);
+}
+
+fn register_name(dbg: &Debugger, reg: u8) -> String {
+    let aliases = dbg.register_aliases(reg);
+    if aliases.is_empty() {
+        format!("x{}", reg)
+    } else {
+        format!("x{} ({})", reg, aliases.join(", "))
+    }
+}
+
+fn print_source(dbg: &Debugger) {
+    let pc = match dbg.coroutine() {
+        Some(co) => co.pc,
+        None => return,
+    };
+    let line = dbg.line_of(pc).unwrap_or(dbg.source.len());
+    let first = line.saturating_sub(5);
+    for (idx, text) in dbg.source.iter().enumerate().skip(first).take(11) {
+        let marker = if idx == line { "=>" } else { "  " };
+        println!("{} {:4} {}", marker, idx + 1, text);
+    }
+}
+
+fn execute(dbg: &mut Debugger, command: &str, args: &[&str]) -> Result<(), String> {
+    let arg = |idx: usize| {
+        args.get(idx)
+            .copied()
+            .ok_or_else(|| format!("[{}] needs more arguments, see help", command))
+    };
+    match command {
+        "b" | "break" => {
+            let pc = dbg.code_location(arg(0)?)?;
+            dbg.breakpoints.insert(pc);
+            println!("Breakpoint at {}", dbg.describe(pc));
+        }
+        "delete" => {
+            let pc = dbg.code_location(arg(0)?)?;
+            if !dbg.breakpoints.remove(&pc) {
+                return Err(format!("No breakpoint at pc {}", pc));
+            }
+        }
+        "watch" => {
+            let addr = dbg.address(arg(0)?)?;
+            dbg.watch(addr);
+            println!("Watching 0x{:04x} = {}", addr, dbg.vm.memory[addr]);
+        }
+        "unwatch" => {
+            let addr = dbg.address(arg(0)?)?;
+            dbg.watchpoints.remove(&addr);
+        }
+        "s" | "step" => {
+            let stop = dbg.step();
+            print_stop(dbg, stop);
+        }
+        "n" | "next" => {
+            let stop = dbg.step_over();
+            print_stop(dbg, stop);
+        }
+        "finish" => {
+            let stop = dbg.step_out();
+            print_stop(dbg, stop);
+        }
+        "c" | "continue" => {
+            let stop = dbg.cont();
+            print_stop(dbg, stop);
+        }
+        "f" | "frame" => {
+            let stop = dbg.frame();
+            print_stop(dbg, stop);
+        }
+        "p" | "print" => {
+            let reg = dbg.register(arg(0)?)?;
+            let value = dbg.coroutine().ok_or("Nothing running")?.regs[reg as usize];
+            println!("{} = {}", register_name(dbg, reg), value);
+        }
+        "set" => {
+            let reg = dbg.register(arg(0)?)?;
+            if reg == 0 {
+                return Err("zero can't be changed".to_string());
+            }
+            let value = parse_immediate(arg(1)?, 0, false, &dbg.program.constants)
+                .map_err(|e| e.to_string())?;
+            dbg.coroutine_mut().ok_or("Nothing running")?.regs[reg as usize] = value;
+        }
+        "regs" => {
+            let co = dbg.coroutine().ok_or("Nothing running")?;
+            for (reg, value) in co.regs.iter().enumerate() {
+                if *value != 0.0 {
+                    println!("  {} = {}", register_name(dbg, reg as u8), value);
+                }
+            }
+        }
+        "x" => {
+            let addr = dbg.address(arg(0)?)?;
+            let count = match args.get(1) {
+                Some(count) => count.parse::<usize>().map_err(|e| e.to_string())?,
+                None => 1,
+            };
+            let end = addr.saturating_add(count).min(dbg.vm.memory.len());
+            for row_start in (addr..end).step_by(8) {
+                let row_end = (row_start + 8).min(end);
+                let cells: Vec<String> = dbg.vm.memory[row_start..row_end]
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect();
+                println!("  0x{:04x}: {}", row_start, cells.join(" "));
+            }
+        }
+        "core" => {
+            let core = arg(0)?.parse::<usize>().map_err(|e| e.to_string())?;
+            if core >= dbg.vm.cores.len() {
+                return Err(format!("There are only {} cores", dbg.vm.cores.len()));
+            }
+            dbg.core = core;
+            print_location(dbg);
+        }
+        "cores" => {
+            println!("Frame {}", dbg.vm.frame);
+            for core in dbg.vm.cores.iter() {
+                let marker = if core.id == dbg.core { "*" } else { " " };
+                println!(
+                    "{} core {}: {} cycles, {} ops left this frame",
+                    marker, core.id, core.cycles, core.budget
+                );
+                for co in core.coroutines.iter() {
+                    println!(
+                        "    coroutine {} [{}] {}",
+                        co.id,
+                        state_name(co.state),
+                        dbg.describe(co.pc)
+                    );
+                }
+            }
+        }
+        "l" | "list" => print_source(dbg),
+        "h" | "help" => println!("{}", HELP),
+        _ => return Err(format!("Unknown command [{}], try help", command)),
+    }
+    Ok(())
+}
+
+pub fn debug(args: DebugArgs) -> i32 {
+    let src = match read_source(&args.source) {
+        Ok(src) => src,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    let mut dbg = match Debugger::new(&src) {
+        Ok(dbg) => dbg,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+
+    println!(
+        "Loaded {} ops from {}, type help for commands.",
+        dbg.program.ops.len(),
+        args.source
+    );
+    print_location(&dbg);
+
+    let stdin = io::stdin();
+    let mut last = String::new();
+    loop {
+        print!("(asmjr) ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        // an empty line repeats the previous command, like gdb
+        let line = match line.trim() {
+            "" => last.clone(),
+            line => line.to_string(),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, rest) = match words.split_first() {
+            Some((command, rest)) => (*command, rest),
+            None => continue,
+        };
+        if command == "q" || command == "quit" {
+            break;
+        }
+        if let Err(e) = execute(&mut dbg, command, rest) {
+            println!("{}", e);
+        }
+        last = line;
+    }
+    0
+}
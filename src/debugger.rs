@@ -0,0 +1,370 @@
+use crate::linker::{is_object, unlinked};
+use crate::ops::{op_name, parse_immediate, parse_register};
+use crate::parser::{assemble, Program};
+use crate::symbols::Symbols;
+use crate::vm::{CoState, Coroutine, FrameStats, Vm, VmError, MEMORY_SIZE};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::vec::Vec;
+
+// Debugging support on top of the reference VM, shared by the terminal
+// debugger and the DAP server. Execution is all-stop: while running, every
+// core advances as usual, and the whole machine pauses right before the op
+// that triggered a stop (or right after the op that tripped a watchpoint).
+
+// frames to run without hitting anything before giving up on a continue
+pub const MAX_RUN_FRAMES: u64 = 10_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    // the requested step/next/finish completed
+    Step,
+    // core, coroutine id, pc
+    Breakpoint(usize, i64, usize),
+    // address, old value, new value
+    Watchpoint(usize, f64, f64),
+    Frame(FrameStats),
+    Halted,
+    Fault(VmError),
+    // ran this many frames without stopping
+    Limit(u64),
+}
+
+pub struct Debugger {
+    pub vm: Vm,
+    pub program: Program,
+    // the program's labels, for naming pcs
+    pub symbols: Symbols,
+    pub source: Vec<String>,
+    // pcs to stop at
+    pub breakpoints: BTreeSet<usize>,
+    // watched address -> last value seen there
+    pub watchpoints: BTreeMap<usize, f64>,
+    // core that stepping and register access apply to
+    pub core: usize,
+    // the op we're paused right before, which may run without re-triggering
+    // its own breakpoint
+    paused: Option<(usize, i64, usize)>,
+}
+
+fn is_call(vm: &Vm, pc: usize) -> bool {
+    match vm.program.get(pc) {
+        Some(op) => matches!(op_name(op.op.opcode), Some("jal" | "jalr")) && op.op.rd != 0,
+        None => false,
+    }
+}
+
+// Source to debug, which object files built with -c aren't
+pub fn read_source(filename: &str) -> Result<String, String> {
+    let data = fs::read(filename).map_err(|e| format!("Failed to read {}: {}", filename, e))?;
+    if is_object(&data) {
+        return Err(format!(
+            "{} is an object file, link it into a cartridge first",
+            filename
+        ));
+    }
+    String::from_utf8(data).map_err(|_| format!("{} is not valid UTF-8", filename))
+}
+
+impl Debugger {
+    pub fn new(src: &str) -> Result<Debugger, String> {
+        let program = assemble(src).map_err(|e| e.to_string())?;
+        if let Some(reason) = unlinked(&program) {
+            return Err(reason);
+        }
+        Ok(Debugger {
+            vm: Vm::new(program.ops.clone()),
+            symbols: Symbols::from_program(&program),
+            program,
+            source: src.lines().map(|line| line.to_string()).collect(),
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            core: 0,
+            paused: None,
+        })
+    }
+
+    // 0-based source line of an op
+    pub fn line_of(&self, pc: usize) -> Option<usize> {
+        self.program.lines.get(pc).copied()
+    }
+
+    pub fn describe(&self, pc: usize) -> String {
+        match self.line_of(pc) {
+            Some(line) => format!(
+                "pc {} (line {}): {}",
+                pc,
+                line + 1,
+                self.source.get(line).map(|s| s.trim()).unwrap_or_default()
+            ),
+            None => format!("pc {} (end of program)", pc),
+        }
+    }
+
+    // A label, or a 1-based source line (the first op on or after it)
+    pub fn code_location(&self, spec: &str) -> Result<usize, String> {
+        if let Some(pc) = self.program.labels.get(spec) {
+            return Ok(*pc as usize);
+        }
+        let line = spec
+            .parse::<usize>()
+            .map_err(|_| format!("\"{}\" is not a label or line number", spec))?;
+        let pc = self.program.pc_at_line(line.saturating_sub(1));
+        if pc >= self.program.ops.len() {
+            return Err(format!("No code on or after line {}", line));
+        }
+        Ok(pc)
+    }
+
+    pub fn register(&self, name: &str) -> Result<u8, String> {
+        parse_register(name, &self.program.aliases).map_err(|e| e.to_string())
+    }
+
+    // Alias names other than xN, sorted so the output is stable
+    pub fn register_aliases(&self, reg: u8) -> Vec<&str> {
+        let plain = format!("x{}", reg);
+        let mut names: Vec<&str> = self
+            .program
+            .aliases
+            .iter()
+            .filter(|(name, r)| **r == reg && **name != plain)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    // Numbers, constants, labels or $ memmap names
+    pub fn address(&self, expr: &str) -> Result<usize, String> {
+        let addr =
+            parse_immediate(expr, 0, false, &self.program.constants).map_err(|e| e.to_string())?;
+        if addr < 0.0 || addr >= MEMORY_SIZE as f64 {
+            return Err(format!("Address {} is out of range", addr));
+        }
+        Ok(addr as usize)
+    }
+
+    pub fn coroutine(&self) -> Option<&Coroutine> {
+        self.vm.cores.get(self.core)?.scheduled()
+    }
+
+    pub fn coroutine_mut(&mut self) -> Option<&mut Coroutine> {
+        let core = self.vm.cores.get_mut(self.core)?;
+        let id = core.scheduled()?.id;
+        core.coroutines.iter_mut().find(|co| co.id == id)
+    }
+
+    pub fn watch(&mut self, addr: usize) {
+        self.watchpoints.insert(addr, self.vm.memory[addr]);
+    }
+
+    fn check_watchpoints(&mut self) -> Option<Stop> {
+        for (addr, last) in self.watchpoints.iter_mut() {
+            let value = self.vm.memory[*addr];
+            if value.to_bits() != last.to_bits() {
+                let old = *last;
+                *last = value;
+                return Some(Stop::Watchpoint(*addr, old, value));
+            }
+        }
+        None
+    }
+
+    fn run(&mut self, stop_at_frame: bool, done: impl FnMut(&Vm, usize) -> bool) -> Stop {
+        let stop = self.run_until(stop_at_frame, done);
+        // stops at a frame boundary are between ops, so nothing was skipped
+        self.paused = match stop {
+            Stop::Frame(_) | Stop::Limit(_) | Stop::Halted => None,
+            _ => self.vm.peek(),
+        };
+        stop
+    }
+
+    // Run until `done` says so (given the core that just ran an op), or
+    // something else stops the machine first
+    fn run_until(&mut self, stop_at_frame: bool, mut done: impl FnMut(&Vm, usize) -> bool) -> Stop {
+        let mut resume = self.paused.take();
+        let mut frames = 0;
+        loop {
+            match self.vm.peek() {
+                None => {
+                    if self.vm.is_halted() {
+                        return Stop::Halted;
+                    }
+                    let stats = self.vm.end_frame();
+                    if stop_at_frame {
+                        return Stop::Frame(stats);
+                    }
+                    frames += 1;
+                    if frames >= MAX_RUN_FRAMES {
+                        return Stop::Limit(frames);
+                    }
+                    continue;
+                }
+                Some(next) => {
+                    let (core, co, pc) = next;
+                    if resume != Some(next) && self.breakpoints.contains(&pc) {
+                        self.core = core;
+                        return Stop::Breakpoint(core, co, pc);
+                    }
+                }
+            }
+            resume = None;
+            let ran = match self.vm.step() {
+                Ok(Some(ran)) => ran,
+                Ok(None) => continue,
+                Err(e) => {
+                    self.core = e.core;
+                    return Stop::Fault(e);
+                }
+            };
+            if let Some(stop) = self.check_watchpoints() {
+                self.core = ran;
+                return stop;
+            }
+            if done(&self.vm, ran) {
+                return Stop::Step;
+            }
+        }
+    }
+
+    fn selected(&self) -> Option<(usize, i64, usize)> {
+        let co = self.coroutine()?;
+        if co.state == CoState::Halted {
+            return None;
+        }
+        Some((self.core, co.id, co.pc))
+    }
+
+    // Run until the given coroutine is back at a pc
+    fn run_to(&mut self, core: usize, id: i64, pc: usize) -> Stop {
+        self.run(false, |vm, ran| {
+            if ran != core {
+                return false;
+            }
+            let co = vm
+                .cores
+                .get(core)
+                .and_then(|c| c.coroutines.iter().find(|co| co.id == id));
+            match co {
+                Some(co) => co.pc == pc || co.state == CoState::Halted,
+                // killed, so it'll never get there
+                None => true,
+            }
+        })
+    }
+
+    // Execute one op on the selected core
+    pub fn step(&mut self) -> Stop {
+        let core = match self.selected() {
+            Some((core, _, _)) => core,
+            None => return Stop::Halted,
+        };
+        self.run(false, |_, ran| ran == core)
+    }
+
+    // Like step, but runs called routines to completion
+    pub fn step_over(&mut self) -> Stop {
+        match self.selected() {
+            Some((core, id, pc)) if is_call(&self.vm, pc) => self.run_to(core, id, pc + 1),
+            Some(_) => self.step(),
+            None => Stop::Halted,
+        }
+    }
+
+    // Run until the current routine returns to the address in ra
+    pub fn step_out(&mut self) -> Stop {
+        match (self.selected(), self.coroutine()) {
+            (Some((core, id, _)), Some(co)) => {
+                let ret = co.regs[1] as usize;
+                self.run_to(core, id, ret)
+            }
+            _ => Stop::Halted,
+        }
+    }
+
+    pub fn cont(&mut self) -> Stop {
+        self.run(false, |_, _| false)
+    }
+
+    // Run to the end of the current frame
+    pub fn frame(&mut self) -> Stop {
+        self.run(true, |_, _| false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: &str = "li x1, 2
+crcfg zero, x1
+crid x5
+reg counter = x6
+LOOP:
+jal ra, BUMP
+store counter, x5, 0x200
+yield zero, 1
+jal zero, LOOP
+BUMP:
+addi counter, counter, 1
+jalr zero, ra, 0
+";
+
+    #[test]
+    fn test_breakpoints() {
+        let mut dbg = Debugger::new(SRC).unwrap();
+        let bump = dbg.code_location("BUMP").unwrap();
+        assert_eq!(bump, 7);
+        assert_eq!(dbg.code_location("7"), Ok(4));
+        assert!(dbg.code_location("NOWHERE").is_err());
+        assert!(dbg.code_location("100").is_err());
+        // .extern symbols are only filled in by the linker
+        assert_eq!(
+            Debugger::new(".extern F\njal ra, F").err().unwrap(),
+            "F is .extern, so this has to be built with -c and linked with other modules."
+        );
+
+        dbg.breakpoints.insert(bump);
+        assert_eq!(dbg.cont(), Stop::Breakpoint(0, 0, bump));
+        // core 1 starts from the top after crcfg, so it gets there later
+        assert_eq!(dbg.cont(), Stop::Breakpoint(1, 0, bump));
+        assert_eq!(dbg.core, 1);
+        assert_eq!(dbg.step_out(), Stop::Step);
+        assert_eq!(dbg.coroutine().unwrap().pc, 4);
+        assert_eq!(dbg.coroutine().unwrap().regs[6], 1.0);
+        assert_eq!(dbg.register("counter"), Ok(6));
+        assert_eq!(dbg.register_aliases(6), vec!["counter", "t1"]);
+    }
+
+    #[test]
+    fn test_stepping() {
+        let mut dbg = Debugger::new(SRC).unwrap();
+        assert_eq!(dbg.step(), Stop::Step);
+        assert_eq!(dbg.step(), Stop::Step);
+        assert_eq!(dbg.vm.cores.len(), 2);
+        assert_eq!(dbg.step(), Stop::Step);
+        assert_eq!(dbg.coroutine().unwrap().pc, 3);
+        // next runs BUMP without stopping inside it
+        assert_eq!(dbg.step_over(), Stop::Step);
+        assert_eq!(dbg.coroutine().unwrap().pc, 4);
+        assert_eq!(dbg.coroutine().unwrap().regs[6], 1.0);
+
+        assert!(matches!(dbg.frame(), Stop::Frame(_)));
+        dbg.core = 1;
+        assert_eq!(dbg.coroutine().unwrap().state, CoState::Ready);
+        assert_eq!(dbg.coroutine().unwrap().pc, 6);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut dbg = Debugger::new(SRC).unwrap();
+        let addr = dbg.address("0x201").unwrap();
+        dbg.watch(addr);
+        assert_eq!(dbg.cont(), Stop::Watchpoint(0x201, 0.0, 1.0));
+        assert_eq!(dbg.core, 1);
+        assert_eq!(dbg.cont(), Stop::Watchpoint(0x201, 1.0, 2.0));
+        assert_eq!(dbg.address("$VIDEO_SPRITE_COUNT"), Ok(10));
+        assert!(dbg.address("-1").is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use crate::ops::{parse_immediate, OpErr};
+use std::collections::HashMap;
+use std::vec::Vec;
+
+// Named sets of constants. ".enum GameState { TITLE, PLAYING, PAUSED = 10,
+// GAMEOVER }" numbers its members from 0, each one more than the last
+// unless given a value, and ".flags" gives each the next power of two
+// instead, for bits that can be or-ed together. Members become constants
+// scoped by the set's name, GameState.TITLE, along with GameState.COUNT for
+// how many members there are.
+
+// The constants for a .enum or .flags directive, given its { } part
+pub fn members(
+    name: &str,
+    text: &str,
+    flags: bool,
+    constants: &HashMap<String, f64>,
+) -> Result<Vec<(String, f64)>, OpErr> {
+    let invalid = |msg: String| Err(OpErr::InvalidEnum(msg));
+    let body = match (text.find('{'), text.rfind('}')) {
+        (Some(open), Some(close)) if open < close && text[close + 1..].trim().is_empty() => {
+            &text[open + 1..close]
+        }
+        _ => return invalid(format!("{} needs its members in {{ }}", name)),
+    };
+
+    let mut members: Vec<(String, f64)> = Vec::new();
+    let mut next = if flags { 1.0 } else { 0.0 };
+    for member in body.split(',').map(str::trim) {
+        if member.is_empty() {
+            continue;
+        }
+        let (member, value) = match member.split_once('=') {
+            Some((member, value)) => {
+                let value = parse_immediate(value.trim(), 0, false, constants)?;
+                (member.trim(), value)
+            }
+            None => (member, next),
+        };
+        if member.is_empty() || member.contains(char::is_whitespace) {
+            return invalid(format!("\"{}\" is not a member name", member));
+        }
+        let scoped = format!("{}.{}", name, member);
+        if member == "COUNT" || members.iter().any(|(other, _)| *other == scoped) {
+            return invalid(format!("{} already has a member called {}", name, member));
+        }
+        if flags && (value < 0.0 || value.fract() != 0.0) {
+            return invalid(format!("{} has to be a whole number of bits", scoped));
+        }
+        next = match flags {
+            // the next bit up from the highest one set
+            true => 2f64.powi((value + 1.0).log2().ceil() as i32),
+            false => value + 1.0,
+        };
+        members.push((scoped, value));
+    }
+    let count = members.len() as f64;
+    members.push((format!("{}.COUNT", name), count));
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::assemble;
+
+    #[test]
+    fn test_enums() {
+        let constants: HashMap<String, f64> = HashMap::new();
+        let members = |text: &str, flags: bool| {
+            members("E", text, flags, &constants).map(|members| {
+                members
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, value))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            })
+        };
+        assert_eq!(
+            members("enum E { TITLE, PLAYING, PAUSED = 10, GAMEOVER }", false).unwrap(),
+            "E.TITLE=0 E.PLAYING=1 E.PAUSED=10 E.GAMEOVER=11 E.COUNT=4"
+        );
+        assert_eq!(
+            members("flags E {A, B, BOTH=3, C, NONE = 0, D = 0x10, E,}", true).unwrap(),
+            "E.A=1 E.B=2 E.BOTH=3 E.C=4 E.NONE=0 E.D=16 E.E=32 E.COUNT=7"
+        );
+        assert_eq!(members("enum E {}", false).unwrap(), "E.COUNT=0");
+        assert!(members("enum E { A, A }", false).is_err());
+        assert!(members("enum E { COUNT }", false).is_err());
+        assert!(members("enum E { A B }", false).is_err());
+        assert!(members("enum E { A = HUH }", false).is_err());
+        assert!(members("enum E A, B", false).is_err());
+        assert!(members("flags E { A = 1.5 }", true).is_err());
+
+        let program = assemble(
+            "const BASE = 100
+.enum GameState { TITLE, PLAYING, PAUSED = BASE, GAMEOVER }
+.flags Buttons { A, B, START }  // one bit each
+li x5, GameState.GAMEOVER
+li x6, Buttons.START
+const STATES = GameState.COUNT
+",
+        )
+        .unwrap();
+        assert_eq!(program.ops[0].imm, 101.0);
+        assert_eq!(program.ops[1].imm, 4.0);
+        assert_eq!(program.constants["STATES"], 4.0);
+        assert!(assemble(".enum E { A }\n.enum E { B }").is_err());
+        assert!(assemble(".enum { A }").is_err());
+    }
+}
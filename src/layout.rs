@@ -0,0 +1,120 @@
+use crate::ops::OpErr;
+use std::vec::Vec;
+
+// Record layouts, declared with .struct: fields one after the other, each
+// some number of words from the start of the record. A layout named Sprite
+// becomes a constant per field, Sprite.x being the field's offset, and
+// Sprite.SIZE for the whole record, so a register pointing at a record
+// reaches its fields with memory operands like cursprite[Sprite.x].
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub name: String,
+    // field names and sizes, in order
+    pub fields: Vec<(String, usize)>,
+}
+
+impl Layout {
+    pub fn new(name: &str) -> Layout {
+        Layout {
+            name: name.to_string(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn add_field(&mut self, name: &str, size: usize) -> Result<(), OpErr> {
+        if name == "SIZE" || self.fields.iter().any(|(field, _)| field == name) {
+            return Err(OpErr::InvalidStruct(format!(
+                "{} already has a field called {}",
+                self.name, name
+            )));
+        }
+        self.fields.push((name.to_string(), size));
+        Ok(())
+    }
+
+    pub fn size(&self) -> usize {
+        self.fields.iter().map(|(_, size)| size).sum()
+    }
+
+    // NAME.field for every field's offset, then NAME.SIZE
+    pub fn constants(&self) -> Vec<(String, f64)> {
+        let mut constants = Vec::new();
+        let mut offset = 0;
+        for (field, size) in self.fields.iter() {
+            constants.push((format!("{}.{}", self.name, field), offset as f64));
+            offset += size;
+        }
+        constants.push((format!("{}.SIZE", self.name), offset as f64));
+        constants
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::assemble;
+    use crate::vm::Vm;
+
+    #[test]
+    fn test_layout() {
+        let mut layout = Layout::new("Point");
+        layout.add_field("x", 1).unwrap();
+        layout.add_field("tag", 3).unwrap();
+        layout.add_field("y", 1).unwrap();
+        assert!(layout.add_field("x", 1).is_err());
+        assert!(layout.add_field("SIZE", 1).is_err());
+        assert_eq!(layout.size(), 5);
+        assert_eq!(
+            layout.constants(),
+            vec![
+                ("Point.x".to_string(), 0.0),
+                ("Point.tag".to_string(), 1.0),
+                ("Point.y".to_string(), 4.0),
+                ("Point.SIZE".to_string(), 5.0),
+            ]
+        );
+
+        let program = assemble(
+            "const SPRITES = 0x200
+.struct Sprite
+.field x
+.field y
+.field palette, 4
+.field fg_color
+.endstruct
+const LAST = Sprite.SIZE
+reg cursprite = x15
+li cursprite, 1
+muli cursprite, cursprite, Sprite.SIZE
+addi cursprite, cursprite, SPRITES
+li x5, 60
+store x5, cursprite[Sprite.fg_color]
+li x5, LAST
+store x5, cursprite[Sprite.y]
+",
+        )
+        .unwrap();
+        assert_eq!(program.constants["Sprite.fg_color"], 6.0);
+        assert_eq!(program.constants["Sprite.SIZE"], 7.0);
+        let mut vm = Vm::new(program.ops.clone());
+        while !vm.is_halted() {
+            vm.run_frame().unwrap();
+        }
+        assert_eq!(vm.memory[0x200 + 7 + 6], 60.0);
+        assert_eq!(vm.memory[0x200 + 7 + 1], 7.0);
+
+        for bad in [
+            ".struct A\n.field x\n",
+            ".field x",
+            ".endstruct",
+            ".struct A\n.struct B\n.endstruct\n.endstruct",
+            ".struct A\n.field x, 0\n.endstruct",
+            ".struct A\n.field x, 1.5\n.endstruct",
+            ".struct A\n.endstruct\n.struct A\n.endstruct",
+            ".struct A\nnop\n.endstruct",
+        ] {
+            assert!(assemble(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -4,10 +4,26 @@ use wasm_bindgen::prelude::*;
 mod compression;
 mod memmap;
 
+pub mod analysis;
 pub mod cartridge;
+pub mod control;
+pub mod debugger;
+pub mod enums;
+pub mod layout;
+pub mod linker;
+pub mod linkmap;
+pub mod listing;
 pub mod metadata;
 pub mod ops;
+pub mod optimizer;
 pub mod parser;
+pub mod regalloc;
+pub mod regions;
+pub mod sourcemap;
+pub mod stdlib;
+pub mod symbols;
+pub mod testing;
+pub mod vm;
 
 fn bounded_copy(dest: &mut [u8], src: &[u8]) -> usize {
     let ncopy = min(dest.len(), src.len());
@@ -31,7 +47,7 @@ pub fn assemble_simple(src: &str, rom: &[u8], dest: &mut [u8]) -> i32 {
         None
     };
 
-    let data = cartridge::pack_cartridge(None, vrom, &ops, true);
+    let data = cartridge::pack_cartridge(None, vrom, &ops, None, None, true);
     let ncopied = bounded_copy(dest, &data);
 
     ncopied as i32
@@ -0,0 +1,83 @@
+use asmjr::linker::{link as link_objects, Object};
+use asmjr::sourcemap::SourceMap;
+use asmjr::symbols::Symbols;
+use asmjr::{cartridge, metadata};
+use clap::Args;
+use std::fs;
+
+#[derive(Args, Debug)]
+pub struct LinkArgs {
+    /// Object files, the first one holding the entry point at pc 0
+    #[clap(value_parser, required = true)]
+    objects: Vec<String>,
+
+    /// Output ECJR cartridge file
+    #[clap(short, long, value_parser)]
+    output: String,
+
+    /// Leave the source map out of the cartridge (for release builds)
+    #[clap(long, action)]
+    strip: bool,
+
+    /// Embed label names in the cartridge
+    #[clap(long, action)]
+    symbols: bool,
+
+    /// Leave cart body uncompressed
+    #[clap(short, long, action)]
+    uncompressed: bool,
+}
+
+pub fn link(args: LinkArgs) -> i32 {
+    let mut modules: Vec<(String, Object)> = Vec::new();
+    for filename in args.objects.iter() {
+        let object = match fs::read(filename) {
+            Ok(data) => Object::from_bytes(&data).map_err(|e| e.to_string()),
+            Err(e) => Err(format!("Failed to read {}: {}", filename, e)),
+        };
+        match object {
+            Ok(object) => modules.push((filename.clone(), object)),
+            Err(e) => {
+                println!("{}: {}", filename, e);
+                return 1;
+            }
+        }
+    }
+
+    let program = match link_objects(&modules) {
+        Ok(program) => program,
+        Err(errors) => {
+            for e in errors {
+                println!("{}", e);
+            }
+            return 1;
+        }
+    };
+    println!(
+        "Linked {} ops from {} modules.",
+        program.ops.len(),
+        modules.len()
+    );
+    if !program.regions.regions.is_empty() {
+        print!("{}", program.regions.to_text());
+    }
+
+    let source_map = SourceMap::from_program(&program);
+    let source_map = if args.strip { None } else { Some(&source_map) };
+    let symbols = if args.symbols {
+        Some(Symbols::from_program(&program))
+    } else {
+        None
+    };
+    let cartdata = cartridge::pack_cartridge(
+        Some(metadata::format_metadata(None, None)),
+        None,
+        &program.ops,
+        source_map,
+        symbols.as_ref(),
+        !args.uncompressed,
+    );
+    fs::write(&args.output, &cartdata).expect("Failed to write output file!");
+    println!("Wrote {} bytes to {}.", cartdata.len(), args.output);
+    0
+}
@@ -0,0 +1,526 @@
+use crate::cartridge::{cart, deserialize_ops, serialize_ops};
+use crate::memmap::IO_END;
+use crate::ops::{op_info, Op};
+use crate::parser::{Function, Program};
+use crate::regions::Region;
+use crate::sourcemap::SourceMap;
+use crate::vm::MEMORY_SIZE;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::vec::Vec;
+
+use prost::Message;
+
+// Separately assembled modules and the linker that puts them together.
+// An object holds a module's ops as if it started at pc 0, with relocations
+// for every immediate that depends on where things end up: absolute label
+// addresses (li x5, LOOP) and references to .extern symbols, absolute or
+// pc-relative. Branches and jumps to the module's own labels don't move
+// relative to each other, so they need nothing. Memory each module reserved
+// stays where it was put, since its addresses are already in the ops, so
+// modules that reserved the same memory can't be linked together.
+
+const OBJECT_MAGIC: &[u8] = b"ECJROBJ1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub pc: usize,
+    pub symbol: String,
+    // the immediate is the symbol's pc minus the op's own
+    pub relative: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Object {
+    pub ops: Vec<Op>,
+    // every label in the module, exported or not
+    pub labels: BTreeMap<String, usize>,
+    pub exports: Vec<String>,
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+    pub source_map: SourceMap,
+    pub functions: Vec<Function>,
+    pub regions: Vec<Region>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkErr {
+    BadObject(String),
+    Undefined {
+        symbol: String,
+        module: String,
+    },
+    Duplicate {
+        symbol: String,
+        first: String,
+        second: String,
+    },
+    // reservations, as NAME at START..END in MODULE
+    Overlap {
+        first: String,
+        second: String,
+    },
+}
+
+impl fmt::Display for LinkErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkErr::BadObject(s) => write!(f, "Not a valid object file: {}", s),
+            LinkErr::Undefined { symbol, module } => {
+                write!(f, "Undefined symbol {} referenced in {}", symbol, module)
+            }
+            LinkErr::Duplicate {
+                symbol,
+                first,
+                second,
+            } => write!(f, "Duplicate symbol {} in {} and {}", symbol, first, second),
+            LinkErr::Overlap { first, second } => write!(f, "{} overlaps {}", second, first),
+        }
+    }
+}
+
+pub fn is_object(data: &[u8]) -> bool {
+    data.starts_with(OBJECT_MAGIC)
+}
+
+// Why an assembled program can't run until it's linked, if it can't
+pub fn unlinked(program: &Program) -> Option<String> {
+    let (_, symbol) = program.extern_refs.first()?;
+    Some(format!(
+        "{} is .extern, so this has to be built with -c and linked with other modules.",
+        symbol
+    ))
+}
+
+impl Object {
+    pub fn from_program(program: &Program) -> Object {
+        let labels: BTreeMap<String, usize> = program
+            .labels
+            .iter()
+            .map(|(name, pc)| (name.clone(), *pc as usize))
+            .collect();
+        // any label at the pc does for an absolute address, so the first
+        let mut by_pc: HashMap<usize, &str> = HashMap::new();
+        for (name, pc) in labels.iter().rev() {
+            by_pc.insert(*pc, name);
+        }
+
+        let mut relocations: Vec<Relocation> = program
+            .label_refs
+            .iter()
+            .filter_map(|pc| {
+                let symbol = by_pc.get(&(program.ops[*pc].imm as usize))?;
+                Some(Relocation {
+                    pc: *pc,
+                    symbol: symbol.to_string(),
+                    relative: false,
+                })
+            })
+            .collect();
+        relocations.extend(program.extern_refs.iter().map(|(pc, symbol)| Relocation {
+            pc: *pc,
+            symbol: symbol.clone(),
+            relative: op_info(program.ops[*pc].op.opcode).is_some_and(|info| info.rel()),
+        }));
+        relocations.sort_by_key(|r| r.pc);
+        // the linker fills these in
+        let mut ops = program.ops.clone();
+        for r in relocations.iter() {
+            ops[r.pc].imm = 0.0;
+        }
+
+        Object {
+            ops,
+            labels,
+            exports: program.globals.clone(),
+            imports: program.externs.clone(),
+            relocations,
+            source_map: SourceMap::from_program(program),
+            functions: program.functions.clone(),
+            regions: program.regions.regions.clone(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let object = cart::Object {
+            program: serialize_ops(&self.ops),
+            labels: self
+                .labels
+                .iter()
+                .map(|(name, pc)| cart::Label {
+                    name: name.clone(),
+                    pc: *pc as u32,
+                })
+                .collect(),
+            exports: self.exports.clone(),
+            imports: self.imports.clone(),
+            relocations: self
+                .relocations
+                .iter()
+                .map(|r| cart::Relocation {
+                    pc: r.pc as u32,
+                    symbol: r.symbol.clone(),
+                    relative: r.relative,
+                })
+                .collect(),
+            source_map: Some(self.source_map.to_proto()),
+            functions: self.functions.iter().map(Function::to_proto).collect(),
+            regions: self
+                .regions
+                .iter()
+                .map(|r| cart::Region {
+                    name: r.name.clone(),
+                    start: r.start as u32,
+                    size: r.size as u32,
+                    fixed: r.fixed,
+                })
+                .collect(),
+        };
+        let mut data = OBJECT_MAGIC.to_vec();
+        data.extend(object.encode_to_vec());
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Object, LinkErr> {
+        if !is_object(data) {
+            return Err(LinkErr::BadObject("bad magic".to_string()));
+        }
+        let object = cart::Object::decode(&data[OBJECT_MAGIC.len()..])
+            .map_err(|e| LinkErr::BadObject(e.to_string()))?;
+        let ops =
+            deserialize_ops(&object.program).map_err(|e| LinkErr::BadObject(e.to_string()))?;
+        let relocations: Vec<Relocation> = object
+            .relocations
+            .iter()
+            .map(|r| Relocation {
+                pc: r.pc as usize,
+                symbol: r.symbol.clone(),
+                relative: r.relative,
+            })
+            .collect();
+        if let Some(r) = relocations.iter().find(|r| r.pc >= ops.len()) {
+            return Err(LinkErr::BadObject(format!("relocation at pc {}", r.pc)));
+        }
+        let functions: Vec<Function> = object.functions.iter().map(Function::from_proto).collect();
+        if let Some(f) = functions.iter().find(|f| f.end > ops.len()) {
+            return Err(LinkErr::BadObject(format!(
+                "function {} past the end",
+                f.name
+            )));
+        }
+        let regions: Vec<Region> = object
+            .regions
+            .iter()
+            .map(|r| Region {
+                name: r.name.clone(),
+                start: r.start as usize,
+                size: r.size as usize,
+                fixed: r.fixed,
+            })
+            .collect();
+        if let Some(r) = regions
+            .iter()
+            .find(|r| r.start < IO_END || r.end() > MEMORY_SIZE)
+        {
+            return Err(LinkErr::BadObject(format!("{} outside of memory", r.name)));
+        }
+        Ok(Object {
+            ops,
+            labels: object
+                .labels
+                .iter()
+                .map(|label| (label.name.clone(), label.pc as usize))
+                .collect(),
+            exports: object.exports,
+            imports: object.imports,
+            relocations,
+            source_map: object
+                .source_map
+                .map(|map| SourceMap::from_proto(&map))
+                .unwrap_or_default(),
+            functions,
+            regions,
+        })
+    }
+}
+
+// Puts named modules one after the other, the first one starting at pc 0,
+// and fills in every relocation. Exported labels are visible to every
+// module; anything else only to its own.
+pub fn link(modules: &[(String, Object)]) -> Result<Program, Vec<LinkErr>> {
+    let mut errors: Vec<LinkErr> = Vec::new();
+    let mut bases: Vec<usize> = Vec::new();
+    let mut size = 0;
+    for (_, object) in modules.iter() {
+        bases.push(size);
+        size += object.ops.len();
+    }
+
+    // exported label -> (pc, module)
+    let mut globals: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    for (idx, (name, object)) in modules.iter().enumerate() {
+        for symbol in object.exports.iter() {
+            let Some(pc) = object.labels.get(symbol) else {
+                errors.push(LinkErr::BadObject(format!(
+                    "{} exports unknown {}",
+                    name, symbol
+                )));
+                continue;
+            };
+            match globals.get(symbol.as_str()) {
+                Some((_, first)) => errors.push(LinkErr::Duplicate {
+                    symbol: symbol.clone(),
+                    first: modules[*first].0.clone(),
+                    second: name.clone(),
+                }),
+                None => drop(globals.insert(symbol, (bases[idx] + pc, idx))),
+            }
+        }
+    }
+
+    let mut program = Program::default();
+    // which module each reservation came from, by start address
+    let mut owners: BTreeMap<usize, &str> = BTreeMap::new();
+    let describe = |r: &Region, module: &str| {
+        format!(
+            "{} at {:#06x}..{:#06x} in {}",
+            r.name,
+            r.start,
+            r.end(),
+            module
+        )
+    };
+    for (idx, (name, object)) in modules.iter().enumerate() {
+        let base = bases[idx];
+        for region in object.regions.iter() {
+            match program.regions.add(region.clone()) {
+                Ok(()) => drop(owners.insert(region.start, name)),
+                Err(other) => errors.push(LinkErr::Overlap {
+                    first: describe(&other, owners[&other.start]),
+                    second: describe(region, name),
+                }),
+            }
+        }
+        let mut ops = object.ops.clone();
+        for r in object.relocations.iter() {
+            let target = match object.imports.contains(&r.symbol) {
+                true => globals.get(r.symbol.as_str()).map(|(pc, _)| *pc),
+                false => object.labels.get(&r.symbol).map(|pc| base + pc),
+            };
+            let Some(target) = target else {
+                errors.push(LinkErr::Undefined {
+                    symbol: r.symbol.clone(),
+                    module: name.clone(),
+                });
+                continue;
+            };
+            ops[r.pc].imm = match r.relative {
+                true => target as f64 - (base + r.pc) as f64,
+                false => target as f64,
+            };
+            if !r.relative {
+                program.label_refs.push(base + r.pc);
+            }
+        }
+        program.ops.extend(ops);
+        program
+            .functions
+            .extend(object.functions.iter().map(|f| Function {
+                start: base + f.start,
+                end: base + f.end,
+                ..f.clone()
+            }));
+
+        let files = program.files.len();
+        // lines carry on from the previous module's, so they keep going up
+        // and pc_at_line works as it does for a single file
+        let first_line = program.lines.last().map_or(0, |line| line + 1);
+        match object.source_map.files.is_empty() {
+            true => program.files.push(name.clone()),
+            false => program
+                .files
+                .extend(object.source_map.files.iter().cloned()),
+        }
+        for pc in 0..object.ops.len() {
+            let mut location = object.source_map.location(pc).cloned().unwrap_or_default();
+            location.file += files;
+            for (file, _) in location.stack.iter_mut() {
+                *file += files;
+            }
+            let line = location
+                .stack
+                .first()
+                .map_or(location.line, |(_, line)| *line);
+            program.lines.push(first_line + line);
+            program.locations.push(location);
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // exported labels, then each module's own as long as the name is free
+    for (symbol, (pc, _)) in globals.iter() {
+        program.labels.insert(symbol.to_string(), *pc as f64);
+        program.globals.push(symbol.to_string());
+    }
+    for (idx, (_, object)) in modules.iter().enumerate() {
+        for (label, pc) in object.labels.iter() {
+            if !program.labels.contains_key(label) {
+                program
+                    .labels
+                    .insert(label.clone(), (bases[idx] + pc) as f64);
+            }
+        }
+    }
+    program.label_refs.sort_unstable();
+    program.constants = program.labels.clone();
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimizer::optimize;
+    use crate::parser::assemble_file;
+    use crate::vm::Vm;
+
+    fn object(src: &str, filename: &str) -> (String, Object) {
+        let program = assemble_file(src, filename).unwrap();
+        let object = Object::from_program(&program);
+        let bytes = object.to_bytes();
+        assert!(is_object(&bytes));
+        assert_eq!(Object::from_bytes(&bytes), Ok(object.clone()));
+        (filename.to_string(), object)
+    }
+
+    #[test]
+    fn test_link() {
+        let main = object(
+            ".extern DOUBLE, TABLE, EXIT
+li x5, 4
+jal ra, DOUBLE
+store x5, zero, 0x300
+li x6, TABLE
+aipc x7, DOUBLE
+li x5, 1
+jalr ra, x7, 0
+store x5, zero, 0x301
+jal zero, EXIT
+",
+            "main.asm",
+        );
+        let lib = object(
+            ".global DOUBLE, TABLE, EXIT
+HELPER:
+jalr zero, ra, 0
+DOUBLE:
+add x5, x5, x5
+beq zero, zero, DONE
+DONE:
+jalr zero, ra, 0
+TABLE:
+li x8, HELPER
+EXIT:
+",
+            "lib.asm",
+        );
+        assert_eq!(
+            main.1.relocations,
+            vec![
+                Relocation {
+                    pc: 1,
+                    symbol: "DOUBLE".to_string(),
+                    relative: true
+                },
+                Relocation {
+                    pc: 3,
+                    symbol: "TABLE".to_string(),
+                    relative: false
+                },
+                Relocation {
+                    pc: 4,
+                    symbol: "DOUBLE".to_string(),
+                    relative: true
+                },
+                Relocation {
+                    pc: 8,
+                    symbol: "EXIT".to_string(),
+                    relative: true
+                },
+            ]
+        );
+
+        let program = link(&[main.clone(), lib.clone()]).unwrap();
+        assert_eq!(program.ops.len(), 14);
+        assert_eq!(program.labels["DOUBLE"], 10.0);
+        assert_eq!(program.labels["EXIT"], 14.0);
+        assert_eq!(program.ops[1].imm, 9.0);
+        assert_eq!(program.ops[3].imm, 13.0);
+        assert_eq!(program.ops[13].imm, 9.0);
+        assert_eq!(program.label_refs, vec![3, 13]);
+        assert_eq!(program.files, vec!["main.asm", "lib.asm"]);
+        assert_eq!(program.locations[10].file, 1);
+
+        let mut vm = Vm::new(program.ops.clone());
+        while !vm.is_halted() {
+            vm.run_frame().unwrap();
+        }
+        assert_eq!(vm.memory[0x300], 8.0);
+        assert_eq!(vm.memory[0x301], 2.0);
+
+        let errors = link(std::slice::from_ref(&main)).unwrap_err();
+        assert_eq!(
+            errors.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+            vec![
+                "Undefined symbol DOUBLE referenced in main.asm",
+                "Undefined symbol TABLE referenced in main.asm",
+                "Undefined symbol DOUBLE referenced in main.asm",
+                "Undefined symbol EXIT referenced in main.asm",
+            ]
+        );
+        // functions move with their module, and lines count on from the
+        // previous module's so a line still finds the ops it names
+        let func = object(".global F\n.func F\nli x5, 1\nret\n.endfunc\n", "func.asm");
+        let program = link(&[lib.clone(), func]).unwrap();
+        assert_eq!(program.functions[0].name, "F");
+        assert_eq!(program.functions[0].start, 5);
+        assert_eq!(program.functions[0].end, 7);
+        assert_eq!(program.lines[4], 9);
+        assert_eq!(program.lines[5], 12);
+        assert_eq!(program.pc_at_line(10), 5);
+
+        // reservations keep their addresses, so they mustn't collide
+        let fixed = object(".reserve SPRITES, 36, at=0x200\n", "fixed.asm");
+        let free = object(".reserve BUFFER, 0x10\n", "free.asm");
+        let program = link(&[fixed.clone(), free.clone()]).unwrap();
+        assert_eq!(program.regions.regions.len(), 2);
+        let errors = link(&[fixed, free.clone(), free]).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "BUFFER at 0x0100..0x0110 in free.asm overlaps BUFFER at 0x0100..0x0110 in free.asm"
+        );
+
+        // -O keeps the ops the linker fills in
+        let program = assemble_file(
+            ".extern SIX\nli x5, SIX\nadd x6, zero, x5\nstore x6, zero, 0x300\n",
+            "opt.asm",
+        )
+        .unwrap();
+        let optimized = Object::from_program(&optimize(&program).0);
+        let six = object(".global SIX\nnop\nnop\nnop\nSIX:\n", "six.asm");
+        let program = link(&[(String::from("opt.asm"), optimized), six]).unwrap();
+        let mut vm = Vm::new(program.ops.clone());
+        while !vm.is_halted() {
+            vm.run_frame().unwrap();
+        }
+        assert_eq!(vm.memory[0x300], 6.0);
+
+        let lib2 = (String::from("lib2.asm"), lib.1.clone());
+        let errors = link(&[main, lib, lib2]).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "Duplicate symbol DOUBLE in lib.asm and lib2.asm"
+        );
+    }
+}
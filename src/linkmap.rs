@@ -0,0 +1,201 @@
+use crate::cartridge::{body_sizes, serialize_ops};
+use crate::compression::compress_bytes;
+use crate::parser::Program;
+use serde_json::json;
+use std::fmt::Write;
+use std::vec::Vec;
+
+// Where the bytes of a cartridge go: each label starts a region running up
+// to the next label, and every part of the cartridge is measured before and
+// after zstd. Sections are compressed on their own here, so their
+// compressed sizes only roughly add up to the cartridge's.
+
+// serialized size of one op: 4 register/opcode bytes + 8 immediate bytes
+const OP_BYTES: usize = 12;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    // labels sharing a pc are listed together
+    pub name: String,
+    pub start: usize,
+    pub ops: usize,
+    // fraction of the serialized program
+    pub share: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: &'static str,
+    pub bytes: usize,
+    pub compressed: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkMap {
+    pub ops: usize,
+    // largest first
+    pub regions: Vec<Region>,
+    pub sections: Vec<Section>,
+}
+
+fn section(name: &'static str, data: &[u8]) -> Section {
+    Section {
+        name,
+        bytes: data.len(),
+        compressed: compress_bytes(&data.to_vec()).len(),
+    }
+}
+
+fn regions(program: &Program, program_bytes: usize) -> Vec<Region> {
+    let mut starts: Vec<(usize, String)> = program
+        .labels
+        .iter()
+        .map(|(name, pc)| (*pc as usize, name.clone()))
+        .collect();
+    starts.sort();
+    // code before the first label still takes space
+    if starts.first().map(|(pc, _)| *pc) != Some(0) && !program.ops.is_empty() {
+        starts.insert(0, (0, "(start)".to_string()));
+    }
+
+    let mut regions: Vec<Region> = Vec::new();
+    for (idx, (start, name)) in starts.iter().enumerate() {
+        if let Some(last) = regions.last_mut().filter(|r| r.start == *start) {
+            last.name += &format!(", {}", name);
+            continue;
+        }
+        let end = starts[idx..]
+            .iter()
+            .map(|(pc, _)| *pc)
+            .find(|pc| pc > start)
+            .unwrap_or(program.ops.len());
+        let ops = end.saturating_sub(*start);
+        regions.push(Region {
+            name: name.clone(),
+            start: *start,
+            ops,
+            share: (ops * OP_BYTES) as f64 / program_bytes.max(1) as f64,
+        });
+    }
+    regions.sort_by(|a, b| b.ops.cmp(&a.ops).then(a.start.cmp(&b.start)));
+    regions
+}
+
+impl LinkMap {
+    // The cartridge, if there is one, is what pack_cartridge produced
+    pub fn new(
+        program: &Program,
+        metadata: &str,
+        videorom: &[u8],
+        cartridge: Option<&[u8]>,
+    ) -> LinkMap {
+        let program_data = serialize_ops(&program.ops);
+        let mut sections = vec![
+            section("program", &program_data),
+            section("vrom", videorom),
+            section("metadata", metadata.as_bytes()),
+        ];
+        if let Some(Ok((bytes, compressed))) = cartridge.map(body_sizes) {
+            sections.push(Section {
+                name: "cartridge",
+                bytes,
+                compressed,
+            });
+        }
+        LinkMap {
+            ops: program.ops.len(),
+            regions: regions(program, program_data.len()),
+            sections,
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{:<32} {:>6} {:>6} {:>7}",
+            "REGION", "START", "OPS", "SHARE"
+        );
+        for region in self.regions.iter() {
+            let _ = writeln!(
+                out,
+                "{:<32} {:>6} {:>6} {:>6.1}%",
+                region.name,
+                region.start,
+                region.ops,
+                region.share * 100.0
+            );
+        }
+        let _ = writeln!(out, "{:<32} {:>6} {:>6}", "total", "", self.ops);
+        let _ = writeln!(out, "\n{:<32} {:>10} {:>10}", "SECTION", "BYTES", "ZSTD");
+        for section in self.sections.iter() {
+            let _ = writeln!(
+                out,
+                "{:<32} {:>10} {:>10}",
+                section.name, section.bytes, section.compressed
+            );
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        let regions: Vec<_> = self
+            .regions
+            .iter()
+            .map(|r| json!({"name": r.name, "start": r.start, "ops": r.ops, "share": r.share}))
+            .collect();
+        let sections: Vec<_> = self
+            .sections
+            .iter()
+            .map(|s| json!({"name": s.name, "bytes": s.bytes, "compressed": s.compressed}))
+            .collect();
+        json!({"ops": self.ops, "regions": regions, "sections": sections}).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::pack_cartridge;
+    use crate::parser::assemble;
+
+    #[test]
+    fn test_link_map() {
+        let program = assemble(
+            "li x1, 1
+MAIN:
+ALSO_MAIN:
+jal ra, SUB
+jal zero, MAIN
+SUB:
+addi x1, x1, 1
+addi x1, x1, 1
+addi x1, x1, 1
+jalr zero, ra, 0
+",
+        )
+        .unwrap();
+        let cart = pack_cartridge(None, None, &program.ops, None, None, true);
+        let map = LinkMap::new(&program, "{}", &[], Some(&cart));
+        let regions: Vec<(&str, usize, usize)> = map
+            .regions
+            .iter()
+            .map(|r| (r.name.as_str(), r.start, r.ops))
+            .collect();
+        assert_eq!(
+            regions,
+            vec![("SUB", 3, 4), ("ALSO_MAIN, MAIN", 1, 2), ("(start)", 0, 1)]
+        );
+        // 7 ops of 12 bytes, plus the 12 byte header
+        assert_eq!(map.regions[0].share, 0.5);
+        let names: Vec<&str> = map.sections.iter().map(|s| s.name).collect();
+        assert_eq!(names, vec!["program", "vrom", "metadata", "cartridge"]);
+        assert_eq!(map.sections[0].bytes, 96);
+
+        let text = map.to_text();
+        assert!(text.contains("\nSUB                                   3      4   50.0%\n"));
+        let json: serde_json::Value = serde_json::from_str(&map.to_json()).unwrap();
+        assert_eq!(json["regions"][0]["name"], "SUB");
+        assert_eq!(json["sections"][0]["bytes"], 96);
+    }
+}
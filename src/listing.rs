@@ -0,0 +1,136 @@
+use crate::ops::{op_info, Op};
+use crate::parser::Program;
+use crate::symbols::Symbols;
+use std::fmt::Write;
+
+// Annotated listing: every source line, with the encoding of the op it
+// assembled to (if any), followed by a symbol table. Ops with pc-relative
+// immediates also get the label they point at.
+
+fn encoded(pc: usize, op: &Op) -> String {
+    format!(
+        "{:5}  {:02x} {:02x} {:02x} {:02x}  {:016x}  {:<12}",
+        pc,
+        op.op.opcode,
+        op.op.rd,
+        op.op.rs1,
+        op.op.rs2,
+        op.imm.to_bits(),
+        op.imm.to_string()
+    )
+}
+
+// Where a pc-relative immediate points
+fn target(pc: usize, op: &Op, symbols: &Symbols) -> Option<String> {
+    if !op_info(op.op.opcode)?.rel() {
+        return None;
+    }
+    let target = pc as f64 + op.imm;
+    if target.fract() != 0.0 || target < 0.0 {
+        return None;
+    }
+    Some(match symbols.label_at(target as usize) {
+        Some((label, 0)) => label.to_string(),
+        _ => format!("pc {}", target),
+    })
+}
+
+pub fn listing(program: &Program, src: &str) -> String {
+    let symbols = Symbols::from_program(program);
+    let mut out = String::new();
+    let mut pc = 0;
+    let _ = writeln!(
+        out,
+        "{:>5}  {:<11}  {:<16}  {:<12}  {:>5}  SOURCE",
+        "PC", "BYTES", "IMM (HEX)", "IMM", "LINE"
+    );
+    for (linepos, text) in src.lines().enumerate() {
+        let mut first = true;
+        while pc < program.ops.len() && program.lines[pc] == linepos {
+            let op = &program.ops[pc];
+            let mut source = if first {
+                text.to_string()
+            } else {
+                String::new()
+            };
+            if let Some(target) = target(pc, op, &symbols) {
+                source += &format!("  ; -> {}", target);
+            }
+            let _ = writeln!(out, "{}  {:5}  {}", encoded(pc, op), linepos + 1, source);
+            first = false;
+            pc += 1;
+        }
+        if first {
+            let _ = writeln!(out, "{:50}  {:5}  {}", "", linepos + 1, text);
+        }
+    }
+
+    let _ = writeln!(out, "\nLabels:");
+    for (name, pc) in symbols.labels.iter() {
+        let _ = writeln!(out, "  {:<24} pc {}", name, pc);
+    }
+    let _ = writeln!(out, "\nConstants:");
+    for (name, value) in symbols.constants.iter() {
+        let _ = writeln!(out, "  {:<24} {}", name, value);
+    }
+    if !symbols.functions.is_empty() {
+        let _ = writeln!(out, "\nFunctions:");
+    }
+    for f in symbols.functions.iter() {
+        let saves: Vec<String> = f.saves.iter().map(|reg| format!("x{}", reg)).collect();
+        let _ = writeln!(
+            out,
+            "  {:<24} pc {}, {} ops, saves {}",
+            f.name,
+            f.start,
+            f.end - f.start,
+            match saves.is_empty() {
+                true => "nothing".to_string(),
+                false => saves.join(" "),
+            }
+        );
+        for (name, reg) in f.registers.iter() {
+            let _ = writeln!(out, "    {:<22} x{}", name, reg);
+        }
+    }
+    let _ = writeln!(out, "\nRegister aliases:");
+    for (name, reg) in symbols.aliases.iter() {
+        let _ = writeln!(out, "  {:<24} x{}", name, reg);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::assemble;
+
+    #[test]
+    fn test_listing() {
+        let src = "const N = 3
+reg count = x5
+// count down
+LOOP:
+subi count, count, 1 ; one less
+bne count, zero, LOOP
+aipc x6, 5
+";
+        let program = assemble(src).unwrap();
+        let text = listing(&program, src);
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].starts_with("   PC  BYTES"));
+        assert!(lines[1].ends_with("    1  const N = 3"));
+        assert_eq!(
+            lines[5],
+            "    0  0f 05 05 00  3ff0000000000000  1                 5  subi count, count, 1 ; one less"
+        );
+        assert_eq!(
+            lines[6],
+            "    1  2f 00 05 00  bff0000000000000  -1                6  bne count, zero, LOOP  ; -> LOOP"
+        );
+        assert!(lines[7].contains("-> pc 7"));
+        assert!(text.contains("\nLabels:\n  LOOP                     pc 0\n"));
+        assert!(text.contains("  N                        3\n"));
+        assert!(text.contains("  count                    x5\n"));
+    }
+}
@@ -1,16 +1,34 @@
-use asmjr::{cartridge, metadata, parser};
-use clap::Parser;
+use asmjr::analysis::budget::Budget;
+use asmjr::analysis::liveness;
+use asmjr::analysis::memory::MemoryMap;
+use asmjr::analysis::races;
+use asmjr::linker::Object;
+use asmjr::linkmap::LinkMap;
+use asmjr::sourcemap::SourceMap;
+use asmjr::symbols::Symbols;
+use asmjr::{cartridge, linker, listing, metadata, optimizer, parser, vm};
+use clap::{Parser, Subcommand};
 use std::fs;
 use std::fs::read_to_string;
+use std::process;
 
+pub mod cfg;
+pub mod debug;
+pub mod link;
+pub mod run;
+pub mod unittest;
 pub mod vrom;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Assembly source file
-    #[clap(value_parser)]
-    source: String,
+    #[clap(value_parser, required = true)]
+    source: Option<String>,
 
     /// Output ECJR cartridge file
     #[clap(value_parser)]
@@ -44,28 +62,195 @@ struct Args {
     #[clap(long, action)]
     bare: bool,
 
-    /// Dump out ops to terminal
-    #[clap(short, long, action)]
-    listing: bool,
+    /// Leave the source map out of the cartridge (for release builds)
+    #[clap(long, action)]
+    strip: bool,
+
+    /// Also write the source map as JSON to this file
+    #[clap(long, value_parser)]
+    source_map: Option<String>,
+
+    /// Embed label, constant and register alias names in the cartridge
+    #[clap(long, action)]
+    symbols: bool,
+
+    /// Write a code size report per label to this file (JSON if it ends in .linkmap.json)
+    #[clap(long, value_parser, value_name = "FILE")]
+    map: Option<String>,
+
+    /// Write an annotated listing to a file, or the terminal if none is given
+    #[clap(short, long, value_parser, value_name = "FILE", min_values = 0)]
+    listing: Option<Option<String>>,
+
+    /// Apply peephole optimizations before writing anything out
+    #[clap(short = 'O', long, action)]
+    optimize: bool,
+
+    /// Write an object file for the link command instead of a cartridge
+    #[clap(short = 'c', long, action)]
+    object: bool,
+
+    /// Remove code that can't be reached from pc 0 or a spawn target
+    #[clap(long, action)]
+    prune: bool,
+
+    /// Label to keep when pruning, for code only reached through jalr (repeatable)
+    #[clap(long, value_parser, value_name = "LABEL")]
+    keep: Vec<String>,
+
+    /// Print worst-case op counts between yields per loop and label
+    #[clap(long, action)]
+    budget: bool,
+
+    /// Print the memory each label reads and writes at known addresses
+    #[clap(long, action)]
+    memory: bool,
+
+    /// Skip the register, frame budget, memory and race checks
+    #[clap(long, action)]
+    no_warnings: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Execute a program in the reference VM without any video/audio output
+    Run(run::RunArgs),
+    /// Run the @test annotations in assembly sources
+    Test(unittest::TestArgs),
+    /// Step through a program interactively
+    Debug(debug::DebugArgs),
+    /// Show the control-flow graph of a program
+    Cfg(cfg::CfgArgs),
+    /// Link object files built with -c into a cartridge
+    Link(link::LinkArgs),
 }
 
 fn main() {
     let args = Args::parse();
 
-    let sourcefile = read_to_string(args.source).expect("Failed to read source file!");
+    match args.command {
+        Some(Command::Run(run_args)) => process::exit(run::run(run_args)),
+        Some(Command::Test(test_args)) => process::exit(unittest::test(test_args)),
+        Some(Command::Debug(debug_args)) => process::exit(debug::debug(debug_args)),
+        Some(Command::Cfg(cfg_args)) => process::exit(cfg::cfg(cfg_args)),
+        Some(Command::Link(link_args)) => process::exit(link::link(link_args)),
+        None => build(args),
+    }
+}
 
-    let ops = match parser::parse(&sourcefile) {
-        Ok(ops) => ops,
+fn write_map(filename: &str, map: &LinkMap) {
+    // run picks up game.map.json next to game.cart as the source map
+    if filename.ends_with(".map.json") {
+        println!(
+            "Not writing the map to {}, that name is for source maps. Use .linkmap.json instead.",
+            filename
+        );
+        return;
+    }
+    let text = if filename.ends_with(".linkmap.json") {
+        map.to_json()
+    } else {
+        map.to_text()
+    };
+    fs::write(filename, text).expect("Failed to write map!");
+    println!("Wrote map to {}.", filename);
+}
+
+fn build(args: Args) {
+    let source = args.source.expect("Source file is required!");
+    let sourcefile = read_to_string(&source).expect("Failed to read source file!");
+
+    let mut program = match parser::assemble_file(&sourcefile, &source) {
+        Ok(program) => program,
         Err(e) => {
-            println!("{}", e.to_string());
+            println!("{}", e);
             return;
         }
     };
+    println!("Assembled {} ops.", program.ops.len());
+    if !program.regions.regions.is_empty() {
+        print!("{}", program.regions.to_text());
+    }
+    if let Some(label) = args.keep.iter().find(|l| !program.labels.contains_key(*l)) {
+        println!("Can't keep {}, there's no label with that name.", label);
+        return;
+    }
+    if args.prune {
+        let before = program.ops.len();
+        let (pruned, removed) = optimizer::remove_dead_code(&program, &args.keep);
+        println!(
+            "Removed {} unreachable ops{}.",
+            before - pruned.ops.len(),
+            match removed.is_empty() {
+                true => String::new(),
+                false => format!(" ({})", removed.join(", ")),
+            }
+        );
+        program = pruned;
+    }
+    if args.optimize {
+        let (optimized, savings) = optimizer::optimize(&program);
+        println!("Optimized away {}.", savings.describe());
+        program = optimized;
+    }
+    if args.object {
+        let Some(output) = args.output else {
+            println!("No output file specified.");
+            return;
+        };
+        let data = Object::from_program(&program).to_bytes();
+        fs::write(&output, &data).expect("Failed to write object file!");
+        println!("Wrote {} bytes of object file to {}.", data.len(), output);
+        return;
+    }
+    if let Some(reason) = linker::unlinked(&program) {
+        println!("{}", reason);
+        return;
+    }
+    let ops = &program.ops;
+    let source_map = SourceMap::from_program(&program);
 
-    println!("Assembled {} ops.", ops.len());
+    // the analyses only run when something is going to use them
+    let warnings = !args.no_warnings;
+    let budget = (warnings || args.budget).then(|| Budget::new(&program));
+    let memory = (warnings || args.memory).then(|| MemoryMap::new(&program));
+    if warnings {
+        let mut findings = liveness::findings(&program);
+        if let Some(budget) = &budget {
+            findings.extend(budget.findings(vm::DEFAULT_CLOCK));
+        }
+        if let Some(memory) = &memory {
+            findings.extend(memory.findings());
+        }
+        findings.extend(races::findings(&program));
+        for finding in findings {
+            println!("Warning on {}", finding.describe(&program));
+        }
+    }
+    if let Some(budget) = budget.filter(|_| args.budget) {
+        print!("{}", budget.to_text(vm::DEFAULT_CLOCK));
+    }
+    if let Some(memory) = memory.filter(|_| args.memory) {
+        print!("{}", memory.to_text());
+    }
 
-    if args.listing {
-        parser::print_ops(&ops);
+    match args.listing {
+        Some(Some(filename)) if filename == source => {
+            println!("Refusing to overwrite the source file with the listing.");
+            return;
+        }
+        Some(Some(filename)) => {
+            fs::write(&filename, listing::listing(&program, &sourcefile))
+                .expect("Failed to write listing!");
+            println!("Wrote listing to {}.", filename);
+        }
+        Some(None) => print!("{}", listing::listing(&program, &sourcefile)),
+        None => {}
+    }
+
+    if let Some(filename) = args.source_map {
+        fs::write(&filename, source_map.to_json()).expect("Failed to write source map!");
+        println!("Wrote source map to {}.", filename);
     }
 
     let output = match args.output {
@@ -77,13 +262,16 @@ fn main() {
     };
 
     if args.bare {
-        let bare_prog = cartridge::serialize_ops(&ops);
+        let bare_prog = cartridge::serialize_ops(ops);
         fs::write(&output, &bare_prog).expect("Failed to write output!");
         println!(
             "Wrote {} bytes of bare program to {}.",
             bare_prog.len(),
             output
         );
+        if let Some(filename) = args.map {
+            write_map(&filename, &LinkMap::new(&program, "", &[], None));
+        }
         return;
     }
 
@@ -100,7 +288,26 @@ fn main() {
     let metadata = metadata::format_metadata(args.author, readme);
     println!("Metadata: {}", metadata);
 
-    let cartdata = cartridge::pack_cartridge(Some(metadata), videorom, &ops, !args.uncompressed);
+    let source_map = if args.strip { None } else { Some(&source_map) };
+    let symbols = if args.symbols {
+        Some(Symbols::from_program(&program))
+    } else {
+        None
+    };
+    let cartdata = cartridge::pack_cartridge(
+        Some(metadata.clone()),
+        videorom.clone(),
+        ops,
+        source_map,
+        symbols.as_ref(),
+        !args.uncompressed,
+    );
     fs::write(&output, &cartdata).expect("Failed to write output file!");
     println!("Wrote {} bytes to {}.", cartdata.len(), output);
+
+    if let Some(filename) = args.map {
+        let videorom = videorom.unwrap_or_default();
+        let map = LinkMap::new(&program, &metadata, &videorom, Some(&cartdata));
+        write_map(&filename, &map);
+    }
 }
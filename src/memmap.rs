@@ -1,3 +1,4 @@
+use crate::layout::Layout;
 use std::collections::HashMap;
 
 const MEMMAP: &[&str] = &[
@@ -33,8 +34,83 @@ const MEMMAP: &[&str] = &[
     "ARR_INPUT_GAMEPADS",
 ];
 
+// Records the hardware reads from or writes to memory, by field and size.
+// Only what examples/dvdlogo.asm relies on is named: sprites live at
+// $VIDEO_SPRITE_BUFFER_ADDR, twelve words each, and the words past the
+// foreground color don't have a documented meaning yet. Gamepads, colormap
+// entries and audio buffers are left out on purpose: nothing documents
+// their fields or stride, and a guessed stride would misplace every record
+// after the first. They get added here once the hardware pins them down.
+const LAYOUTS: &[(&str, &[(&str, usize)])] = &[(
+    "SPRITE",
+    &[
+        ("SRC_X", 1),
+        ("SRC_Y", 1),
+        ("SRC_W", 1),
+        ("SRC_H", 1),
+        ("X", 1),
+        ("Y", 1),
+        ("W", 1),
+        ("H", 1),
+        ("FG_COLOR", 1),
+        ("RESERVED", 3),
+    ],
+)];
+
+pub fn layouts() -> Vec<Layout> {
+    LAYOUTS
+        .iter()
+        .map(|(name, fields)| Layout {
+            name: format!("${}", name),
+            fields: fields
+                .iter()
+                .map(|(field, size)| (field.to_string(), *size))
+                .collect(),
+        })
+        .collect()
+}
+
 pub fn add_memmap_constants(constants: &mut HashMap<String, f64>) {
     for (addr, name) in MEMMAP.iter().enumerate() {
         constants.insert(format!("${}", name), addr as f64);
     }
+    for layout in layouts() {
+        constants.extend(layout.constants());
+    }
+}
+
+// Addresses below this are reserved for I/O, though only the start of the
+// range has names so far
+pub const IO_END: usize = 0x100;
+
+pub fn memmap_name(addr: usize) -> Option<&'static str> {
+    MEMMAP.get(addr).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::assemble;
+    use crate::vm::Vm;
+
+    #[test]
+    fn test_layouts() {
+        let program = assemble(
+            "li x5, 1
+muli x5, x5, $SPRITE.SIZE
+addi x5, x5, 0x200
+li x6, 40
+store x6, x5[$SPRITE.FG_COLOR]
+",
+        )
+        .unwrap();
+        assert_eq!(program.constants["$SPRITE.SIZE"], 12.0);
+        assert_eq!(program.constants["$SPRITE.X"], 4.0);
+        assert!(program.user_constants().is_empty());
+
+        let mut vm = Vm::new(program.ops.clone());
+        while !vm.is_halted() {
+            vm.run_frame().unwrap();
+        }
+        assert_eq!(vm.memory[0x200 + 12 + 8], 40.0);
+    }
 }
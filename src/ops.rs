@@ -1,7 +1,8 @@
 use phf::phf_map;
 use std::collections::HashMap;
+use std::fmt;
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OpArg {
     Rd,
     Rs1,
@@ -86,6 +87,37 @@ static OPS: phf::Map<&'static str, OpInfo> = phf_map! {
 "atan" => OpInfo{opcode: 64, argct: 3, args: [OpArg::Rd, OpArg::Rs1, OpArg::Rs2], rel: false},
 };
 
+impl OpInfo {
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    // the operands written in source, in order
+    pub fn args(&self) -> &[OpArg] {
+        &self.args[..self.argct]
+    }
+
+    // whether a label immediate is encoded relative to the op's own pc
+    pub fn rel(&self) -> bool {
+        self.rel
+    }
+}
+
+// Reverse lookup from an encoded opcode back to its mnemonic
+pub fn op_name(opcode: u8) -> Option<&'static str> {
+    OPS.entries()
+        .find(|(_, info)| info.opcode == opcode)
+        .map(|(name, _)| *name)
+}
+
+pub fn op_by_name(name: &str) -> Option<&'static OpInfo> {
+    OPS.get(name)
+}
+
+pub fn op_info(opcode: u8) -> Option<&'static OpInfo> {
+    OPS.values().find(|info| info.opcode == opcode)
+}
+
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct COp {
     pub opcode: u8,
@@ -95,7 +127,7 @@ pub struct COp {
 }
 
 impl COp {
-    fn set_arg(&mut self, arg: &OpArg, register: u8) {
+    pub fn set_arg(&mut self, arg: &OpArg, register: u8) {
         match arg {
             OpArg::Rd => self.rd = register,
             OpArg::Rs1 => self.rs1 = register,
@@ -120,25 +152,47 @@ pub enum OpErr {
     InvalidArgumentCount(usize, usize),
     InvalidImmediate(String),
     InvalidRegister(String),
+    InvalidDirective(String),
+    UnknownLabel(String),
+    // the label, and where it was defined first
+    DuplicateLabel(String, String),
+    ExternDefined(String),
+    InvalidFunction(String),
+    InvalidBlock(String),
+    InvalidStruct(String),
+    InvalidEnum(String),
+    InvalidReserve(String),
 }
 
-impl ToString for OpErr {
-    fn to_string(&self) -> String {
+impl fmt::Display for OpErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            OpErr::Impossible => "This shouldn't be possible!".to_string(),
-            OpErr::EmptyOp => "Empty opcode (grammar problem?)".to_string(),
-            OpErr::InvalidAlias(s) => format!("Invalid alias: \"{}\"", s),
-            OpErr::InvalidOpcode(s) => format!("Unrecognized opcode: [{}]", s),
-            OpErr::InvalidArgumentCount(got, expected) => format!(
+            OpErr::Impossible => write!(f, "This shouldn't be possible!"),
+            OpErr::EmptyOp => write!(f, "Empty opcode (grammar problem?)"),
+            OpErr::InvalidAlias(s) => write!(f, "Invalid alias: \"{}\"", s),
+            OpErr::InvalidOpcode(s) => write!(f, "Unrecognized opcode: [{}]", s),
+            OpErr::InvalidArgumentCount(got, expected) => write!(
+                f,
                 "Wrong number of arguments: got {}, expected {}",
                 got, expected
             ),
             OpErr::InvalidImmediate(s) => {
-                format!("Immediate \"{}\" is not a literal or known label", s)
+                write!(f, "Immediate \"{}\" is not a literal or known label", s)
             }
             OpErr::InvalidRegister(s) => {
-                format!("Register \"{}\" is not a literal or known alias", s)
+                write!(f, "Register \"{}\" is not a literal or known alias", s)
+            }
+            OpErr::InvalidDirective(s) => write!(f, "Unrecognized directive: [.{}]", s),
+            OpErr::UnknownLabel(s) => write!(f, "Label \"{}\" is not defined", s),
+            OpErr::DuplicateLabel(s, first) => {
+                write!(f, "Label \"{}\" is already defined at {}", s, first)
             }
+            OpErr::InvalidFunction(s)
+            | OpErr::InvalidBlock(s)
+            | OpErr::InvalidStruct(s)
+            | OpErr::InvalidEnum(s)
+            | OpErr::InvalidReserve(s) => write!(f, "{}", s),
+            OpErr::ExternDefined(s) => write!(f, "\"{}\" is declared .extern but defined here", s),
         }
     }
 }
@@ -192,7 +246,7 @@ pub fn parse_immediate(
     }
 }
 
-fn parse_register(token: &str, aliases: &HashMap<String, u8>) -> Result<u8, OpErr> {
+pub fn parse_register(token: &str, aliases: &HashMap<String, u8>) -> Result<u8, OpErr> {
     // Allow numeric literals as register designations
     if let Ok(barenum) = parse_int::parse::<u8>(token) {
         return Ok(barenum);
@@ -0,0 +1,452 @@
+use crate::analysis::cfg::{flow, Cfg, Flow};
+use crate::analysis::liveness::live_out;
+use crate::analysis::OpGraph;
+use crate::ops::{op_by_name, op_info, op_name, Op};
+use crate::parser::Program;
+use std::collections::{BTreeMap, BTreeSet};
+use std::vec::Vec;
+
+// Peephole optimizations on assembled programs. Each rule only drops ops
+// that do nothing, or folds two ops into one, so the program computes the
+// same results in fewer ops. Programs that read clk, or that jalr anywhere
+// but back after a call or to a label or aipc address, can tell the
+// difference.
+//
+// Removing ops moves everything after them, so pc-relative immediates,
+// label immediates (li x5, LOOP), labels and source lines are all fixed up
+// to match. Anything that jumped to a removed op lands on the op after it.
+
+// rule descriptions, in the order they're reported
+const ADD_ZERO: &str = "addi/subi x, x, 0";
+const LOAD_ADD: &str = "li + add into addi";
+const MOVE_SELF: &str = "mv x, x";
+const NOP: &str = "nop";
+const JUMP_NEXT: &str = "jump to the next op";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Savings {
+    // ops saved by each rule
+    pub rules: BTreeMap<&'static str, usize>,
+}
+
+impl Savings {
+    pub fn total(&self) -> usize {
+        self.rules.values().sum()
+    }
+
+    // "3 ops (nop: 2, mv x, x: 1)"
+    pub fn describe(&self) -> String {
+        let rules: Vec<String> = [ADD_ZERO, LOAD_ADD, MOVE_SELF, NOP, JUMP_NEXT]
+            .iter()
+            .filter_map(|rule| self.rules.get(rule).map(|n| format!("{}: {}", rule, n)))
+            .collect();
+        let ops = match self.total() {
+            1 => "1 op".to_string(),
+            total => format!("{} ops", total),
+        };
+        match rules.is_empty() {
+            true => ops,
+            false => format!("{} ({})", ops, rules.join(", ")),
+        }
+    }
+}
+
+// Where a pc-relative immediate points, if it's inside the program or
+// right at its end
+fn rel_target(ops: &[Op], pc: usize) -> Option<usize> {
+    if !op_info(ops[pc].op.opcode)?.rel() {
+        return None;
+    }
+    let target = pc as f64 + ops[pc].imm;
+    (target.fract() == 0.0 && target >= 0.0 && target <= ops.len() as f64)
+        .then_some(target as usize)
+}
+
+// Ops control can reach other than by falling through from the op before
+pub fn entered(program: &Program) -> BTreeSet<usize> {
+    let ops = &program.ops;
+    let mut targets: BTreeSet<usize> = program.labels.values().map(|pc| *pc as usize).collect();
+    targets.extend((0..ops.len()).filter_map(|pc| rel_target(ops, pc)));
+    targets.extend(program.label_refs.iter().map(|pc| ops[*pc].imm as usize));
+    targets.insert(0);
+    targets
+}
+
+// Where a jalr can go: back after a call, or to a label or aipc address
+// that was put in a register
+fn indirect_targets(program: &Program) -> Vec<usize> {
+    let ops = &program.ops;
+    let mut targets: BTreeSet<usize> = BTreeSet::new();
+    for pc in 0..ops.len() {
+        match op_name(ops[pc].op.opcode) {
+            Some("jal" | "jalr") if ops[pc].op.rd != 0 => drop(targets.insert(pc + 1)),
+            Some("aipc") => targets.extend(rel_target(ops, pc)),
+            _ => {}
+        }
+    }
+    targets.extend(program.label_refs.iter().map(|pc| ops[*pc].imm as usize));
+    targets.into_iter().collect()
+}
+
+// The program without the ops that aren't kept, with everything pointing
+// at pcs moved to match
+pub fn remove_ops(program: &Program, keep: &[bool]) -> Program {
+    let ops = &program.ops;
+    // new pc of each old pc, or of the next kept op after it
+    let mut moved: Vec<usize> = Vec::with_capacity(ops.len() + 1);
+    let mut count = 0;
+    for kept in keep.iter() {
+        moved.push(count);
+        count += *kept as usize;
+    }
+    moved.push(count);
+    let remap = |pc: f64| match pc.fract() == 0.0 && pc >= 0.0 && pc <= ops.len() as f64 {
+        true => moved[pc as usize] as f64,
+        false => pc,
+    };
+
+    let mut out = program.clone();
+    out.ops.clear();
+    out.lines.clear();
+    out.locations.clear();
+    for pc in (0..ops.len()).filter(|pc| keep[*pc]) {
+        let mut op = ops[pc].clone();
+        if let Some(target) = rel_target(ops, pc) {
+            op.imm = moved[target] as f64 - moved[pc] as f64;
+        }
+        out.ops.push(op);
+        out.lines.push(program.lines[pc]);
+        out.locations.push(program.locations[pc].clone());
+    }
+    out.label_refs = program
+        .label_refs
+        .iter()
+        .filter(|pc| keep[**pc])
+        .map(|pc| moved[*pc])
+        .collect();
+    // the linker still has to fill these in, so no rule may drop them
+    out.extern_refs = program
+        .extern_refs
+        .iter()
+        .map(|(pc, symbol)| {
+            debug_assert!(keep[*pc], "dropped the extern ref at pc {}", pc);
+            (moved[*pc], symbol.clone())
+        })
+        .collect();
+    for function in out.functions.iter_mut() {
+        function.start = moved[function.start];
+        function.end = moved[function.end];
+    }
+    out.functions
+        .retain(|function| function.end > function.start);
+    for pc in out.label_refs.iter() {
+        out.ops[*pc].imm = remap(out.ops[*pc].imm);
+    }
+    for (name, pc) in out.labels.iter_mut() {
+        *pc = remap(*pc);
+        out.constants.insert(name.clone(), *pc);
+    }
+    out
+}
+
+// One pass of every rule, or None if none of them apply
+fn pass(program: &Program, savings: &mut Savings) -> Option<Program> {
+    let ops = &program.ops;
+    let live = live_out(ops, &indirect_targets(program));
+    let entered = entered(program);
+    let relocated: BTreeSet<usize> = program.extern_refs.iter().map(|(pc, _)| *pc).collect();
+    let mut rewritten: Vec<Op> = ops.clone();
+    let mut keep = vec![true; ops.len()];
+    let mut drop = |keep: &mut Vec<bool>, pc: usize, rule: &'static str| {
+        keep[pc] = false;
+        *savings.rules.entry(rule).or_default() += 1;
+    };
+
+    for pc in 0..ops.len() {
+        if !keep[pc] {
+            continue;
+        }
+        let op = &ops[pc];
+        match op_name(op.op.opcode) {
+            Some("addi" | "subi") if op.op.rd == op.op.rs1 && op.imm == 0.0 => {
+                drop(&mut keep, pc, ADD_ZERO)
+            }
+            Some("mv") if op.op.rd == op.op.rs1 => drop(&mut keep, pc, MOVE_SELF),
+            Some("nop") => drop(&mut keep, pc, NOP),
+            Some("jal") if op.op.rd == 0 && op.imm == 1.0 => drop(&mut keep, pc, JUMP_NEXT),
+            // li r, K / add d, s, r when nothing else reads r and nothing
+            // jumps in between. K isn't known yet for .extern symbols.
+            Some("li")
+                if op.op.rd != 0
+                    && !program.label_refs.contains(&pc)
+                    && !relocated.contains(&pc) =>
+            {
+                let Some(next) = ops.get(pc + 1) else {
+                    continue;
+                };
+                if op_name(next.op.opcode) != Some("add") || entered.contains(&(pc + 1)) {
+                    continue;
+                }
+                let reg = op.op.rd;
+                let other = match (next.op.rs1 == reg, next.op.rs2 == reg) {
+                    (true, false) => next.op.rs2,
+                    (false, true) => next.op.rs1,
+                    _ => continue,
+                };
+                if live[pc + 1].contains(reg) && next.op.rd != reg {
+                    continue;
+                }
+                let addi = &mut rewritten[pc + 1];
+                addi.op.opcode = op_by_name("addi").unwrap().opcode();
+                addi.op.rs1 = other;
+                addi.op.rs2 = 0;
+                addi.imm = op.imm;
+                drop(&mut keep, pc, LOAD_ADD);
+            }
+            _ => {}
+        }
+    }
+
+    if keep.iter().all(|kept| *kept) {
+        return None;
+    }
+    let mut program = program.clone();
+    program.ops = rewritten;
+    Some(remove_ops(&program, &keep))
+}
+
+// Applies the rules until none of them do anything more
+pub fn optimize(program: &Program) -> (Program, Savings) {
+    let mut savings = Savings::default();
+    let mut program = program.clone();
+    while let Some(smaller) = pass(&program, &mut savings) {
+        program = smaller;
+    }
+    (program, savings)
+}
+
+// Ops control can get to without a static edge: after a jalr call, and
+// addresses loaded with aipc or li LABEL
+fn address_taken(program: &Program) -> Vec<usize> {
+    let ops = &program.ops;
+    let mut targets: Vec<usize> = Vec::new();
+    for pc in 0..ops.len() {
+        match flow(ops, pc) {
+            Flow::Indirect if ops[pc].op.rd != 0 => targets.push(pc + 1),
+            Flow::AddressOf(target) => targets.extend(target),
+            _ => {}
+        }
+    }
+    targets.extend(program.label_refs.iter().map(|pc| ops[*pc].imm as usize));
+    targets
+}
+
+// Removes blocks that can't be reached from pc 0, spawn targets, taken
+// addresses, .global labels or the labels in keep (and the program's .keep
+// directives).
+// Returns the smaller program and the labels that went with the removed
+// code.
+pub fn remove_dead_code(program: &Program, keep: &[String]) -> (Program, Vec<String>) {
+    let ops = &program.ops;
+    let cfg = Cfg::from_program(program);
+    let mut roots = OpGraph::entries(ops);
+    roots.extend(address_taken(program));
+    roots.extend(
+        keep.iter()
+            .chain(program.keep.iter())
+            .chain(program.globals.iter())
+            .filter_map(|label| program.labels.get(label))
+            .map(|pc| *pc as usize),
+    );
+    let roots: Vec<usize> = roots.iter().filter_map(|pc| cfg.block_at(*pc)).collect();
+    let live = cfg.reachable(&roots);
+    let mut kept = vec![false; ops.len()];
+    for idx in live {
+        let block = &cfg.blocks[idx];
+        kept[block.start..block.end].fill(true);
+    }
+
+    let mut removed: Vec<String> = program
+        .labels
+        .iter()
+        .filter(|(_, pc)| kept.get(**pc as usize) == Some(&false))
+        .map(|(name, _)| name.clone())
+        .collect();
+    removed.sort();
+    let mut out = remove_ops(program, &kept);
+    for name in removed.iter() {
+        out.labels.remove(name);
+        out.constants.remove(name);
+    }
+    (out, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::assemble;
+    use crate::vm::Vm;
+
+    // memory after running a program to the end, a frame at a time
+    fn run(program: &Program) -> Vec<f64> {
+        let mut vm = Vm::new(program.ops.clone());
+        for _ in 0..20 {
+            if vm.is_halted() {
+                break;
+            }
+            vm.run_frame().unwrap();
+        }
+        assert!(vm.is_halted());
+        vm.memory
+    }
+
+    #[test]
+    fn test_optimize() {
+        let src = "li x5, 10
+li x9, SUB
+nop
+LOOP:
+li x6, 3
+add x7, x5, x6
+addi x7, x7, 0
+mv x7, x7
+store x7, x5, 0x300
+jal ra, SUB
+subi x5, x5, 1
+jal zero, NEXT
+NEXT:
+bne x5, zero, LOOP
+jalr ra, x9, 0
+yield zero, 1
+jal zero, END
+SUB:
+li x8, 2
+add x8, x8, x7
+store x8, x5, 0x400
+jalr zero, ra, 0
+END:
+";
+        let program = assemble(src).unwrap();
+        let (optimized, savings) = optimize(&program);
+        assert_eq!(
+            savings.describe(),
+            "6 ops (addi/subi x, x, 0: 1, li + add into addi: 2, mv x, x: 1, nop: 1, jump to the next op: 1)"
+        );
+        assert_eq!(optimized.ops.len(), program.ops.len() - 6);
+        assert_eq!(run(&optimized), run(&program));
+
+        // labels and the li of one of them moved along with the ops
+        assert_eq!(optimized.labels["LOOP"], 2.0);
+        assert_eq!(optimized.labels["SUB"], 10.0);
+        assert_eq!(optimized.constants["SUB"], 10.0);
+        assert_eq!(optimized.ops[1].imm, 10.0);
+        assert_eq!(optimized.label_refs, vec![1]);
+        assert_eq!(optimized.lines[2], 5);
+        let addi = &optimized.ops[2];
+        assert_eq!(
+            (op_name(addi.op.opcode), addi.op.rd, addi.op.rs1, addi.imm),
+            (Some("addi"), 7, 5, 3.0)
+        );
+    }
+
+    #[test]
+    fn test_optimize_across_frames() {
+        let program = assemble(
+            "li x1, 2
+crcfg zero, x1
+crid x5
+li x6, 0x300
+add x6, x6, x5
+addi x10, x5, 1
+spawn x10, x5, COUNTER
+FRAME:
+load x7, x6, 0
+addi x7, x7, 1
+mv x7, x7
+store x7, x6, 0
+yield zero, 1
+jal zero, FRAME
+COUNTER:
+nop
+li x8, 0x310
+add x8, tp, x8
+load x9, x8, 0
+subi x9, x9, 0
+addi x9, x9, 2
+store x9, x8, 0
+yield zero, 1
+jal zero, COUNTER
+",
+        )
+        .unwrap();
+        let (optimized, savings) = optimize(&program);
+        assert_eq!(savings.total(), 5);
+        let mut before = Vm::new(program.ops.clone());
+        let mut after = Vm::new(optimized.ops.clone());
+        for _ in 0..10 {
+            before.run_frame().unwrap();
+            after.run_frame().unwrap();
+            assert_eq!(before.memory, after.memory);
+        }
+        assert_eq!(after.memory[0x301], 10.0);
+        assert_eq!(after.memory[0x311], 20.0);
+    }
+
+    #[test]
+    fn test_remove_dead_code() {
+        let src = ".keep TABLE_B
+li x5, TABLE
+li x10, 1
+spawn x10, zero, WORKER
+jal ra, USED
+jalr ra, x5, 0
+store x6, zero, 0x300
+jal zero, END
+UNUSED:
+li x6, 1
+jalr zero, ra, 0
+USED:
+li x7, 7
+beq x7, zero, USED
+jalr zero, ra, 0
+TABLE:
+li x6, 5
+jal zero, TABLE_B
+TABLE_B:
+jalr zero, ra, 0
+WORKER:
+store x7, zero, 0x301
+jal zero, END
+DEAD:
+jal zero, DEAD
+END:
+";
+        let program = assemble(src).unwrap();
+        assert_eq!(program.keep, vec!["TABLE_B"]);
+        let (pruned, removed) = remove_dead_code(&program, &[]);
+        assert_eq!(removed, vec!["DEAD", "UNUSED"]);
+        assert_eq!(pruned.ops.len(), program.ops.len() - 3);
+        assert_eq!(pruned.labels["USED"], 7.0);
+        assert_eq!(pruned.constants["WORKER"], 13.0);
+        assert!(!pruned.constants.contains_key("UNUSED"));
+        assert_eq!(pruned.ops[0].imm, 10.0);
+        assert_eq!(run(&pruned), run(&program));
+        assert_eq!(run(&pruned)[0x300], 5.0);
+
+        // TABLE_B only stays when something keeps it
+        let src = src
+            .replace(".keep TABLE_B", "")
+            .replace("jal zero, TABLE_B", "jalr zero, ra, 0");
+        let program = assemble(&src).unwrap();
+        assert_eq!(
+            remove_dead_code(&program, &[]).1,
+            vec!["DEAD", "TABLE_B", "UNUSED"]
+        );
+        assert_eq!(
+            remove_dead_code(&program, &["TABLE_B".to_string()]).1,
+            vec!["DEAD", "UNUSED"]
+        );
+        assert!(assemble(".keep NOWHERE").is_err());
+        assert!(assemble(".bogus").is_err());
+    }
+}
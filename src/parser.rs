@@ -1,9 +1,17 @@
+use crate::control::{self, Control};
+use crate::enums;
+use crate::layout::Layout;
 use crate::memmap::add_memmap_constants;
-use crate::ops::{parse_immediate, parse_op, Op, OpErr};
+use crate::ops::{op_info, parse_immediate, parse_op, parse_register, Op, OpArg, OpErr};
+use crate::regalloc::{self, VirtualFunction, VirtualUse};
+use crate::regions::Regions;
+use crate::stdlib;
+use crate::vm::MEMORY_SIZE;
 use std::collections::HashMap;
+use std::fmt;
 use std::vec::Vec;
 
-use pest::iterators::Pairs;
+use pest::iterators::{Pair, Pairs};
 use pest::Parser;
 use pest_derive::Parser;
 
@@ -59,34 +67,123 @@ fn add_constant(
     }
 }
 
-#[derive(Debug)]
+// A whole number of words, at least one
+fn word_count(token: &str, constants: &HashMap<String, f64>) -> Result<usize, OpErr> {
+    let count = parse_immediate(token, 0, false, constants)?;
+    if count < 1.0 || count.fract() != 0.0 {
+        return Err(OpErr::InvalidReserve(format!(
+            "{} isn't a whole number of words",
+            token
+        )));
+    }
+    if count > MEMORY_SIZE as f64 {
+        return Err(OpErr::InvalidReserve(format!(
+            "{} is more words than there is memory",
+            token
+        )));
+    }
+    Ok(count as usize)
+}
+
+// .reserve NAME, SIZE [, ALIGN | at=ADDR], defining NAME and NAME_END
+fn reserve(
+    regions: &mut Regions,
+    constants: &mut HashMap<String, f64>,
+    name: &str,
+    args: &[&str],
+) -> Result<(), OpErr> {
+    let end = format!("{}_END", name);
+    if constants.contains_key(name) || constants.contains_key(&end) {
+        return Err(OpErr::InvalidReserve(format!(
+            "{} is already defined",
+            name
+        )));
+    }
+    let (size, placement) = match args {
+        [size] => (word_count(size, constants)?, None),
+        [size, placement] => (word_count(size, constants)?, Some(*placement)),
+        _ => return Err(OpErr::InvalidArgumentCount(args.len() + 1, 3)),
+    };
+    let start = match placement.map(|arg| arg.strip_prefix("at=").ok_or(arg)) {
+        None => regions.reserve(name, size, 1)?,
+        Some(Err(align)) => regions.reserve(name, size, word_count(align, constants)?)?,
+        Some(Ok(addr)) => {
+            let addr = parse_immediate(addr, 0, false, constants)?;
+            if addr < 0.0 || addr >= MEMORY_SIZE as f64 || addr.fract() != 0.0 {
+                return Err(OpErr::InvalidReserve(format!("{} isn't an address", addr)));
+            }
+            regions.place(name, addr as usize, size)?;
+            addr as usize
+        }
+    };
+    constants.insert(name.to_string(), start as f64);
+    constants.insert(end, (start + size) as f64);
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
 pub enum ParseErr {
     Generic(String),
     Line(usize, String, String),
 }
 
-impl ToString for ParseErr {
-    fn to_string(&self) -> String {
+impl fmt::Display for ParseErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            ParseErr::Generic(s) => format!("Parse error: {}", s),
+            ParseErr::Generic(s) => write!(f, "Parse error: {}", s),
             ParseErr::Line(pos, line, msg) => {
-                format!("Parse error on line {} [\"{}\"]: {}", pos + 1, line, msg)
+                write!(f, "Parse error on line {} [\"{}\"]: {}", pos + 1, line, msg)
             }
         }
     }
 }
 
+// A directive's name and arguments, with an .if or .while condition split
+// into its operands and comparison
+fn directive_tokens(line: Pair<'_, Rule>) -> Vec<&str> {
+    line.into_inner()
+        .flat_map(|pair| match pair.as_rule() {
+            Rule::condition => pair.into_inner().map(|part| part.as_str()).collect(),
+            _ => vec![pair.as_str()],
+        })
+        .collect()
+}
+
+// Labels inside a .func are local to it, and named FUNC.LABEL
 fn find_labels(lines: Pairs<Rule>) -> HashMap<String, f64> {
     let mut labels: HashMap<String, f64> = HashMap::new();
-    let mut pc: u32 = 0;
+    let mut pc: usize = 0;
+    // name of the function we're in, and how many registers it saves
+    let mut func: Option<(&str, usize)> = None;
     for line in lines.clone() {
         match line.as_rule() {
             Rule::label => {
                 let label = line.into_inner().next().unwrap().as_str();
-                labels.insert(label.to_string(), pc as f64);
+                let label = match func {
+                    Some((name, _)) => format!("{}.{}", name, label),
+                    None => label.to_string(),
+                };
+                labels.insert(label, pc as f64);
+            }
+            Rule::directive => {
+                let tokens = directive_tokens(line);
+                match tokens.as_slice() {
+                    ["func", name, args @ ..] => {
+                        let saves = saved_registers(args).map_or(0, |saves| saves.len());
+                        labels.insert(name.to_string(), pc as f64);
+                        pc += frame_ops(saves);
+                        func = Some((name, saves));
+                    }
+                    ["endfunc"] => func = None,
+                    [name, args @ ..] => pc += control::op_count(name, args).unwrap_or(0),
+                    _ => {}
+                }
             }
             Rule::op => {
-                pc += 1;
+                pc += match (line.as_str().trim().to_lowercase() == "ret", func) {
+                    (true, Some((_, saves))) => frame_ops(saves) + 1,
+                    _ => 1,
+                };
             }
             _ => {}
         }
@@ -94,57 +191,787 @@ fn find_labels(lines: Pairs<Rule>) -> HashMap<String, f64> {
     labels
 }
 
+// A .func block, from its prologue to the op after its .endfunc
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    pub start: usize,
+    // exclusive
+    pub end: usize,
+    // callee-saved registers, in stack order
+    pub saves: Vec<u8>,
+    // what each virtual register (%tmp) ended up as
+    pub registers: Vec<(String, u8)>,
+}
+
+// A .func that's still being assembled
+struct OpenFunction {
+    function: Function,
+    // at the .func line, for when there's no .endfunc
+    unclosed: ParseErr,
+    virtuals: Vec<String>,
+    // at the line that first used each virtual register
+    first_uses: Vec<ParseErr>,
+    uses: Vec<VirtualUse>,
+}
+
+// Register names in a .func's "saves=x5..x9, ra" arguments, with ranges
+// spelled out
+fn saved_registers(args: &[&str]) -> Result<Vec<String>, OpErr> {
+    let Some((first, rest)) = args.split_first() else {
+        return Ok(Vec::new());
+    };
+    let Some(first) = first.strip_prefix("saves=") else {
+        return Err(OpErr::InvalidFunction(format!(
+            "expected saves=REGISTERS, got {}",
+            first
+        )));
+    };
+    let mut names: Vec<String> = Vec::new();
+    for arg in std::iter::once(first).chain(rest.iter().copied()) {
+        let Some((from, to)) = arg.split_once("..") else {
+            names.push(arg.to_string());
+            continue;
+        };
+        // x5..x9, t0..t4: same prefix, then numbers
+        let split = |reg: &str| {
+            let digits = reg.len() - reg.trim_start_matches(|c: char| !c.is_ascii_digit()).len();
+            let (prefix, number) = reg.split_at(digits);
+            Some((prefix.to_string(), number.parse::<usize>().ok()?))
+        };
+        match (split(from), split(to)) {
+            (Some((prefix, from)), Some((other, to))) if prefix == other && from <= to => {
+                names.extend((from..=to).map(|n| format!("{}{}", prefix, n)))
+            }
+            _ => return Err(OpErr::InvalidRegister(arg.to_string())),
+        }
+    }
+    Ok(names)
+}
+
+// Ops a prologue takes to save n registers, and an epilogue to restore
+// them (before its jalr)
+fn frame_ops(saves: usize) -> usize {
+    match saves {
+        0 => 0,
+        n => n + 1,
+    }
+}
+
+fn frame_op(tokens: &[String]) -> Op {
+    let tokens: Vec<&str> = tokens.iter().map(|t| t.as_str()).collect();
+    parse_op(&tokens, 0, &HashMap::new(), &HashMap::new()).unwrap()
+}
+
+// Makes room on the stack and stores the saved registers there
+fn prologue(saves: &[u8]) -> Vec<Op> {
+    let mut ops: Vec<Op> = Vec::new();
+    if !saves.is_empty() {
+        ops.push(frame_op(&[
+            "subi".into(),
+            "2".into(),
+            "2".into(),
+            saves.len().to_string(),
+        ]));
+    }
+    for (slot, reg) in saves.iter().enumerate() {
+        ops.push(frame_op(&[
+            "store".into(),
+            reg.to_string(),
+            "2".into(),
+            slot.to_string(),
+        ]));
+    }
+    ops
+}
+
+// What ret expands to: restore the saved registers, pop them and return
+fn epilogue(saves: &[u8]) -> Vec<Op> {
+    let mut ops: Vec<Op> = Vec::new();
+    for (slot, reg) in saves.iter().enumerate() {
+        ops.push(frame_op(&[
+            "load".into(),
+            reg.to_string(),
+            "2".into(),
+            slot.to_string(),
+        ]));
+    }
+    if !saves.is_empty() {
+        ops.push(frame_op(&[
+            "addi".into(),
+            "2".into(),
+            "2".into(),
+            saves.len().to_string(),
+        ]));
+    }
+    ops.push(frame_op(&[
+        "jalr".into(),
+        "0".into(),
+        "1".into(),
+        "0".into(),
+    ]));
+    ops
+}
+
+// Symbols named in .extern directives, which ops can refer to before the
+// directive
+fn find_externs(lines: Pairs<Rule>) -> Vec<String> {
+    let mut externs: Vec<String> = Vec::new();
+    for line in lines.filter(|line| line.as_rule() == Rule::directive) {
+        let mut inner = line.into_inner();
+        if inner.next().unwrap().as_str() == "extern" {
+            externs.extend(inner.map(|arg| arg.as_str().to_string()));
+        }
+    }
+    externs
+}
+
+// The token an op's immediate was written as
+fn immediate_token<'a>(op: &Op, tokens: &[&'a str]) -> Option<&'a str> {
+    let info = op_info(op.op.opcode)?;
+    let idx = info.args().iter().position(|arg| *arg == OpArg::Im)?;
+    tokens.get(idx + 1).copied()
+}
+
+// Whether an op's immediate was written as a label and holds its absolute pc
+fn refers_to_label(op: &Op, tokens: &[&str], labels: &HashMap<String, f64>) -> bool {
+    let rel = op_info(op.op.opcode).is_some_and(|info| info.rel());
+    !rel && immediate_token(op, tokens).is_some_and(|token| labels.contains_key(token))
+}
+
+// .name arg, arg... lines that tell the assembler something about the
+// program rather than producing ops
+fn add_directive(
+    program: &mut Program,
+    labels: &HashMap<String, f64>,
+    name: &str,
+    args: &[&str],
+) -> Result<(), OpErr> {
+    match name {
+        // labels that have to survive dead code elimination, for code only
+        // reached through jalr
+        "keep" if !args.is_empty() => {
+            for label in args {
+                if !labels.contains_key(*label) {
+                    return Err(OpErr::UnknownLabel(label.to_string()));
+                }
+                program.keep.push(label.to_string());
+            }
+            Ok(())
+        }
+        // labels other modules can refer to
+        "global" if !args.is_empty() => {
+            for label in args {
+                if !labels.contains_key(*label) {
+                    return Err(OpErr::UnknownLabel(label.to_string()));
+                }
+                program.globals.push(label.to_string());
+            }
+            Ok(())
+        }
+        // labels from other modules, filled in by the linker
+        "extern" if !args.is_empty() => {
+            for symbol in args {
+                if labels.contains_key(*symbol) {
+                    return Err(OpErr::ExternDefined(symbol.to_string()));
+                }
+                program.externs.push(symbol.to_string());
+            }
+            Ok(())
+        }
+        // already expanded by expand_includes
+        "include" => Ok(()),
+        "keep" | "global" | "extern" => Err(OpErr::InvalidArgumentCount(0, 1)),
+        _ => Err(OpErr::InvalidDirective(name.to_string())),
+    }
+}
+
+// Line and column (1-based, like pest's line_col) of positions in a text.
+// pest scans from the start of the text for every lookup, which makes
+// assembling quadratic, so the line starts are found once up front.
+struct LineIndex<'a> {
+    text: &'a str,
+    starts: Vec<usize>,
+}
+
+impl LineIndex<'_> {
+    fn new(text: &str) -> LineIndex<'_> {
+        let starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(at, _)| at + 1))
+            .collect();
+        LineIndex { text, starts }
+    }
+
+    fn line_col(&self, pos: usize) -> (usize, usize) {
+        let line = self.starts.partition_point(|start| *start <= pos);
+        let start = self.starts[line - 1];
+        (line, self.text[start..pos].chars().count() + 1)
+    }
+}
+
+// A line of source once .include directives are expanded, and where it
+// came from
+struct SourceLine<'a> {
+    text: &'a str,
+    file: usize,
+    line: usize,
+    // (file, line) of the .include directives that led here, outermost first
+    stack: Vec<(usize, usize)>,
+}
+
+impl SourceLine<'_> {
+    // line in the file being assembled, which for included lines is the
+    // outermost .include
+    fn main_line(&self) -> usize {
+        self.stack.first().map_or(self.line, |(_, line)| *line)
+    }
+}
+
+// Appends the lines of a file to out, with every .include <std/...>
+// followed by the lines of the file it names. Each file is only included
+// once, however many times it's asked for.
+fn expand_includes<'a>(
+    src: &'a str,
+    file: usize,
+    stack: &[(usize, usize)],
+    files: &mut Vec<String>,
+    out: &mut Vec<SourceLine<'a>>,
+) -> Result<(), ParseErr> {
+    let padded = src.to_owned() + "\n";
+    let lines = AsmParser::parse(Rule::program, &padded).map_err(|e| match file {
+        0 => ParseErr::Generic(e.to_string()),
+        _ => ParseErr::Generic(format!("{}: {}", files[file], e)),
+    })?;
+    let index = LineIndex::new(&padded);
+    let mut includes: HashMap<usize, Vec<&str>> = HashMap::new();
+    for line in lines.filter(|line| line.as_rule() == Rule::directive) {
+        let linepos = index.line_col(line.as_span().start()).0 - 1;
+        let tokens = directive_tokens(line);
+        if tokens[0] == "include" {
+            includes.insert(linepos, tokens[1..].to_vec());
+        }
+    }
+
+    for (linepos, text) in src.lines().enumerate() {
+        let line = SourceLine {
+            text,
+            file,
+            line: linepos,
+            stack: stack.to_vec(),
+        };
+        let Some(args) = includes.get(&linepos) else {
+            out.push(line);
+            continue;
+        };
+        let name = match args.as_slice() {
+            [name] => name.strip_prefix('<').and_then(|n| n.strip_suffix('>')),
+            _ => None,
+        };
+        let Some((name, included)) = name.and_then(|n| Some((n, stdlib::file(n)?))) else {
+            let msg = format!(
+                "Can only include the standard library ({})",
+                stdlib::names().join(", ")
+            );
+            return Err(ParseErr::Line(
+                line.main_line(),
+                text.trim().to_string(),
+                msg,
+            ));
+        };
+        out.push(line);
+        if files.iter().any(|f| f == name) {
+            continue;
+        }
+        files.push(name.to_string());
+        let mut inner = stack.to_vec();
+        inner.push((file, linepos));
+        expand_includes(included, files.len() - 1, &inner, files, out)?;
+    }
+    Ok(())
+}
+
 fn parse_err(operr: OpErr, linepos: usize, line: &str) -> ParseErr {
     ParseErr::Line(linepos, line.to_string(), operr.to_string())
 }
 
+// Where an op came from, all 0-based
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SourceLocation {
+    // index into Program::files
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+    // (file, line) of the includes/expansions that led here, outermost first
+    pub stack: Vec<(usize, usize)>,
+}
+
+// Everything the assembler knows about a program, beyond the ops themselves
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub ops: Vec<Op>,
+    // source line (0-based) each op was assembled from. Included code gets
+    // the line of the .include, and linked modules count on from the end of
+    // the one before, so these never go down.
+    pub lines: Vec<usize>,
+    // source file names, the first being the one assembled
+    pub files: Vec<String>,
+    pub locations: Vec<SourceLocation>,
+    pub labels: HashMap<String, f64>,
+    // ops whose immediate is a label's absolute pc (li x5, LOOP), which
+    // need fixing up whenever ops move
+    pub label_refs: Vec<usize>,
+    // labels named in .keep directives
+    pub keep: Vec<String>,
+    // labels exported with .global, and symbols imported with .extern
+    pub globals: Vec<String>,
+    pub externs: Vec<String>,
+    // ops whose immediate refers to an extern symbol, and which one. Until
+    // the program is linked the immediate is NaN.
+    pub extern_refs: Vec<(usize, String)>,
+    pub functions: Vec<Function>,
+    // memory claimed with .reserve
+    pub regions: Regions,
+    // final constant and alias tables, including builtins
+    pub constants: HashMap<String, f64>,
+    pub aliases: HashMap<String, u8>,
+}
+
+impl Program {
+    // pc of the first op on or after a source line
+    pub fn pc_at_line(&self, line: usize) -> usize {
+        self.lines.partition_point(|l| *l < line)
+    }
+
+    // Constants defined by the program itself, without labels or $ names
+    pub fn user_constants(&self) -> Vec<(&str, f64)> {
+        let mut constants: Vec<(&str, f64)> = self
+            .constants
+            .iter()
+            .filter(|(name, _)| !name.starts_with('$') && !self.labels.contains_key(*name))
+            .map(|(name, value)| (name.as_str(), *value))
+            .collect();
+        constants.sort_by(|a, b| a.0.cmp(b.0));
+        constants
+    }
+
+    // Register aliases defined by the program itself, by register
+    pub fn user_aliases(&self) -> Vec<(&str, u8)> {
+        let defaults = default_aliases();
+        let mut aliases: Vec<(&str, u8)> = self
+            .aliases
+            .iter()
+            .filter(|(name, reg)| defaults.get(*name) != Some(*reg))
+            .map(|(name, reg)| (name.as_str(), *reg))
+            .collect();
+        aliases.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(b.0)));
+        aliases
+    }
+}
+
 pub fn parse(src: &str) -> Result<Vec<Op>, ParseErr> {
+    assemble(src).map(|program| program.ops)
+}
+
+pub fn assemble(src: &str) -> Result<Program, ParseErr> {
+    assemble_file(src, "")
+}
+
+// Like assemble, with the file name to record in source locations
+pub fn assemble_file(src: &str, filename: &str) -> Result<Program, ParseErr> {
+    let mut files = vec![filename.to_string()];
+    let mut source: Vec<SourceLine> = Vec::new();
+    expand_includes(src, 0, &[], &mut files, &mut source)?;
     // the grammar requires a newline at the end so just always give it one
-    let src = src.to_owned() + "\n";
+    let texts: Vec<&str> = source.iter().map(|line| line.text).collect();
+    let src = texts.join("\n") + "\n";
     let lines =
         AsmParser::parse(Rule::program, &src).map_err(|e| ParseErr::Generic(e.to_string()))?;
 
     // an op can refer to a label that's defined further in the program,
     // so need to do a prepass to find label locations
-    let mut constants = find_labels(lines.clone());
+    let labels = find_labels(lines.clone());
+    let mut constants = labels.clone();
     add_memmap_constants(&mut constants);
+    let externs = find_externs(lines.clone());
+    for symbol in externs.iter() {
+        constants.entry(symbol.clone()).or_insert(f64::NAN);
+    }
     let mut aliases = default_aliases();
     let mut ops: Vec<Op> = Vec::new();
+    let mut oplines: Vec<usize> = Vec::new();
+    let mut locations: Vec<SourceLocation> = Vec::new();
+    let mut label_refs: Vec<usize> = Vec::new();
+    let mut program = Program::default();
+    let mut func: Option<OpenFunction> = None;
+    // waiting for their virtual registers until every function is known
+    let mut closed: Vec<OpenFunction> = Vec::new();
+    let mut control = Control::default();
+    // the .struct we're in, and the line it started on for errors
+    let mut structure: Option<(Layout, ParseErr)> = None;
+    // at the line that opened each block, for when it isn't closed
+    let mut blocks: Vec<ParseErr> = Vec::new();
+    // where each label was defined, to catch a second definition
+    let mut defined: HashMap<String, String> = HashMap::new();
 
-    for (linepos, line) in lines.enumerate() {
+    let index = LineIndex::new(&src);
+    for line in lines {
         let linestr = line.clone().as_str();
+        let (linepos, colpos) = index.line_col(line.as_span().start());
+        // only the empty line the newline above adds can be past the end
+        let Some(origin) = source.get(linepos - 1) else {
+            continue;
+        };
+        let (linepos, colpos) = (origin.main_line(), colpos - 1);
+        let files = &files;
+        let err = |e: OpErr| match origin.stack.is_empty() {
+            true => parse_err(e, linepos, linestr),
+            false => {
+                let at = format!("{}:{}", files[origin.file], origin.line + 1);
+                ParseErr::Line(linepos, linestr.to_string(), format!("{}: {}", at, e))
+            }
+        };
+        let mut define = |label: String| match defined.get(&label) {
+            Some(first) => Err(err(OpErr::DuplicateLabel(label, first.clone()))),
+            None => {
+                let here = match origin.stack.is_empty() {
+                    true => format!("line {}", linepos + 1),
+                    false => format!("{}:{}", files[origin.file], origin.line + 1),
+                };
+                defined.insert(label, here);
+                Ok(())
+            }
+        };
 
         let pc = ops.len() as u32;
-        match line.as_rule() {
+        // ops still to be assembled from their tokens
+        let mut lowered: Vec<Vec<String>> = Vec::new();
+        let emitted: Vec<Op> = match line.as_rule() {
+            Rule::label | Rule::op if structure.is_some() => {
+                let msg = "only .field can go inside a .struct".to_string();
+                return Err(err(OpErr::InvalidStruct(msg)));
+            }
+            Rule::label => {
+                let label = line.into_inner().next().unwrap().as_str();
+                match &func {
+                    Some(open) => define(format!("{}.{}", open.function.name, label))?,
+                    None => define(label.to_string())?,
+                }
+                Vec::new()
+            }
             Rule::alias => {
                 let mut inner = line.into_inner();
                 let name = inner.next().unwrap().as_str();
                 let value = inner.next().unwrap().as_str();
-                add_alias(&mut aliases, name, value).map_err(|e| parse_err(e, linepos, linestr))?
+                add_alias(&mut aliases, name, value).map_err(err)?;
+                Vec::new()
             }
             Rule::constant => {
                 let mut inner = line.into_inner();
                 let name = inner.next().unwrap().as_str();
                 let value = inner.next().unwrap().as_str();
-                add_constant(&mut constants, name, value)
-                    .map_err(|e| parse_err(e, linepos, linestr))?
+                add_constant(&mut constants, name, value).map_err(err)?;
+                Vec::new()
+            }
+            Rule::directive => {
+                let tokens = directive_tokens(line);
+                match tokens.as_slice() {
+                    [name, ..]
+                        if structure.is_some() && !matches!(*name, "field" | "endstruct") =>
+                    {
+                        let msg = "only .field can go inside a .struct".to_string();
+                        return Err(err(OpErr::InvalidStruct(msg)));
+                    }
+                    ["struct", name] => {
+                        if structure.is_some() {
+                            let msg = ".struct can't be inside another .struct".to_string();
+                            return Err(err(OpErr::InvalidStruct(msg)));
+                        }
+                        if constants.contains_key(&format!("{}.SIZE", name)) {
+                            let msg = format!("{} is already defined", name);
+                            return Err(err(OpErr::InvalidStruct(msg)));
+                        }
+                        let unclosed = OpErr::InvalidStruct(format!("{} has no .endstruct", name));
+                        structure = Some((Layout::new(name), err(unclosed)));
+                        Vec::new()
+                    }
+                    ["field", name, size @ ..] => {
+                        let Some((layout, _)) = structure.as_mut() else {
+                            let msg = ".field outside of a .struct".to_string();
+                            return Err(err(OpErr::InvalidStruct(msg)));
+                        };
+                        let size = match size {
+                            [] => 1.0,
+                            [size] => parse_immediate(size, pc, false, &constants).map_err(err)?,
+                            _ => return Err(err(OpErr::InvalidArgumentCount(size.len() + 1, 2))),
+                        };
+                        if size < 1.0 || size.fract() != 0.0 {
+                            let msg = format!("{} has to be a whole number of words", name);
+                            return Err(err(OpErr::InvalidStruct(msg)));
+                        }
+                        layout.add_field(name, size as usize).map_err(err)?;
+                        Vec::new()
+                    }
+                    ["endstruct"] => match structure.take() {
+                        Some((layout, _)) => {
+                            constants.extend(layout.constants());
+                            Vec::new()
+                        }
+                        None => {
+                            let msg = ".endstruct without a .struct".to_string();
+                            return Err(err(OpErr::InvalidStruct(msg)));
+                        }
+                    },
+                    [kind @ ("enum" | "flags"), name, body @ ..] if !name.contains(['{', '}']) => {
+                        if constants.contains_key(&format!("{}.COUNT", name)) {
+                            let msg = format!("{} is already defined", name);
+                            return Err(err(OpErr::InvalidEnum(msg)));
+                        }
+                        let flags = *kind == "flags";
+                        let members = enums::members(name, &body.join(" "), flags, &constants)
+                            .map_err(err)?;
+                        constants.extend(members);
+                        Vec::new()
+                    }
+                    ["reserve", name, args @ ..] => {
+                        reserve(&mut program.regions, &mut constants, name, args).map_err(err)?;
+                        Vec::new()
+                    }
+                    ["func", name, args @ ..] => {
+                        if func.is_some() {
+                            let msg = ".func can't be inside another .func".to_string();
+                            return Err(err(OpErr::InvalidFunction(msg)));
+                        }
+                        if control.depth() > 0 {
+                            let msg = ".func can't be inside a block".to_string();
+                            return Err(err(OpErr::InvalidBlock(msg)));
+                        }
+                        define(name.to_string())?;
+                        let mut saves: Vec<u8> = Vec::new();
+                        for reg in saved_registers(args).map_err(err)? {
+                            match parse_register(&reg, &aliases).map_err(err)? {
+                                0 | 2 => return Err(err(OpErr::InvalidRegister(reg))),
+                                reg => saves.push(reg),
+                            }
+                        }
+                        let function = Function {
+                            name: name.to_string(),
+                            start: pc as usize,
+                            end: pc as usize,
+                            saves,
+                            registers: Vec::new(),
+                        };
+                        let unclosed = OpErr::InvalidFunction(format!("{} has no .endfunc", name));
+                        let ops = prologue(&function.saves);
+                        func = Some(OpenFunction {
+                            function,
+                            unclosed: err(unclosed),
+                            virtuals: Vec::new(),
+                            first_uses: Vec::new(),
+                            uses: Vec::new(),
+                        });
+                        ops
+                    }
+                    ["func"] => return Err(err(OpErr::InvalidArgumentCount(0, 1))),
+                    ["endfunc"] if control.depth() > 0 => {
+                        return Err(blocks.pop().unwrap());
+                    }
+                    ["endfunc"] => match func.take() {
+                        Some(mut open) => {
+                            open.function.end = pc as usize;
+                            closed.push(open);
+                            Vec::new()
+                        }
+                        None => {
+                            let msg = ".endfunc without a .func".to_string();
+                            return Err(err(OpErr::InvalidFunction(msg)));
+                        }
+                    },
+                    [name, args @ ..] if control::op_count(name, args).is_some() => {
+                        let depth = control.depth();
+                        lowered = control
+                            .lower(name, args, pc as usize, &mut ops)
+                            .map_err(err)?;
+                        if control.depth() > depth {
+                            let msg = format!(".{} has no .end{}", name, name);
+                            blocks.push(err(OpErr::InvalidBlock(msg)));
+                        } else if control.depth() < depth {
+                            blocks.pop();
+                        }
+                        Vec::new()
+                    }
+                    _ => {
+                        add_directive(&mut program, &labels, tokens[0], &tokens[1..])
+                            .map_err(err)?;
+                        Vec::new()
+                    }
+                }
             }
+            Rule::op if line.as_str().trim().to_lowercase() == "ret" => match &func {
+                Some(open) => epilogue(&open.function.saves),
+                None => {
+                    let msg = "ret outside of a .func".to_string();
+                    return Err(err(OpErr::InvalidFunction(msg)));
+                }
+            },
             Rule::op => {
-                let tokens: Vec<&str> = line.into_inner().map(|pair| pair.as_str()).collect();
-                let op = parse_op(&tokens, pc, &constants, &aliases)
-                    .map_err(|e| parse_err(e, linepos, linestr))?;
-                //println!("op: {:?}", op);
-                ops.push(op);
+                lowered.push(
+                    line.into_inner()
+                        .map(|pair| pair.as_str().to_string())
+                        .collect(),
+                );
+                Vec::new()
             }
-            _ => {}
+            _ => Vec::new(),
+        };
+        let mut emitted = emitted;
+        for (offset, tokens) in lowered.into_iter().enumerate() {
+            let pc = pc + offset as u32;
+            // labels local to the function win over global ones
+            let local = |token: &str| match &func {
+                Some(open) => Some(format!("{}.{}", open.function.name, token))
+                    .filter(|label| labels.contains_key(label)),
+                None => None,
+            };
+            let local: Vec<String> = tokens
+                .into_iter()
+                .enumerate()
+                .map(|(idx, token)| match idx {
+                    0 => token,
+                    _ => local(&token).unwrap_or(token),
+                })
+                .collect();
+            let mut tokens: Vec<&str> = local.iter().map(|token| token.as_str()).collect();
+            // virtual registers get a physical one once every function is known
+            let virtuals: Vec<(usize, &str)> = tokens
+                .iter()
+                .enumerate()
+                .skip(1)
+                .filter(|(_, token)| token.starts_with('%'))
+                .map(|(idx, token)| (idx, *token))
+                .collect();
+            for (idx, _) in virtuals.iter() {
+                tokens[*idx] = "0";
+            }
+            let op = parse_op(&tokens, pc, &constants, &aliases).map_err(err)?;
+            if let Some((_, token)) = virtuals.first() {
+                let Some(open) = func.as_mut() else {
+                    let msg = format!("virtual register {} outside of a .func", token);
+                    return Err(err(OpErr::InvalidFunction(msg)));
+                };
+                let args = op_info(op.op.opcode).unwrap().args();
+                for (idx, token) in virtuals {
+                    let arg = args[idx - 1];
+                    if !matches!(arg, OpArg::Rd | OpArg::Rs1 | OpArg::Rs2) {
+                        return Err(err(OpErr::InvalidImmediate(token.to_string())));
+                    }
+                    let reg = match open.virtuals.iter().position(|name| name == token) {
+                        Some(reg) => reg,
+                        None => {
+                            let msg =
+                                format!("no register left for {} in {}", token, open.function.name);
+                            open.virtuals.push(token.to_string());
+                            open.first_uses.push(err(OpErr::InvalidFunction(msg)));
+                            open.virtuals.len() - 1
+                        }
+                    };
+                    open.uses.push(VirtualUse {
+                        pc: pc as usize,
+                        arg,
+                        reg,
+                    });
+                }
+            }
+            if refers_to_label(&op, &tokens, &labels) {
+                label_refs.push(pc as usize);
+            }
+            match immediate_token(&op, &tokens) {
+                Some(token) if externs.iter().any(|symbol| symbol == token) => {
+                    program.extern_refs.push((pc as usize, token.to_string()))
+                }
+                _ => {}
+            }
+            emitted.push(op);
+        }
+        for op in emitted {
+            ops.push(op);
+            oplines.push(linepos);
+            locations.push(SourceLocation {
+                file: origin.file,
+                line: origin.line,
+                column: colpos,
+                stack: origin.stack.clone(),
+            });
         }
     }
+    if let Some(open) = func {
+        return Err(open.unclosed);
+    }
+    if let Some(unclosed) = blocks.pop() {
+        return Err(unclosed);
+    }
+    if let Some((_, unclosed)) = structure {
+        return Err(unclosed);
+    }
+    let virtuals: Vec<VirtualFunction> = closed
+        .iter()
+        .map(|open| VirtualFunction {
+            start: open.function.start,
+            end: open.function.end,
+            count: open.virtuals.len(),
+            uses: &open.uses,
+        })
+        .collect();
+    let registers = regalloc::allocate_functions(&mut ops, &virtuals)
+        .map_err(|(idx, reg)| closed[idx].first_uses[reg].clone())?;
+    for (open, registers) in closed.into_iter().zip(registers) {
+        let mut function = open.function;
+        function.registers = open.virtuals.into_iter().zip(registers).collect();
+        program.functions.push(function);
+    }
 
-    Ok(ops)
+    constants.retain(|name, value| !(value.is_nan() && externs.contains(name)));
+    Ok(Program {
+        ops,
+        lines: oplines,
+        files,
+        locations,
+        labels,
+        label_refs,
+        constants,
+        aliases,
+        ..program
+    })
 }
 
-pub fn print_ops(ops: &[Op]) {
-    for (pc, op) in ops.iter().enumerate() {
-        println!("{}: {:?}", pc, op);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops::string_literal_to_immediate;
+
+    #[test]
+    fn test_comments() {
+        // a comment ends a value even without a space before it, and can
+        // follow an op that takes no arguments
+        let program = assemble("const A = 1// one\nnop // nothing\nli x5, A//\n").unwrap();
+        assert_eq!(program.constants["A"], 1.0);
+        assert_eq!(program.ops.len(), 2);
+        assert_eq!(program.ops[1].imm, 1.0);
+        // inside a string it's just text
+        let program = assemble("li x5, \"//\"").unwrap();
+        assert_eq!(
+            program.ops[0].imm,
+            string_literal_to_immediate("\"//\"").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_line_index() {
+        // same answers as pest, which counts characters rather than bytes
+        let text = "nop\n\n  li x5, \"é\" // ü\nret";
+        let index = LineIndex::new(text);
+        for (pos, _) in text.char_indices() {
+            let expected = pest::Position::new(text, pos).unwrap().line_col();
+            assert_eq!(index.line_col(pos), expected, "{}", pos);
+        }
     }
 }
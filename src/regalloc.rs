@@ -0,0 +1,390 @@
+use crate::analysis::cfg::{flow, Flow};
+use crate::analysis::liveness::{reads, writes, RegSet};
+use crate::analysis::{strongly_connected, OpGraph};
+use crate::ops::{op_info, Op, OpArg};
+use std::collections::{BTreeSet, HashMap};
+use std::vec::Vec;
+
+// Register allocation for the virtual registers (%tmp, %acc, ...) of a
+// .func. Virtual registers are live from a write to the last read that can
+// see it, following branches and jumps inside the function; calls are taken
+// to return to the op after them. Two virtual registers that are live at
+// the same time get different physical ones. Candidates are x5 and up,
+// handed out from x255 down, leaving out anything the function names itself.
+//
+// Nothing is saved around calls, so a virtual register live across a call
+// also stays clear of every register the call can overwrite: whatever the
+// ops it can reach write, and the registers given to functions among them.
+// Functions are allocated once all of them are known, callees first. A call
+// that can come back into a function not allocated yet, or that goes
+// somewhere unknown, can overwrite anything.
+
+// zero, ra, sp, gp and tp
+pub const RESERVED: std::ops::RangeInclusive<u8> = 0..=4;
+
+// A virtual register in one of an op's register arguments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualUse {
+    pub pc: usize,
+    pub arg: OpArg,
+    // index of the virtual register
+    pub reg: usize,
+}
+
+// A .func's ops, ops[start..end], and its virtual registers 0..count
+pub struct VirtualFunction<'a> {
+    pub start: usize,
+    pub end: usize,
+    pub count: usize,
+    pub uses: &'a [VirtualUse],
+}
+
+// Physical registers for virtual registers 0..count, used in ops[start..end],
+// given the registers each call there can overwrite. Fails with the first
+// virtual register there's no register left for.
+pub fn allocate(
+    ops: &[Op],
+    start: usize,
+    end: usize,
+    count: usize,
+    uses: &[VirtualUse],
+    calls: &HashMap<usize, RegSet>,
+) -> Result<Vec<u8>, usize> {
+    let len = end - start;
+    let mut used: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); len];
+    let mut defined: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); len];
+    for u in uses.iter() {
+        // stand-in registers tell which role the argument plays
+        let mut marked = ops[u.pc].clone();
+        marked.op.rd = 1;
+        marked.op.rs1 = 2;
+        marked.op.rs2 = 3;
+        let marker = match u.arg {
+            OpArg::Rd => 1,
+            OpArg::Rs1 => 2,
+            _ => 3,
+        };
+        if reads(&marked).contains(marker) {
+            used[u.pc - start].insert(u.reg);
+        }
+        if writes(&marked).contains(marker) {
+            defined[u.pc - start].insert(u.reg);
+        }
+    }
+
+    // registers the function picks itself are off limits
+    let mut explicit: BTreeSet<u8> = RESERVED.collect();
+    for (pc, op) in ops.iter().enumerate().take(end).skip(start) {
+        let Some(info) = op_info(op.op.opcode) else {
+            continue;
+        };
+        for arg in info.args() {
+            let reg = match arg {
+                OpArg::Rd => op.op.rd,
+                OpArg::Rs1 => op.op.rs1,
+                OpArg::Rs2 => op.op.rs2,
+                _ => continue,
+            };
+            if !uses.iter().any(|u| u.pc == pc && u.arg == *arg) {
+                explicit.insert(reg);
+            }
+        }
+    }
+
+    let successors: Vec<Vec<usize>> = (start..end)
+        .map(|pc| {
+            let next = match flow(ops, pc) {
+                Flow::Next | Flow::AddressOf(_) | Flow::Spawn(_) | Flow::Call(_) => vec![pc + 1],
+                Flow::Branch(target) => [Some(pc + 1), target].into_iter().flatten().collect(),
+                Flow::Jump(target) => target.into_iter().collect(),
+                Flow::Indirect => Vec::new(),
+            };
+            next.into_iter()
+                .filter(|next| (start..end).contains(next))
+                .map(|next| next - start)
+                .collect()
+        })
+        .collect();
+
+    let mut live_in: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); len];
+    let mut live_out: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); len];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for idx in (0..len).rev() {
+            let out: BTreeSet<usize> = successors[idx]
+                .iter()
+                .flat_map(|next| live_in[*next].iter().copied())
+                .collect();
+            let mut live: BTreeSet<usize> = out.difference(&defined[idx]).copied().collect();
+            live.extend(used[idx].iter().copied());
+            if live != live_in[idx] || out != live_out[idx] {
+                live_in[idx] = live;
+                live_out[idx] = out;
+                changed = true;
+            }
+        }
+    }
+
+    // live across a call, so out of its reach
+    let mut clobbered: Vec<RegSet> = vec![RegSet::default(); count];
+    for (pc, regs) in calls.iter() {
+        for live in live_out[*pc - start].iter() {
+            clobbered[*live] = clobbered[*live].union(regs);
+        }
+    }
+
+    let mut interferes: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); count];
+    let mut conflict = |a: usize, b: usize| {
+        if a != b {
+            interferes[a].insert(b);
+            interferes[b].insert(a);
+        }
+    };
+    for idx in 0..len {
+        for def in defined[idx].iter() {
+            for live in live_out[idx].iter() {
+                conflict(*def, *live);
+            }
+        }
+    }
+    // read before anything writes them, so they all hold something on entry
+    if let Some(entry) = live_in.first() {
+        for a in entry.iter() {
+            for b in entry.iter() {
+                conflict(*a, *b);
+            }
+        }
+    }
+
+    let mut registers: Vec<u8> = Vec::new();
+    for (reg, others) in interferes.iter().enumerate() {
+        let taken: BTreeSet<u8> = others
+            .iter()
+            .filter(|other| **other < reg)
+            .map(|other| registers[*other])
+            .collect();
+        match (0..=u8::MAX).rev().find(|phys| {
+            !explicit.contains(phys) && !taken.contains(phys) && !clobbered[reg].contains(*phys)
+        }) {
+            Some(phys) => registers.push(phys),
+            None => return Err(reg),
+        }
+    }
+    Ok(registers)
+}
+
+// Allocates every function's virtual registers and puts the physical ones
+// into the ops. Fails with the function and virtual register there's no
+// register left for.
+pub fn allocate_functions(
+    ops: &mut [Op],
+    functions: &[VirtualFunction],
+) -> Result<Vec<Vec<u8>>, (usize, usize)> {
+    let graph = OpGraph::new(ops);
+    let mut owner: Vec<Option<usize>> = vec![None; ops.len()];
+    for (idx, function) in functions.iter().enumerate() {
+        owner[function.start..function.end].fill(Some(idx));
+    }
+
+    // what can run from each op on: the registers its ops name, and the
+    // functions they're in. Virtual registers are still 0 here.
+    let succs: Vec<Vec<usize>> = (0..ops.len())
+        .map(|pc| {
+            let mut succs = graph.within[pc].clone();
+            succs.extend(graph.callees[pc]);
+            succs
+        })
+        .collect();
+    let components = strongly_connected(&succs);
+    let mut component: Vec<usize> = vec![0; ops.len()];
+    for (idx, members) in components.iter().enumerate() {
+        for pc in members.iter() {
+            component[*pc] = idx;
+        }
+    }
+    let mut reach: Vec<(RegSet, BTreeSet<usize>)> = Vec::new();
+    for (idx, members) in components.iter().enumerate() {
+        let mut regs = RegSet::default();
+        let mut owners: BTreeSet<usize> = BTreeSet::new();
+        for pc in members.iter() {
+            regs = regs.union(&writes(&ops[*pc]));
+            owners.extend(owner[*pc]);
+            for succ in succs[*pc].iter().filter(|succ| component[**succ] != idx) {
+                let (their_regs, their_owners) = &reach[component[*succ]];
+                regs = regs.union(their_regs);
+                owners.extend(their_owners.iter().copied());
+            }
+        }
+        reach.push((regs, owners));
+    }
+
+    // the functions each one's calls can reach, allocated before it
+    let calls: Vec<Vec<(usize, Option<usize>)>> = functions
+        .iter()
+        .map(|function| {
+            (function.start..function.end)
+                .filter_map(|pc| match flow(ops, pc) {
+                    Flow::Call(target) => Some((pc, target)),
+                    _ => None,
+                })
+                .collect()
+        })
+        .collect();
+    let callees: Vec<Vec<usize>> = calls
+        .iter()
+        .map(|calls| {
+            let callees: BTreeSet<usize> = calls
+                .iter()
+                .filter_map(|(_, target)| *target)
+                .flat_map(|target| reach[component[target]].1.iter().copied())
+                .collect();
+            callees.into_iter().collect()
+        })
+        .collect();
+
+    let mut registers: Vec<Option<Vec<u8>>> = vec![None; functions.len()];
+    for idx in strongly_connected(&callees).into_iter().flatten() {
+        let function = &functions[idx];
+        let overwritten: HashMap<usize, RegSet> = calls[idx]
+            .iter()
+            .map(|(pc, target)| {
+                let Some(target) = target else {
+                    return (*pc, RegSet::full());
+                };
+                let (regs, owners) = &reach[component[*target]];
+                let mut regs = *regs;
+                for owner in owners.iter() {
+                    match &registers[*owner] {
+                        Some(theirs) => regs = regs.union(&theirs.iter().copied().collect()),
+                        None => return (*pc, RegSet::full()),
+                    }
+                }
+                (*pc, regs)
+            })
+            .collect();
+        let allocated = allocate(
+            ops,
+            function.start,
+            function.end,
+            function.count,
+            function.uses,
+            &overwritten,
+        )
+        .map_err(|reg| (idx, reg))?;
+        for u in function.uses.iter() {
+            ops[u.pc].op.set_arg(&u.arg, allocated[u.reg]);
+        }
+        registers[idx] = Some(allocated);
+    }
+    Ok(registers.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::assemble;
+    use crate::vm::Vm;
+
+    #[test]
+    fn test_allocate() {
+        let src = "li x5, 10
+jal ra, TRIANGLE
+store x5, zero, 0x300
+jal zero, END
+.func TRIANGLE
+li %acc, 0
+LOOP:
+beq x5, zero, DONE
+mv %tmp, x5
+add %acc, %acc, %tmp
+subi x5, x5, 1
+jal zero, LOOP
+DONE:
+mv %tmp, %acc
+mv x5, %tmp
+ret
+.endfunc
+END:
+";
+        let program = assemble(src).unwrap();
+        let function = &program.functions[0];
+        assert_eq!(
+            function.registers,
+            vec![("%acc".to_string(), 255), ("%tmp".to_string(), 254)]
+        );
+        assert_eq!(program.ops[6].op.rd, 254);
+        assert_eq!(program.ops[7].op.rs2, 254);
+        assert_eq!(program.ops[10].op.rs1, 255);
+        assert_eq!(program.ops[11].op.rs1, 254);
+
+        let mut vm = Vm::new(program.ops.clone());
+        while !vm.is_halted() {
+            vm.run_frame().unwrap();
+        }
+        assert_eq!(vm.memory[0x300], 55.0);
+
+        // values that are never live at the same time share a register
+        let program = assemble(
+            ".func F\nli %a, 1\nstore %a, zero, 0x300\nli %b, 2\nstore %b, zero, 0x301\nret\n.endfunc",
+        )
+        .unwrap();
+        assert_eq!(program.functions[0].registers[0].1, 255);
+        assert_eq!(program.functions[0].registers[1].1, 255);
+
+        // explicitly named registers are left alone
+        let program =
+            assemble(".func F\nli x255, 1\nli %a, 2\nadd x5, %a, x255\nret\n.endfunc").unwrap();
+        assert_eq!(program.functions[0].registers[0].1, 254);
+
+        // a value needed after a call stays clear of what the callee uses,
+        // even when the callee comes later
+        let program = assemble(
+            "li sp, 0x10000
+jal ra, OUTER
+store x5, zero, 0x300
+jal zero, END
+.func OUTER, saves=ra
+li %acc, 40
+jal ra, INNER
+add x5, %acc, x5
+ret
+.endfunc
+.func INNER
+li %tmp, 2
+mv x5, %tmp
+ret
+.endfunc
+END:
+",
+        )
+        .unwrap();
+        assert_eq!(program.functions[0].registers[0].1, 254);
+        assert_eq!(program.functions[1].registers[0].1, 255);
+        let mut vm = Vm::new(program.ops.clone());
+        while !vm.is_halted() {
+            vm.run_frame().unwrap();
+        }
+        assert_eq!(vm.memory[0x300], 42.0);
+        // a call back into the same function overwrites all of them
+        let err = assemble(".func F, saves=ra\nli %n, 1\njal ra, F\nmv x5, %n\nret\n.endfunc")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("no register left for %n in F"), "{}", err);
+
+        assert!(assemble("li %tmp, 1").is_err());
+        assert!(assemble(".func F\nli x5, %tmp\nret\n.endfunc").is_err());
+
+        // 251 registers from x5 to x255, all live at once
+        let mut src = String::from(".func F\n");
+        for idx in 0..252 {
+            src += &format!("li %v{}, {}\n", idx, idx);
+        }
+        for idx in 0..252 {
+            src += &format!("store %v{}, zero, {}\n", idx, idx);
+        }
+        src += "ret\n.endfunc\n";
+        let err = assemble(&src).unwrap_err().to_string();
+        assert!(err.contains("%v251"), "{}", err);
+        assert!(err.contains("line 253"), "{}", err);
+    }
+}
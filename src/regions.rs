@@ -0,0 +1,218 @@
+use crate::memmap::IO_END;
+use crate::ops::OpErr;
+use crate::vm::MEMORY_SIZE;
+use std::fmt::Write;
+use std::vec::Vec;
+
+// Memory claimed with .reserve NAME, SIZE [, ALIGN]. Everything from the end
+// of the I/O area to the top of memory is free for programs, and each
+// reservation gets the lowest range there that fits and doesn't overlap an
+// earlier one. With at=ADDR instead of an alignment it goes exactly there,
+// and overlapping anything is an error, so fixed ones are best declared
+// first.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub start: usize,
+    pub size: usize,
+    // placed with at=
+    pub fixed: bool,
+}
+
+impl Region {
+    pub fn end(&self) -> usize {
+        self.start + self.size
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Regions {
+    // by address
+    pub regions: Vec<Region>,
+}
+
+impl Regions {
+    fn overlapping(&self, start: usize, size: usize) -> Option<&Region> {
+        self.regions
+            .iter()
+            .find(|r| start < r.end() && r.start < start + size)
+    }
+
+    fn insert(&mut self, region: Region) {
+        let at = self.regions.partition_point(|r| r.start < region.start);
+        self.regions.insert(at, region);
+    }
+
+    // A reservation made somewhere else, like another module, unless it
+    // overlaps one that's here already
+    pub fn add(&mut self, region: Region) -> Result<(), Region> {
+        if let Some(other) = self.overlapping(region.start, region.size) {
+            return Err(other.clone());
+        }
+        self.insert(region);
+        Ok(())
+    }
+
+    // The lowest free range of size words starting at a multiple of align
+    pub fn reserve(&mut self, name: &str, size: usize, align: usize) -> Result<usize, OpErr> {
+        let fits = |start: &usize| {
+            start
+                .checked_add(size)
+                .is_some_and(|end| end <= MEMORY_SIZE)
+        };
+        let mut next = IO_END.checked_next_multiple_of(align);
+        while let Some(start) = next.filter(fits) {
+            match self.overlapping(start, size) {
+                Some(region) => next = region.end().checked_next_multiple_of(align),
+                None => {
+                    self.insert(Region {
+                        name: name.to_string(),
+                        start,
+                        size,
+                        fixed: false,
+                    });
+                    return Ok(start);
+                }
+            }
+        }
+        Err(OpErr::InvalidReserve(format!(
+            "no room left for {} ({} words)",
+            name, size
+        )))
+    }
+
+    pub fn place(&mut self, name: &str, start: usize, size: usize) -> Result<(), OpErr> {
+        if start < IO_END
+            || start
+                .checked_add(size)
+                .map_or(true, |end| end > MEMORY_SIZE)
+        {
+            return Err(OpErr::InvalidReserve(format!(
+                "{} at {:#06x} isn't inside {:#06x}..{:#06x}",
+                name, start, IO_END, MEMORY_SIZE
+            )));
+        }
+        if let Some(region) = self.overlapping(start, size) {
+            return Err(OpErr::InvalidReserve(format!(
+                "{} at {:#06x}..{:#06x} overlaps {} at {:#06x}..{:#06x}",
+                name,
+                start,
+                start + size,
+                region.name,
+                region.start,
+                region.end()
+            )));
+        }
+        self.insert(Region {
+            name: name.to_string(),
+            start,
+            size,
+            fixed: true,
+        });
+        Ok(())
+    }
+
+    // Every reservation and the gaps between them, by address
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        let used: usize = self.regions.iter().map(|r| r.size).sum();
+        let _ = writeln!(
+            out,
+            "Memory layout: {} words reserved, {} free",
+            used,
+            MEMORY_SIZE - IO_END - used
+        );
+        let mut at = IO_END;
+        let gap = |out: &mut String, from: usize, to: usize| {
+            if to > from {
+                let _ = writeln!(
+                    out,
+                    "  {:#06x}..{:#06x}  {:<24} {}",
+                    from,
+                    to,
+                    "(free)",
+                    to - from
+                );
+            }
+        };
+        for r in self.regions.iter() {
+            gap(&mut out, at, r.start);
+            let _ = writeln!(
+                out,
+                "  {:#06x}..{:#06x}  {:<24} {}{}",
+                r.start,
+                r.end(),
+                r.name,
+                r.size,
+                if r.fixed { ", fixed" } else { "" }
+            );
+            at = r.end();
+        }
+        gap(&mut out, at, MEMORY_SIZE);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::assemble;
+
+    #[test]
+    fn test_regions() {
+        let mut regions = Regions::default();
+        regions.place("SCREEN", 0x200, 0x100).unwrap();
+        assert_eq!(regions.reserve("A", 0x80, 1), Ok(0x100));
+        assert_eq!(regions.reserve("B", 0x90, 1), Ok(0x300));
+        assert_eq!(regions.reserve("C", 0x10, 0x100), Ok(0x400));
+        assert_eq!(regions.reserve("D", 0x10, 1), Ok(0x180));
+        assert_eq!(
+            regions.place("E", 0x1f0, 0x20).unwrap_err().to_string(),
+            "E at 0x01f0..0x0210 overlaps SCREEN at 0x0200..0x0300"
+        );
+        assert!(regions.place("F", 0xff, 1).is_err());
+        assert!(regions.place("G", 0xffff, 2).is_err());
+        assert!(regions.reserve("H", MEMORY_SIZE, 1).is_err());
+        assert!(regions.reserve("H", usize::MAX, 1).is_err());
+        assert!(regions.reserve("H", 1, usize::MAX).is_err());
+        assert!(regions.place("H", usize::MAX, 2).is_err());
+        let text = regions.to_text();
+        assert!(text.starts_with("Memory layout: 560 words reserved, 64720 free\n"));
+        assert!(text.contains("  0x0180..0x0190  D                        16\n"));
+        assert!(text.contains("  0x0190..0x0200  (free)                   112\n"));
+        assert!(text.contains("  0x0200..0x0300  SCREEN                   256, fixed\n"));
+        assert!(text.ends_with("  0x0410..0x10000  (free)                   64496\n"));
+
+        let program = assemble(
+            ".struct Point\n.field x\n.field y\n.endstruct
+.reserve SPRITES, 36, at=0x200
+.reserve POINTS, Point.SIZE
+.reserve TABLE, 0x100, 0x100
+li x5, POINTS_END
+",
+        )
+        .unwrap();
+        assert_eq!(program.constants["SPRITES"], 512.0);
+        assert_eq!(program.constants["SPRITES_END"], 548.0);
+        assert_eq!(program.constants["POINTS"], 256.0);
+        assert_eq!(program.constants["TABLE"], 768.0);
+        assert_eq!(program.ops[0].imm, 258.0);
+        assert_eq!(program.regions.regions.len(), 3);
+
+        for bad in [
+            ".reserve A",
+            ".reserve A, 0",
+            ".reserve A, 1, 0",
+            ".reserve A, 1.5",
+            ".reserve A, 1\n.reserve A, 1",
+            "const A = 1\n.reserve A, 1",
+            ".reserve A, 0x10, at=0x100\n.reserve B, 1, at=0x10f",
+            ".reserve A, 1e20",
+            ".reserve A, 1, 1e20",
+            ".reserve A, 1, at=1e20",
+        ] {
+            assert!(assemble(bad).is_err(), "{}", bad);
+        }
+    }
+}
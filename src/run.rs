@@ -0,0 +1,202 @@
+use asmjr::ops::Op;
+use asmjr::sourcemap::SourceMap;
+use asmjr::symbols::Symbols;
+use asmjr::vm::{CoState, Vm};
+use asmjr::{cartridge, linker, parser};
+use clap::Args;
+use std::fs;
+use std::path::Path;
+
+// exit codes, so scripts can tell failures apart
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_LOAD: i32 = 1;
+pub const EXIT_FAULT: i32 = 2;
+pub const EXIT_TIMEOUT: i32 = 3;
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    /// Assembly source file or .cart cartridge
+    #[clap(value_parser)]
+    program: String,
+
+    /// Number of frames to run
+    #[clap(short, long, value_parser, default_value_t = 60)]
+    frames: u64,
+
+    /// Fail with a timeout if the program hasn't halted after all frames
+    #[clap(long, action)]
+    halt: bool,
+
+    /// Memory range to print afterwards, like 0x200..0x20c (repeatable)
+    #[clap(short, long, value_parser)]
+    mem: Vec<String>,
+
+    /// Ops each core may execute per frame
+    #[clap(long, value_parser)]
+    clock: Option<u64>,
+}
+
+// A game.map.json sidecar next to game.cart
+fn sidecar_source_map(filename: &str) -> Option<SourceMap> {
+    let text = fs::read_to_string(Path::new(filename).with_extension("map.json")).ok()?;
+    SourceMap::from_json(&text).ok()
+}
+
+pub struct LoadedProgram {
+    pub ops: Vec<Op>,
+    // debug info, when the cartridge has it
+    pub source_map: Option<SourceMap>,
+    pub symbols: Option<Symbols>,
+}
+
+// Cartridges are recognized by their magic, anything else is assembled.
+// The source map comes from the cartridge or its sidecar, if there is one.
+pub fn load_program(filename: &str) -> Result<LoadedProgram, String> {
+    let data = fs::read(filename).map_err(|e| format!("Failed to read {}: {}", filename, e))?;
+    if cartridge::is_cartridge(&data) {
+        let cart = cartridge::unpack_cartridge(&data).map_err(|e| e.to_string())?;
+        return Ok(LoadedProgram {
+            ops: cartridge::deserialize_ops(&cart.program).map_err(|e| e.to_string())?,
+            source_map: match cart.source_map {
+                Some(map) => Some(SourceMap::from_proto(&map)),
+                None => sidecar_source_map(filename),
+            },
+            symbols: cart.symbols.map(|symbols| Symbols::from_proto(&symbols)),
+        });
+    }
+    if linker::is_object(&data) {
+        return Err(format!(
+            "{} is an object file, link it into a cartridge first",
+            filename
+        ));
+    }
+    let src = String::from_utf8(data).map_err(|_| format!("{} is not valid UTF-8", filename))?;
+    let program = parser::assemble_file(&src, filename).map_err(|e| e.to_string())?;
+    if let Some(reason) = linker::unlinked(&program) {
+        return Err(reason);
+    }
+    Ok(LoadedProgram {
+        source_map: Some(SourceMap::from_program(&program)),
+        symbols: Some(Symbols::from_program(&program)),
+        ops: program.ops,
+    })
+}
+
+pub fn parse_range(range: &str) -> Result<(usize, usize), String> {
+    let (start, end) = range
+        .split_once("..")
+        .ok_or_else(|| format!("Memory range \"{}\" should look like START..END", range))?;
+    let start = parse_int::parse::<usize>(start.trim())
+        .map_err(|_| format!("Bad memory range start \"{}\"", start))?;
+    let end = parse_int::parse::<usize>(end.trim())
+        .map_err(|_| format!("Bad memory range end \"{}\"", end))?;
+    if start > end {
+        return Err(format!("Memory range \"{}\" is backwards", range));
+    }
+    Ok((start, end))
+}
+
+pub fn print_registers(vm: &Vm) {
+    for core in vm.cores.iter() {
+        for co in core.coroutines.iter() {
+            let state = match co.state {
+                CoState::Ready => "ready",
+                CoState::Frame => "waiting for frame",
+                CoState::Suspended => "suspended",
+                CoState::Halted => "halted",
+            };
+            println!(
+                "Core {} coroutine {} (pc {}, {}):",
+                core.id, co.id, co.pc, state
+            );
+            let nonzero: Vec<String> = co
+                .regs
+                .iter()
+                .enumerate()
+                .filter(|(_, v)| **v != 0.0)
+                .map(|(idx, v)| format!("x{} = {}", idx, v))
+                .collect();
+            if nonzero.is_empty() {
+                println!("  (all registers zero)");
+            }
+            for row in nonzero.chunks(4) {
+                println!("  {}", row.join(", "));
+            }
+        }
+    }
+}
+
+pub fn print_memory(vm: &Vm, start: usize, end: usize) {
+    let end = end.min(vm.memory.len());
+    println!("Memory 0x{:04x}..0x{:04x}:", start, end);
+    for row_start in (start..end).step_by(8) {
+        let row_end = (row_start + 8).min(end);
+        let cells: Vec<String> = vm.memory[row_start..row_end]
+            .iter()
+            .map(|v| v.to_string())
+            .collect();
+        println!("  0x{:04x}: {}", row_start, cells.join(" "));
+    }
+}
+
+pub fn run(args: RunArgs) -> i32 {
+    let ranges: Result<Vec<(usize, usize)>, String> =
+        args.mem.iter().map(|r| parse_range(r)).collect();
+    let (program, ranges) = match (load_program(&args.program), ranges) {
+        (Ok(program), Ok(ranges)) => (program, ranges),
+        (Err(e), _) | (_, Err(e)) => {
+            println!("{}", e);
+            return EXIT_LOAD;
+        }
+    };
+
+    let mut vm = Vm::new(program.ops);
+    if let Some(clock) = args.clock {
+        vm.set_clock(clock);
+    }
+
+    let mut code = EXIT_OK;
+    while vm.frame < args.frames && !vm.is_halted() {
+        match vm.run_frame() {
+            Ok(stats) => {
+                let per_core: Vec<String> = stats.ops.iter().map(|n| n.to_string()).collect();
+                println!(
+                    "Frame {}: {} ops [{}]",
+                    stats.frame,
+                    stats.total(),
+                    per_core.join(", ")
+                );
+            }
+            Err(e) => {
+                println!("{}", e);
+                if let Some((label, offset)) =
+                    program.symbols.as_ref().and_then(|s| s.label_at(e.pc))
+                {
+                    println!("  in {}+{}", label, offset);
+                }
+                if let Some(location) = program.source_map.as_ref().and_then(|m| m.describe(e.pc)) {
+                    println!("  at {}", location);
+                }
+                code = EXIT_FAULT;
+                break;
+            }
+        }
+    }
+
+    if code == EXIT_OK {
+        if vm.is_halted() {
+            println!("Halted after {} frames.", vm.frame);
+        } else if args.halt {
+            println!("Timed out: still running after {} frames.", vm.frame);
+            code = EXIT_TIMEOUT;
+        } else {
+            println!("Ran {} frames.", vm.frame);
+        }
+    }
+
+    print_registers(&vm);
+    for (start, end) in ranges {
+        print_memory(&vm, start, end);
+    }
+    code
+}
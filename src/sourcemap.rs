@@ -0,0 +1,208 @@
+use crate::cartridge::cart;
+use crate::parser::{Program, SourceLocation};
+use serde_json::{json, Value};
+use std::vec::Vec;
+
+// Maps each pc back to where it was written, so runtime errors can point at
+// the source. It's stored either next to a cartridge as a .map.json sidecar
+// or embedded in it, and both use 1-based lines and columns:
+//
+// {"version": 1, "files": ["game.asm"], "locations": [
+//   {"pc": 0, "file": 0, "line": 3, "column": 1},
+//   {"pc": 1, "file": 1, "line": 7, "column": 5, "stack": [{"file": 0, "line": 2}]}
+// ]}
+
+pub const SOURCE_MAP_VERSION: u64 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SourceMap {
+    pub files: Vec<String>,
+    // one per op, indexed by pc
+    pub locations: Vec<SourceLocation>,
+}
+
+fn file_name(files: &[String], file: usize) -> &str {
+    match files.get(file).map(|name| name.as_str()) {
+        Some("") | None => "<source>",
+        Some(name) => name,
+    }
+}
+
+fn json_usize(value: &Value, key: &str) -> Result<usize, String> {
+    value[key]
+        .as_u64()
+        .map(|v| v as usize)
+        .ok_or_else(|| format!("Source map entry is missing \"{}\"", key))
+}
+
+// Lines and columns are stored 1-based, but 0 is tolerated
+fn from_one_based(value: usize) -> usize {
+    value.saturating_sub(1)
+}
+
+impl SourceMap {
+    pub fn from_program(program: &Program) -> SourceMap {
+        SourceMap {
+            files: program.files.clone(),
+            locations: program.locations.clone(),
+        }
+    }
+
+    pub fn location(&self, pc: usize) -> Option<&SourceLocation> {
+        self.locations.get(pc)
+    }
+
+    // file:line:column of a pc, followed by where it was included from
+    pub fn describe(&self, pc: usize) -> Option<String> {
+        let loc = self.location(pc)?;
+        let mut text = format!(
+            "{}:{}:{}",
+            file_name(&self.files, loc.file),
+            loc.line + 1,
+            loc.column + 1
+        );
+        for (file, line) in loc.stack.iter().rev() {
+            text += &format!(" (from {}:{})", file_name(&self.files, *file), line + 1);
+        }
+        Some(text)
+    }
+
+    pub fn to_json(&self) -> String {
+        let locations: Vec<Value> = self
+            .locations
+            .iter()
+            .enumerate()
+            .map(|(pc, loc)| {
+                let mut entry = json!({
+                    "pc": pc,
+                    "file": loc.file,
+                    "line": loc.line + 1,
+                    "column": loc.column + 1,
+                });
+                if !loc.stack.is_empty() {
+                    let stack: Vec<Value> = loc
+                        .stack
+                        .iter()
+                        .map(|(file, line)| json!({"file": file, "line": line + 1}))
+                        .collect();
+                    entry["stack"] = json!(stack);
+                }
+                entry
+            })
+            .collect();
+        json!({
+            "version": SOURCE_MAP_VERSION,
+            "files": self.files,
+            "locations": locations,
+        })
+        .to_string()
+    }
+
+    pub fn from_json(text: &str) -> Result<SourceMap, String> {
+        let value: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        if value["version"].as_u64() != Some(SOURCE_MAP_VERSION) {
+            return Err(format!(
+                "Unsupported source map version {}",
+                value["version"]
+            ));
+        }
+        let files = value["files"]
+            .as_array()
+            .ok_or("Source map has no files")?
+            .iter()
+            .map(|file| file.as_str().unwrap_or_default().to_string())
+            .collect();
+        let mut locations = Vec::new();
+        for entry in value["locations"]
+            .as_array()
+            .ok_or("Source map has no locations")?
+        {
+            let mut stack = Vec::new();
+            for site in entry["stack"].as_array().unwrap_or(&Vec::new()) {
+                stack.push((
+                    json_usize(site, "file")?,
+                    from_one_based(json_usize(site, "line")?),
+                ));
+            }
+            if json_usize(entry, "pc")? != locations.len() {
+                return Err("Source map locations are out of order".to_string());
+            }
+            locations.push(SourceLocation {
+                file: json_usize(entry, "file")?,
+                line: from_one_based(json_usize(entry, "line")?),
+                column: from_one_based(json_usize(entry, "column")?),
+                stack,
+            });
+        }
+        Ok(SourceMap { files, locations })
+    }
+
+    pub fn to_proto(&self) -> cart::SourceMap {
+        cart::SourceMap {
+            files: self.files.clone(),
+            locations: self
+                .locations
+                .iter()
+                .map(|loc| cart::SourceLocation {
+                    file: loc.file as u32,
+                    line: loc.line as u32 + 1,
+                    column: loc.column as u32 + 1,
+                    stack: loc
+                        .stack
+                        .iter()
+                        .map(|(file, line)| cart::IncludeSite {
+                            file: *file as u32,
+                            line: *line as u32 + 1,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn from_proto(map: &cart::SourceMap) -> SourceMap {
+        SourceMap {
+            files: map.files.clone(),
+            locations: map
+                .locations
+                .iter()
+                .map(|loc| SourceLocation {
+                    file: loc.file as usize,
+                    line: from_one_based(loc.line as usize),
+                    column: from_one_based(loc.column as usize),
+                    stack: loc
+                        .stack
+                        .iter()
+                        .map(|site| (site.file as usize, from_one_based(site.line as usize)))
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::assemble_file;
+
+    #[test]
+    fn test_source_map() {
+        let program = assemble_file("// setup\nli x1, 2\nLOOP:\n    addi x1, x1, 1\n", "a.asm");
+        let mut map = SourceMap::from_program(&program.unwrap());
+        assert_eq!(map.describe(0), Some("a.asm:2:1".to_string()));
+        assert_eq!(map.describe(1), Some("a.asm:4:5".to_string()));
+        assert_eq!(map.describe(2), None);
+
+        map.files.push("lib.asm".to_string());
+        map.locations[1].file = 1;
+        map.locations[1].stack = vec![(0, 3)];
+        assert_eq!(
+            map.describe(1),
+            Some("lib.asm:4:5 (from a.asm:4)".to_string())
+        );
+        assert_eq!(SourceMap::from_json(&map.to_json()), Ok(map.clone()));
+        assert_eq!(SourceMap::from_proto(&map.to_proto()), map);
+        assert!(SourceMap::from_json("{\"version\": 99}").is_err());
+    }
+}
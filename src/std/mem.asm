@@ -0,0 +1,35 @@
+// asmjr standard library: memory
+//
+// Every std routine takes its arguments in t0, t1, t2... (x5 up), returns
+// results in t0 and goes back with jalr zero, ra, 0. It may overwrite
+// t0..t10 (x5..x15) and nothing else. Routines that call others keep ra and
+// anything else they need on the stack, which grows down from sp, so point
+// sp at free memory before calling them (li sp, 0x10000).
+//
+// Include std files after your own code, where nothing runs into them.
+const STD_VERSION = 1
+
+// Copy t2 cells from t1 to t0. Overlapping ranges are fine as long as t0
+// comes before t1.
+memcpy:
+    bge zero, t2, memcpy.done
+memcpy.loop:
+    load t3, t1, 0
+    store t3, t0, 0
+    addi t0, t0, 1
+    addi t1, t1, 1
+    subi t2, t2, 1
+    blt zero, t2, memcpy.loop
+memcpy.done:
+    jalr zero, ra, 0
+
+// Set t2 cells from t0 on to t1.
+memset:
+    bge zero, t2, memset.done
+memset.loop:
+    store t1, t0, 0
+    addi t0, t0, 1
+    subi t2, t2, 1
+    blt zero, t2, memset.loop
+memset.done:
+    jalr zero, ra, 0
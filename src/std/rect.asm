@@ -0,0 +1,35 @@
+// asmjr standard library: rectangles
+//
+// Calling convention as in std/mem.asm: arguments in t0, t1..., results in
+// t0, return with jalr zero, ra, 0, only t0..t10 overwritten. Needs sp.
+.include <std/mem.asm>
+const STD_VERSION = 1
+
+// Fill a t4 by t5 rectangle at (t2, t3) with t6, in a buffer at t0 with
+// rows t1 cells wide.
+fill_rect:
+    subi sp, sp, 6
+    store ra, sp, 0
+    store t1, sp, 1
+    store t4, sp, 2
+    store t6, sp, 3
+    mul t3, t3, t1
+    add t0, t0, t3
+    add t0, t0, t2
+fill_rect.row:
+    bge zero, t5, fill_rect.done
+    store t0, sp, 4
+    store t5, sp, 5
+    load t1, sp, 3
+    load t2, sp, 2
+    jal ra, memset
+    load t0, sp, 4
+    load t1, sp, 1
+    add t0, t0, t1
+    load t5, sp, 5
+    subi t5, t5, 1
+    jal zero, fill_rect.row
+fill_rect.done:
+    load ra, sp, 0
+    addi sp, sp, 6
+    jalr zero, ra, 0
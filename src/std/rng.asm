@@ -0,0 +1,27 @@
+// asmjr standard library: random numbers
+//
+// Calling convention as in std/mem.asm: arguments in t0, t1..., results in
+// t0, return with jalr zero, ra, 0, only t0..t10 overwritten.
+//
+// A small linear congruential generator, s = (s * 75 + 74) mod 65537. The
+// caller keeps the state, a whole number from 0 to 65536, and passes it in
+// t0 each time. Good enough for games, not for anything else.
+const STD_VERSION = 1
+
+// Next state in t0.
+rand:
+    muli t0, t0, 75
+    addi t0, t0, 74
+    modi t0, t0, 65537
+    jalr zero, ra, 0
+
+// Next state in t0, and a whole number from 0 to t1 - 1 in t1.
+rand_below:
+    muli t0, t0, 75
+    addi t0, t0, 74
+    modi t0, t0, 65537
+    mul t2, t0, t1
+    divi t2, t2, 65537
+    modi t3, t2, 1
+    sub t1, t2, t3
+    jalr zero, ra, 0
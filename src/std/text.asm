@@ -0,0 +1,40 @@
+// asmjr standard library: text
+//
+// Calling convention as in std/mem.asm: arguments in t0, t1..., results in
+// t0, return with jalr zero, ra, 0, only t0..t10 overwritten.
+const STD_VERSION = 1
+
+// Write t0 as decimal text from t1 on, one character per cell, with a "-"
+// in front of negative numbers. Any fraction is dropped. Returns the number
+// of cells written in t0.
+int_to_text:
+    mv t2, t1
+    modi t3, t0, 1
+    sub t0, t0, t3
+    bge t0, zero, int_to_text.positive
+    li t3, "-"
+    store t3, t2, 0
+    addi t2, t2, 1
+    sub t0, zero, t0
+int_to_text.positive:
+    // t3 = place value of the first digit
+    li t3, 1
+int_to_text.scale:
+    muli t4, t3, 10
+    blt t0, t4, int_to_text.digits
+    mv t3, t4
+    jal zero, int_to_text.scale
+int_to_text.digits:
+    li t6, 1
+int_to_text.digit:
+    mod t4, t0, t3
+    sub t5, t0, t4
+    div t5, t5, t3
+    addi t5, t5, "0"
+    store t5, t2, 0
+    addi t2, t2, 1
+    mv t0, t4
+    divi t3, t3, 10
+    bge t3, t6, int_to_text.digit
+    sub t0, t2, t1
+    jalr zero, ra, 0
@@ -0,0 +1,129 @@
+// Routines that ship with the assembler, included with .include <std/...>.
+// Each file sets the STD_VERSION constant, which goes up whenever a
+// routine's arguments or results change.
+
+pub const VERSION: u32 = 1;
+
+const FILES: &[(&str, &str)] = &[
+    ("std/mem.asm", include_str!("std/mem.asm")),
+    ("std/rect.asm", include_str!("std/rect.asm")),
+    ("std/rng.asm", include_str!("std/rng.asm")),
+    ("std/text.asm", include_str!("std/text.asm")),
+];
+
+pub fn file(name: &str) -> Option<&'static str> {
+    FILES
+        .iter()
+        .find(|(file, _)| *file == name)
+        .map(|(_, src)| *src)
+}
+
+pub fn names() -> Vec<&'static str> {
+    FILES.iter().map(|(name, _)| *name).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::assemble;
+    use crate::testing::run_tests;
+
+    const TESTS: &str = r#"
+jal zero, END
+
+; @test memcpy
+li t0, 0x200
+li t1, 7
+li t2, 3
+jal ra, memset
+li t0, 0x300
+li t1, 0x200
+li t2, 4
+jal ra, memcpy
+; @expect mem[0x300..0x305] == 7, 7, 7, 0, 0
+; @expect t0 == 0x304
+
+; @test memset_nothing
+li t0, 0x200
+li t1, 7
+li t2, 0
+jal ra, memset
+; @expect mem[0x200] == 0
+
+; @test int_to_text
+li t0, 1024.75
+li t1, 0x200
+jal ra, int_to_text
+; @expect t0 == 4
+; @expect mem[0x200..0x204] == "1024"
+li t0, -30
+li t1, 0x210
+jal ra, int_to_text
+; @expect t0 == 3
+; @expect mem[0x210..0x213] == "-30"
+li t0, 0
+li t1, 0x220
+jal ra, int_to_text
+; @expect t0 == 1
+; @expect mem[0x220..0x222] == "0", 0
+
+; @test rand
+li t0, 1
+jal ra, rand
+; @expect t0 == 149
+jal ra, rand
+; @expect t0 == 11249
+li t1, 10
+jal ra, rand_below
+; @expect t0 == 57305
+; @expect t1 == 8
+
+; @test fill_rect
+li sp, 0x10000
+li t0, 0x400
+li t1, 4
+li t2, 1
+li t3, 1
+li t4, 2
+li t5, 3
+li t6, 9
+jal ra, fill_rect
+; @expect mem[0x400..0x410] == 0, 0, 0, 0, 0, 9, 9, 0, 0, 9, 9, 0, 0, 9, 9, 0
+; @expect sp == 0x10000
+
+END:
+.include <std/mem.asm>
+.include <std/text.asm>
+.include <std/rng.asm>
+.include <std/rect.asm>
+"#;
+
+    #[test]
+    fn test_stdlib() {
+        for name in names() {
+            let program = assemble(&format!(".include <{}>", name)).unwrap();
+            assert_eq!(program.constants["STD_VERSION"], VERSION as f64, "{}", name);
+        }
+        // rect.asm includes mem.asm itself, so the second include does nothing
+        let program = assemble("nop\n.include <std/rect.asm>\n.include <std/mem.asm>").unwrap();
+        assert_eq!(program.files, vec!["", "std/rect.asm", "std/mem.asm"]);
+        assert_eq!(program.locations[1].file, 2);
+        assert_eq!(program.locations[1].stack, vec![(0, 1), (1, 4)]);
+        assert_eq!(program.lines[1], 1);
+        assert_eq!(program.labels["memset"], 9.0);
+        assert!(assemble(".include <std/nothing.asm>").is_err());
+        // a label of the program's own can't quietly replace a routine's
+        let err = assemble("memset:\nnop\n.include <std/mem.asm>").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Parse error on line 3 [\"memset:\"]: std/mem.asm:27: Label \"memset\" is already defined at line 1"
+        );
+        assert!(assemble("A:\nnop\nA:\nnop").is_err());
+
+        let results = run_tests(TESTS, 100_000).unwrap();
+        for result in results.iter() {
+            assert!(result.passed(), "{}: {:?}", result.name, result.failure);
+        }
+        assert_eq!(results.len(), 5);
+    }
+}
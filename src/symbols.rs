@@ -0,0 +1,252 @@
+use crate::cartridge::cart;
+use crate::parser::{Function, Program};
+use std::vec::Vec;
+
+// Names a program defines, optionally embedded in a cartridge so tools
+// showing a pc or a register can show what it was called in the source.
+// Builtins ($ names, xN, ra...) are left out since every reader knows them.
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Symbols {
+    // sorted by pc
+    pub labels: Vec<(String, usize)>,
+    // sorted by name
+    pub constants: Vec<(String, f64)>,
+    // sorted by register
+    pub aliases: Vec<(String, u8)>,
+    // sorted by pc
+    pub functions: Vec<Function>,
+}
+
+impl Symbols {
+    pub fn from_program(program: &Program) -> Symbols {
+        let mut labels: Vec<(String, usize)> = program
+            .labels
+            .iter()
+            .map(|(name, pc)| (name.clone(), *pc as usize))
+            .collect();
+        labels.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        let mut functions = program.functions.clone();
+        functions.sort_by_key(|f| f.start);
+        Symbols {
+            labels,
+            constants: program
+                .user_constants()
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+            aliases: program
+                .user_aliases()
+                .into_iter()
+                .map(|(name, reg)| (name.to_string(), reg))
+                .collect(),
+            functions,
+        }
+    }
+
+    // Closest label at or before a pc, with the offset from it
+    pub fn label_at(&self, pc: usize) -> Option<(&str, usize)> {
+        let idx = self.labels.partition_point(|(_, at)| *at <= pc);
+        let at = self.labels.get(idx.checked_sub(1)?)?.1;
+        // with several labels on one pc, the first one by name
+        let (name, _) = &self.labels[self.labels.partition_point(|(_, other)| *other < at)];
+        Some((name.as_str(), pc - at))
+    }
+
+    pub fn to_proto(&self) -> cart::Symbols {
+        cart::Symbols {
+            labels: self
+                .labels
+                .iter()
+                .map(|(name, pc)| cart::Label {
+                    name: name.clone(),
+                    pc: *pc as u32,
+                })
+                .collect(),
+            constants: self
+                .constants
+                .iter()
+                .map(|(name, value)| cart::Constant {
+                    name: name.clone(),
+                    value: *value,
+                })
+                .collect(),
+            aliases: self
+                .aliases
+                .iter()
+                .map(|(name, reg)| cart::RegisterAlias {
+                    name: name.clone(),
+                    register: *reg as u32,
+                })
+                .collect(),
+            functions: self.functions.iter().map(Function::to_proto).collect(),
+        }
+    }
+
+    pub fn from_proto(symbols: &cart::Symbols) -> Symbols {
+        Symbols {
+            labels: symbols
+                .labels
+                .iter()
+                .map(|label| (label.name.clone(), label.pc as usize))
+                .collect(),
+            constants: symbols
+                .constants
+                .iter()
+                .map(|constant| (constant.name.clone(), constant.value))
+                .collect(),
+            aliases: symbols
+                .aliases
+                .iter()
+                .map(|alias| (alias.name.clone(), alias.register as u8))
+                .collect(),
+            functions: symbols.functions.iter().map(Function::from_proto).collect(),
+        }
+    }
+}
+
+// Functions are written to object files too
+impl Function {
+    pub fn to_proto(&self) -> cart::Function {
+        cart::Function {
+            name: self.name.clone(),
+            pc: self.start as u32,
+            size: (self.end - self.start) as u32,
+            saves: self.saves.iter().map(|reg| *reg as u32).collect(),
+            registers: self
+                .registers
+                .iter()
+                .map(|(name, reg)| cart::RegisterAlias {
+                    name: name.clone(),
+                    register: *reg as u32,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn from_proto(f: &cart::Function) -> Function {
+        Function {
+            name: f.name.clone(),
+            start: f.pc as usize,
+            end: f.pc as usize + f.size as usize,
+            saves: f.saves.iter().map(|reg| *reg as u8).collect(),
+            registers: f
+                .registers
+                .iter()
+                .map(|alias| (alias.name.clone(), alias.register as u8))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::assemble;
+    use crate::vm::Vm;
+
+    #[test]
+    fn test_symbols() {
+        let program = assemble(
+            "const SPEED = 2.5
+reg theta = x14
+reg t0 = x20
+START:
+li theta, SPEED
+LOOP:
+AGAIN:
+jal zero, LOOP
+",
+        )
+        .unwrap();
+        let symbols = Symbols::from_program(&program);
+        assert_eq!(
+            symbols.labels,
+            vec![
+                ("START".to_string(), 0),
+                ("AGAIN".to_string(), 1),
+                ("LOOP".to_string(), 1)
+            ]
+        );
+        assert_eq!(symbols.constants, vec![("SPEED".to_string(), 2.5)]);
+        assert_eq!(
+            symbols.aliases,
+            vec![("theta".to_string(), 14), ("t0".to_string(), 20)]
+        );
+        assert_eq!(symbols.label_at(0), Some(("START", 0)));
+        assert_eq!(symbols.label_at(5), Some(("AGAIN", 4)));
+        assert_eq!(Symbols::from_proto(&symbols.to_proto()), symbols);
+    }
+
+    #[test]
+    fn test_functions() {
+        let src = "li sp, 0x1000
+li x5, 3
+li x8, 100
+jal ra, SUM
+store x5, zero, 0x300
+store x8, zero, 0x301
+jal zero, END
+.func SUM, saves=x6..x8, ra
+LOOP:
+li x6, 0
+li x8, 0
+AGAIN:
+beq x5, zero, DONE
+add x6, x6, x5
+subi x5, x5, 1
+jal zero, AGAIN
+DONE:
+mv x5, x6
+ret
+.endfunc
+.func LEAF
+ret
+.endfunc
+END:
+LOOP:
+";
+        let program = assemble(src).unwrap();
+        assert_eq!(
+            program.functions,
+            vec![
+                Function {
+                    name: "SUM".to_string(),
+                    start: 7,
+                    end: 25,
+                    saves: vec![6, 7, 8, 1],
+                    registers: vec![]
+                },
+                Function {
+                    name: "LEAF".to_string(),
+                    start: 25,
+                    end: 26,
+                    saves: vec![],
+                    registers: vec![]
+                }
+            ]
+        );
+        assert_eq!(program.labels["SUM.LOOP"], 12.0);
+        assert_eq!(program.labels["LOOP"], 26.0);
+        let symbols = Symbols::from_program(&program);
+        assert_eq!(Symbols::from_proto(&symbols.to_proto()), symbols);
+
+        let mut vm = Vm::new(program.ops.clone());
+        while !vm.is_halted() {
+            vm.run_frame().unwrap();
+        }
+        assert_eq!(vm.memory[0x300..0x302], [6.0, 100.0]);
+
+        for bad in [
+            ".func F\nret\n",
+            "ret\n",
+            ".endfunc\n",
+            ".func F\n.func G\n.endfunc\n.endfunc\n",
+            ".func F, x5\n.endfunc\n",
+            ".func F, saves=x5..t2\n.endfunc\n",
+            ".func F, saves=sp\n.endfunc\n",
+        ] {
+            assert!(assemble(bad).is_err(), "{}", bad);
+        }
+    }
+}
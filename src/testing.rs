@@ -0,0 +1,360 @@
+use crate::ops::{parse_immediate, parse_register, OpErr};
+use crate::parser::{assemble, ParseErr, Program};
+use crate::vm::{CoState, Coroutine, Vm, MEMORY_SIZE};
+use std::vec::Vec;
+
+// Unit tests are written as annotations in assembly comments:
+//
+// ; @test add_numbers
+// li x5, 40
+// addi x5, x5, 2
+// ; @expect x5 == 42
+// ; @expect mem[0x200..0x203] == 1, 2, 3
+//
+// A test starts running at the first op after its @test line, on a fresh
+// machine. Each @expect is checked the first time the test's main coroutine
+// reaches the op following it, so setup code can jal into shared routines.
+
+pub const DEFAULT_STEP_BUDGET: u64 = 1_000_000;
+
+enum Target {
+    Register(u8),
+    // [start, end) range of memory cells
+    Memory(usize, usize),
+}
+
+struct Expect {
+    line: usize,
+    text: String,
+    target: Target,
+    values: Vec<f64>,
+}
+
+struct TestCase {
+    name: String,
+    line: usize,
+    expects: Vec<Expect>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestFailure {
+    // 0-based source line the failure is reported against
+    pub line: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    pub name: String,
+    pub line: usize,
+    pub failure: Option<TestFailure>,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+// The text of an @annotation in a whole-line comment, without the @
+fn annotation(line: &str) -> Option<&str> {
+    let line = line.trim_start();
+    let body = ["//", "%", "#", ";"]
+        .iter()
+        .find_map(|marker| line.strip_prefix(marker))?;
+    body.trim().strip_prefix('@')
+}
+
+fn parse_values(text: &str, program: &Program) -> Result<Vec<f64>, OpErr> {
+    text.split(',')
+        .map(|token| parse_immediate(token.trim(), 0, false, &program.constants))
+        .collect()
+}
+
+// A string literal compared against a memory range is one cell per character
+fn string_cells(text: &str) -> Option<Vec<f64>> {
+    let inner = text.strip_prefix('"')?.strip_suffix('"')?;
+    let mut cells = Vec::new();
+    let mut escaped = false;
+    for c in inner.bytes() {
+        if !escaped && c == b'\\' {
+            escaped = true;
+            continue;
+        }
+        escaped = false;
+        cells.push(c as f64);
+    }
+    Some(cells)
+}
+
+fn parse_expect(text: &str, line: usize, program: &Program) -> Result<Expect, String> {
+    let (lhs, rhs) = text
+        .split_once("==")
+        .ok_or_else(|| "Expectation should look like TARGET == VALUE".to_string())?;
+    let (lhs, rhs) = (lhs.trim(), rhs.trim());
+
+    let target = match lhs.strip_prefix("mem[").and_then(|r| r.strip_suffix(']')) {
+        Some(range) => {
+            // up to and including last, which for the end of a range is
+            // just past the top of memory
+            let address = |token: &str, last: usize| {
+                let v = parse_immediate(token.trim(), 0, false, &program.constants)
+                    .map_err(|e| e.to_string())?;
+                if v < 0.0 || v.fract() != 0.0 || v > last as f64 {
+                    return Err(format!("{} isn't an address in memory", token.trim()));
+                }
+                Ok(v as usize)
+            };
+            match range.split_once("..") {
+                Some((start, end)) => {
+                    let (start, end) = (address(start, MEMORY_SIZE)?, address(end, MEMORY_SIZE)?);
+                    if end < start {
+                        return Err(format!("mem[{}] ends before it starts", range));
+                    }
+                    Target::Memory(start, end)
+                }
+                None => {
+                    let start = address(range, MEMORY_SIZE - 1)?;
+                    Target::Memory(start, start + 1)
+                }
+            }
+        }
+        None => Target::Register(parse_register(lhs, &program.aliases).map_err(|e| e.to_string())?),
+    };
+
+    let values = match (&target, string_cells(rhs)) {
+        (Target::Memory(start, end), Some(cells)) if end - start != 1 => cells,
+        _ => parse_values(rhs, program).map_err(|e| e.to_string())?,
+    };
+    let expected_len = match target {
+        Target::Register(_) => 1,
+        Target::Memory(start, end) => end.saturating_sub(start),
+    };
+    if values.len() != expected_len {
+        return Err(format!(
+            "Expected {} values but {} were given",
+            expected_len,
+            values.len()
+        ));
+    }
+
+    Ok(Expect {
+        line,
+        text: format!("{} == {}", lhs, rhs),
+        target,
+        values,
+    })
+}
+
+fn find_tests(src: &str, program: &Program) -> Result<Vec<TestCase>, ParseErr> {
+    let mut tests: Vec<TestCase> = Vec::new();
+    for (linepos, line) in src.lines().enumerate() {
+        let err = |msg: String| ParseErr::Line(linepos, line.trim().to_string(), msg);
+        let body = match annotation(line) {
+            Some(body) => body,
+            None => continue,
+        };
+        let (kind, rest) = body.split_once(char::is_whitespace).unwrap_or((body, ""));
+        match kind {
+            "test" => tests.push(TestCase {
+                name: rest.trim().to_string(),
+                line: linepos,
+                expects: Vec::new(),
+            }),
+            "expect" => {
+                let expect = parse_expect(rest, linepos, program).map_err(err)?;
+                match tests.last_mut() {
+                    Some(test) => test.expects.push(expect),
+                    None => return Err(err("@expect outside of a @test".to_string())),
+                }
+            }
+            // other tools are free to use their own annotations
+            _ => {}
+        }
+    }
+    for test in tests.iter() {
+        if test.expects.is_empty() {
+            let line = src.lines().nth(test.line).unwrap_or_default();
+            return Err(ParseErr::Line(
+                test.line,
+                line.trim().to_string(),
+                format!("Test {} has no @expect", test.name),
+            ));
+        }
+    }
+    Ok(tests)
+}
+
+// The test's own coroutine, unless something killed it
+fn main_coroutine(vm: &Vm) -> Option<&Coroutine> {
+    vm.cores[0].coroutines.iter().find(|co| co.id == 0)
+}
+
+fn check(vm: &Vm, expect: &Expect) -> Option<TestFailure> {
+    let actual: Vec<f64> = match expect.target {
+        Target::Register(reg) => {
+            vec![main_coroutine(vm)?.regs[reg as usize]]
+        }
+        Target::Memory(start, end) => match vm.memory.get(start..end) {
+            Some(cells) => cells.to_vec(),
+            None => {
+                return Some(TestFailure {
+                    line: expect.line,
+                    message: format!("Memory range {}..{} is out of bounds", start, end),
+                })
+            }
+        },
+    };
+    if actual == expect.values {
+        return None;
+    }
+    let actual: Vec<String> = actual.iter().map(|v| v.to_string()).collect();
+    Some(TestFailure {
+        line: expect.line,
+        message: format!("expected {}, got {}", expect.text, actual.join(", ")),
+    })
+}
+
+fn run_test(program: &Program, test: &TestCase, budget: u64) -> Option<TestFailure> {
+    let mut vm = Vm::with_entry(program.ops.clone(), program.pc_at_line(test.line));
+    let mut steps: u64 = 0;
+    for expect in test.expects.iter() {
+        let checkpoint = program.pc_at_line(expect.line);
+        loop {
+            let fail = |message: String| {
+                Some(TestFailure {
+                    line: expect.line,
+                    message,
+                })
+            };
+            match main_coroutine(&vm) {
+                Some(co) if co.pc == checkpoint => break,
+                Some(co) if co.state != CoState::Halted => {}
+                _ => return fail("Halted before reaching this expectation".to_string()),
+            }
+            if steps >= budget {
+                return fail(format!("Step budget of {} exhausted", budget));
+            }
+            steps += 1;
+            match vm.step() {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    vm.end_frame();
+                }
+                Err(e) => {
+                    return Some(TestFailure {
+                        line: program.lines.get(e.pc).copied().unwrap_or(expect.line),
+                        message: e.to_string(),
+                    })
+                }
+            }
+        }
+        if let Some(failure) = check(&vm, expect) {
+            return Some(failure);
+        }
+    }
+    None
+}
+
+pub fn run_tests(src: &str, budget: u64) -> Result<Vec<TestResult>, ParseErr> {
+    let program = assemble(src)?;
+    let tests = find_tests(src, &program)?;
+    Ok(tests
+        .iter()
+        .map(|test| TestResult {
+            name: test.name.clone(),
+            line: test.line,
+            failure: run_test(&program, test, budget),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: &str = r#"
+const BUF = 0x200
+jal zero, END
+
+// x5 = x5 * 2, via a loop of additions
+DOUBLE:
+mv x6, x5
+add x5, x5, x6
+jalr zero, ra, 0
+
+; @test doubles
+li x5, 21
+jal ra, DOUBLE
+; @expect x5 == 42
+jal ra, DOUBLE
+// @expect x5 == 84
+
+# @test memory
+li x5, "h"
+store x5, zero, BUF
+li x5, "i"
+store x5, zero, 0x201
+% @expect mem[0x200..0x202] == "hi"
+
+; @test wrong
+reg theta = x14
+li theta, 1.5
+; @expect theta == 2
+
+; @test crashes
+jal zero, -100
+; @expect x5 == 0
+
+; @test spins
+jal zero, 0
+; @expect x5 == 0
+END:
+"#;
+
+    #[test]
+    fn test_runner() {
+        let results = run_tests(SRC, 1000).unwrap();
+        let summary: Vec<(&str, bool)> = results
+            .iter()
+            .map(|r| (r.name.as_str(), r.passed()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("doubles", true),
+                ("memory", true),
+                ("wrong", false),
+                ("crashes", false),
+                ("spins", false)
+            ]
+        );
+        let failure = results[2].failure.as_ref().unwrap();
+        assert_eq!(failure.line, 27);
+        assert_eq!(failure.message, "expected theta == 2, got 1.5");
+        assert_eq!(results[3].failure.as_ref().unwrap().line, 30);
+        assert!(results[4]
+            .failure
+            .as_ref()
+            .unwrap()
+            .message
+            .contains("budget"));
+    }
+
+    #[test]
+    fn test_bad_annotations() {
+        assert!(run_tests("; @expect x5 == 1\n", 10).is_err());
+        assert!(run_tests("; @test empty\nnop\n", 10).is_err());
+        assert!(run_tests("; @test t\nnop\n; @expect mem[0..2] == 1\n", 10).is_err());
+        assert!(run_tests("; @test t\nnop\n; @expect bogus == 1\n", 10).is_err());
+        assert!(run_tests(
+            "; @test t\nnop\n; @expect mem[0x205..0x200] == \"ab\"\n",
+            10
+        )
+        .is_err());
+        for bad in ["1e30", "-1", "1.5", "0x10000"] {
+            let src = format!("; @test t\nnop\n; @expect mem[{}] == 0\n", bad);
+            assert!(run_tests(&src, 10).is_err(), "{}", bad);
+        }
+    }
+}
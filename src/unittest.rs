@@ -0,0 +1,56 @@
+use asmjr::testing::{run_tests, DEFAULT_STEP_BUDGET};
+use clap::Args;
+use std::fs::read_to_string;
+
+#[derive(Args, Debug)]
+pub struct TestArgs {
+    /// Assembly source files containing @test annotations
+    #[clap(value_parser, required = true)]
+    sources: Vec<String>,
+
+    /// Ops each test may execute before it is considered hung
+    #[clap(long, value_parser, default_value_t = DEFAULT_STEP_BUDGET)]
+    steps: u64,
+}
+
+pub fn test(args: TestArgs) -> i32 {
+    let (mut passed, mut failed) = (0, 0);
+    for source in args.sources.iter() {
+        let src = match read_to_string(source) {
+            Ok(src) => src,
+            Err(e) => {
+                println!("Failed to read {}: {}", source, e);
+                failed += 1;
+                continue;
+            }
+        };
+        let results = match run_tests(&src, args.steps) {
+            Ok(results) => results,
+            Err(e) => {
+                println!("{}: {}", source, e);
+                failed += 1;
+                continue;
+            }
+        };
+        for result in results {
+            match result.failure {
+                None => {
+                    println!("test {} ... ok", result.name);
+                    passed += 1;
+                }
+                Some(failure) => {
+                    println!("test {} ... FAILED", result.name);
+                    println!("  {}:{}: {}", source, failure.line + 1, failure.message);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", passed, failed);
+    if failed > 0 {
+        1
+    } else {
+        0
+    }
+}
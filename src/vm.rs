@@ -0,0 +1,681 @@
+use crate::ops::{op_name, Op};
+use std::fmt;
+use std::vec::Vec;
+
+// Reference interpreter for ECJR programs.
+//
+// Memory is a flat array of doubles addressed by cell, the same way the
+// $-prefixed memmap constants index it. Every core runs one or more
+// coroutines, each with its own pc and register file. Cores are interleaved
+// one op at a time in core order so that runs are fully deterministic.
+//
+// A frame ends once no core can make progress: every coroutine is waiting
+// (for the next frame or for an xres) or halted, or its core has used up its
+// clock budget for the frame. Hosts drive the machine with step() for single ops
+// or run_frame() for a whole frame at a time.
+
+pub const MEMORY_SIZE: usize = 0x10000;
+pub const REGISTER_COUNT: usize = 256;
+pub const MAX_CORES: usize = 16;
+// ops a core may execute per frame before it is paused until the next one
+pub const DEFAULT_CLOCK: u64 = 100_000;
+
+// conditions for the yield op
+pub const YIELD_NOW: i64 = 0;
+pub const YIELD_FRAME: i64 = 1;
+pub const YIELD_SUSPEND: i64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoState {
+    Ready,
+    // waiting for the next frame boundary
+    Frame,
+    // waiting for another coroutine to xres it
+    Suspended,
+    // ran off the end of the program (kept around so it can be inspected)
+    Halted,
+}
+
+#[derive(Debug, Clone)]
+pub struct Coroutine {
+    pub id: i64,
+    pub pc: usize,
+    pub regs: Vec<f64>,
+    pub state: CoState,
+    // the yield's rd, which gets the frame number once the coroutine wakes
+    wake_reg: u8,
+}
+
+impl Coroutine {
+    fn new(id: i64, pc: usize) -> Coroutine {
+        Coroutine {
+            id,
+            pc,
+            regs: vec![0.0; REGISTER_COUNT],
+            state: CoState::Ready,
+            wake_reg: 0,
+        }
+    }
+
+    fn wake(&mut self, frame: u64) {
+        self.state = CoState::Ready;
+        if self.wake_reg != 0 {
+            self.regs[self.wake_reg as usize] = frame as f64;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Core {
+    pub id: usize,
+    pub coroutines: Vec<Coroutine>,
+    // index into coroutines of the one that runs next
+    pub current: usize,
+    pub clock: u64,
+    pub budget: u64,
+    pub cycles: u64,
+    pub frame_ops: u64,
+}
+
+impl Core {
+    fn new(id: usize, entry: usize, clock: u64) -> Core {
+        Core {
+            id,
+            coroutines: vec![Coroutine::new(0, entry)],
+            current: 0,
+            clock,
+            budget: clock,
+            cycles: 0,
+            frame_ops: 0,
+        }
+    }
+
+    // The next ready coroutine, starting from the current one
+    fn pick(&self) -> Option<usize> {
+        if self.budget == 0 {
+            return None;
+        }
+        let n = self.coroutines.len();
+        (0..n)
+            .map(|offset| (self.current + offset) % n)
+            .find(|idx| self.coroutines[*idx].state == CoState::Ready)
+    }
+
+    fn find(&self, id: i64) -> Option<usize> {
+        self.coroutines.iter().position(|co| co.id == id)
+    }
+
+    fn find_live(&self, id: i64) -> Option<usize> {
+        self.find(id)
+            .filter(|idx| self.coroutines[*idx].state != CoState::Halted)
+    }
+
+    fn remove(&mut self, idx: usize) {
+        self.coroutines.remove(idx);
+        if idx < self.current {
+            self.current -= 1;
+        }
+        if self.current >= self.coroutines.len() {
+            self.current = 0;
+        }
+    }
+
+    pub fn coroutine(&self) -> Option<&Coroutine> {
+        self.coroutines.get(self.current)
+    }
+
+    // The coroutine that will run next on this core (or the last one that
+    // did, if nothing is ready)
+    pub fn scheduled(&self) -> Option<&Coroutine> {
+        let idx = self.pick().unwrap_or(self.current);
+        self.coroutines.get(idx)
+    }
+
+    pub fn coroutine_mut(&mut self) -> Option<&mut Coroutine> {
+        self.coroutines.get_mut(self.current)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    PcOutOfBounds(f64),
+    BadOpcode(u8),
+    BadAddress(f64),
+    BadCoreCount(f64),
+    BadYield(f64),
+    CoroutineExists(i64),
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::PcOutOfBounds(pc) => write!(f, "Jump out of program to {}", pc),
+            Fault::BadOpcode(opcode) => write!(f, "Unknown opcode {}", opcode),
+            Fault::BadAddress(addr) => write!(f, "Memory address {} out of range", addr),
+            Fault::BadCoreCount(n) => write!(f, "Cannot configure {} cores", n),
+            Fault::BadYield(cond) => write!(f, "Unknown yield condition {}", cond),
+            Fault::CoroutineExists(id) => write!(f, "Coroutine {} already exists", id),
+            Fault::Unsupported(name) => {
+                write!(f, "Op [{}] is not supported by the reference VM", name)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmError {
+    pub core: usize,
+    pub coroutine: i64,
+    pub pc: usize,
+    pub fault: Fault,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Runtime fault on core {} (coroutine {}) at pc {}: {}",
+            self.core, self.coroutine, self.pc, self.fault
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameStats {
+    pub frame: u64,
+    // ops executed by each core during the frame
+    pub ops: Vec<u64>,
+}
+
+impl FrameStats {
+    pub fn total(&self) -> u64 {
+        self.ops.iter().sum()
+    }
+}
+
+// Scheduler changes are applied after the op's register write and pc update
+enum Action {
+    None,
+    Yield(i64, u8),
+    Spawn(i64, usize, f64),
+    Kill(usize),
+    Resume(usize),
+    Configure(usize),
+}
+
+pub struct Vm {
+    pub program: Vec<Op>,
+    pub memory: Vec<f64>,
+    pub cores: Vec<Core>,
+    pub frame: u64,
+    names: Vec<Option<&'static str>>,
+    next_core: usize,
+    // clock given to cores enabled later by crcfg
+    clock: u64,
+}
+
+fn jump_target(target: f64, proglen: usize) -> Result<usize, Fault> {
+    // landing exactly on the end of the program is a clean halt
+    if target.is_nan() || target < 0.0 || target >= (proglen + 1) as f64 {
+        return Err(Fault::PcOutOfBounds(target));
+    }
+    Ok(target as usize)
+}
+
+fn address(addr: f64) -> Result<usize, Fault> {
+    if addr.is_nan() || addr < 0.0 || addr >= MEMORY_SIZE as f64 {
+        return Err(Fault::BadAddress(addr));
+    }
+    Ok(addr as usize)
+}
+
+fn truth(cond: bool) -> f64 {
+    if cond {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+fn int(v: f64) -> i64 {
+    v as i64
+}
+
+impl Vm {
+    pub fn new(program: Vec<Op>) -> Vm {
+        Vm::with_entry(program, 0)
+    }
+
+    // Start core 0 at an arbitrary pc instead of the top of the program
+    pub fn with_entry(program: Vec<Op>, entry: usize) -> Vm {
+        let names = program.iter().map(|op| op_name(op.op.opcode)).collect();
+        let mut core = Core::new(0, entry, DEFAULT_CLOCK);
+        // already off the end, like a jump there
+        if entry >= program.len() {
+            core.coroutines[0].state = CoState::Halted;
+        }
+        Vm {
+            program,
+            memory: vec![0.0; MEMORY_SIZE],
+            cores: vec![core],
+            frame: 0,
+            names,
+            next_core: 0,
+            clock: DEFAULT_CLOCK,
+        }
+    }
+
+    // Change the per-frame op budget of every core, present and future
+    pub fn set_clock(&mut self, clock: u64) {
+        self.clock = clock;
+        for core in self.cores.iter_mut() {
+            core.clock = clock;
+            core.budget = clock;
+        }
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.cores
+            .iter()
+            .all(|core| core.coroutines.iter().all(|co| co.state == CoState::Halted))
+    }
+
+    // (core, index of coroutine) that the next step() will run
+    fn schedule(&self) -> Option<(usize, usize)> {
+        let ncores = self.cores.len();
+        (0..ncores)
+            .map(|offset| (self.next_core + offset) % ncores)
+            .find_map(|idx| self.cores[idx].pick().map(|co| (idx, co)))
+    }
+
+    // (core, coroutine id, pc) of the op the next step() will execute,
+    // or None if the current frame has nothing left to do
+    pub fn peek(&self) -> Option<(usize, i64, usize)> {
+        let (idx, co) = self.schedule()?;
+        let co = &self.cores[idx].coroutines[co];
+        Some((idx, co.id, co.pc))
+    }
+
+    // Execute a single op on the next core that can run, returning which
+    // core ran or None if the current frame has nothing left to do.
+    pub fn step(&mut self) -> Result<Option<usize>, VmError> {
+        let (idx, current) = match self.schedule() {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+        self.next_core = (idx + 1) % self.cores.len();
+        self.cores[idx].current = current;
+        let co = &self.cores[idx].coroutines[current];
+        let (coroutine, pc) = (co.id, co.pc);
+        self.execute(idx).map_err(|fault| VmError {
+            core: idx,
+            coroutine,
+            pc,
+            fault,
+        })?;
+        Ok(Some(idx))
+    }
+
+    // Finish the current frame and move on to the next one, waking
+    // everything that yielded until the next frame.
+    pub fn end_frame(&mut self) -> FrameStats {
+        let stats = FrameStats {
+            frame: self.frame,
+            ops: self.cores.iter().map(|core| core.frame_ops).collect(),
+        };
+        self.frame += 1;
+        for core in self.cores.iter_mut() {
+            core.budget = core.clock;
+            core.frame_ops = 0;
+            for co in core.coroutines.iter_mut() {
+                if co.state == CoState::Frame {
+                    co.wake(self.frame);
+                }
+            }
+        }
+        stats
+    }
+
+    pub fn run_frame(&mut self) -> Result<FrameStats, VmError> {
+        while self.step()?.is_some() {}
+        Ok(self.end_frame())
+    }
+
+    // 0: core doesn't exist or has halted, 1: has work left this frame,
+    // 2: everything on the core is waiting
+    fn core_status(&self, idx: f64) -> f64 {
+        let core = match self.cores.get(int(idx) as usize) {
+            Some(core) if idx >= 0.0 => core,
+            _ => return 0.0,
+        };
+        let live = core
+            .coroutines
+            .iter()
+            .filter(|co| co.state != CoState::Halted);
+        if live.clone().count() == 0 {
+            0.0
+        } else if core.budget > 0 && live.into_iter().any(|co| co.state == CoState::Ready) {
+            1.0
+        } else {
+            2.0
+        }
+    }
+
+    fn execute(&mut self, core_idx: usize) -> Result<(), Fault> {
+        let proglen = self.program.len();
+        let core = &self.cores[core_idx];
+        let co = &core.coroutines[core.current];
+        let pc = co.pc;
+        let op = &self.program[pc];
+        let name = self.names[pc].ok_or(Fault::BadOpcode(op.op.opcode))?;
+        let (rd, imm) = (op.op.rd, op.imm);
+        let a = co.regs[op.op.rs1 as usize];
+        let b = co.regs[op.op.rs2 as usize];
+        let d = co.regs[rd as usize];
+
+        let mut next = pc as f64 + 1.0;
+        let mut action = Action::None;
+        let result = match name {
+            "yield" => {
+                let cond = int(imm);
+                if !(YIELD_NOW..=YIELD_SUSPEND).contains(&cond) || cond as f64 != imm {
+                    return Err(Fault::BadYield(imm));
+                }
+                action = Action::Yield(cond, rd);
+                None
+            }
+            "spawn" => {
+                let id = int(a);
+                if core.find_live(id).is_some() {
+                    return Err(Fault::CoroutineExists(id));
+                }
+                let target = jump_target(pc as f64 + imm, proglen)?;
+                action = Action::Spawn(id, target, b);
+                None
+            }
+            "xkill" => match core.find_live(int(a + imm)) {
+                Some(idx) => {
+                    action = Action::Kill(idx);
+                    Some(1.0)
+                }
+                None => Some(0.0),
+            },
+            "xres" => match core.find(int(a + imm)) {
+                Some(idx) if core.coroutines[idx].state == CoState::Suspended => {
+                    action = Action::Resume(idx);
+                    Some(1.0)
+                }
+                _ => Some(0.0),
+            },
+            "crid" => Some(core_idx as f64),
+            "crcfg" => {
+                if a.is_nan() || a < 1.0 || a > MAX_CORES as f64 {
+                    return Err(Fault::BadCoreCount(a));
+                }
+                action = Action::Configure(a as usize);
+                Some(self.cores.len() as f64)
+            }
+            "crcnd" => Some(self.core_status(a)),
+            "crclk" => Some(core.clock as f64),
+            "crct" => Some(self.cores.len() as f64),
+            "clk" => Some(core.cycles as f64),
+            "mv" => Some(a),
+            "nop" => None,
+            "add" => Some(a + b),
+            "addi" => Some(a + imm),
+            "sub" => Some(a - b),
+            "subi" => Some(a - imm),
+            "mul" => Some(a * b),
+            "muli" => Some(a * imm),
+            "div" => Some(a / b),
+            "divi" => Some(a / imm),
+            "mod" => Some(a % b),
+            "modi" => Some(a % imm),
+            "pow" => Some(a.powf(b)),
+            "powi" => Some(a.powf(imm)),
+            "min" => Some(a.min(b)),
+            "mini" => Some(a.min(imm)),
+            "max" => Some(a.max(b)),
+            "maxi" => Some(a.max(imm)),
+            "eq" => Some(truth(a == b)),
+            "neq" => Some(truth(a != b)),
+            "geq" => Some(truth(a >= b)),
+            "lt" => Some(truth(a < b)),
+            "and" => Some((int(a) & int(b)) as f64),
+            "andi" => Some((int(a) & int(imm)) as f64),
+            "or" => Some((int(a) | int(b)) as f64),
+            "ori" => Some((int(a) | int(imm)) as f64),
+            "xor" => Some((int(a) ^ int(b)) as f64),
+            "xori" => Some((int(a) ^ int(imm)) as f64),
+            "lsh" => Some(int(a).wrapping_shl(int(b) as u32) as f64),
+            "lshi" => Some(int(a).wrapping_shl(int(imm) as u32) as f64),
+            "rsh" => Some(int(a).wrapping_shr(int(b) as u32) as f64),
+            "rshi" => Some(int(a).wrapping_shr(int(imm) as u32) as f64),
+            "li" => Some(imm),
+            "aipc" => Some(pc as f64 + imm),
+            "jal" => {
+                next = pc as f64 + imm;
+                Some(pc as f64 + 1.0)
+            }
+            "jalr" => {
+                next = a + imm;
+                Some(pc as f64 + 1.0)
+            }
+            "beq" | "bne" | "blt" | "bge" => {
+                let taken = match name {
+                    "beq" => a == b,
+                    "bne" => a != b,
+                    "blt" => a < b,
+                    _ => a >= b,
+                };
+                if taken {
+                    next = pc as f64 + imm;
+                }
+                None
+            }
+            "load" => Some(self.memory[address(a + imm)?]),
+            "store" => {
+                self.memory[address(a + imm)?] = d;
+                None
+            }
+            "cas" => {
+                let addr = address(a)?;
+                let old = self.memory[addr];
+                if old == d {
+                    self.memory[addr] = b;
+                }
+                Some(old)
+            }
+            // memory permissions aren't modeled, so these always succeed
+            "smprm" | "srprm" | "swprm" | "sxprm" => None,
+            "abs" => Some(a.abs()),
+            "sin" => Some(a.sin()),
+            "cos" => Some(a.cos()),
+            "atan" => Some(a.atan2(b)),
+            _ => return Err(Fault::Unsupported(name)),
+        };
+        let next = jump_target(next, proglen)?;
+
+        let frame = self.frame;
+        let core = &mut self.cores[core_idx];
+        core.budget -= 1;
+        core.cycles += 1;
+        core.frame_ops += 1;
+        if name == "crclk" && a >= 1.0 {
+            core.clock = a as u64;
+        }
+        let current = core.current;
+        let co = &mut core.coroutines[current];
+        if let (Some(value), true) = (result, rd != 0) {
+            co.regs[rd as usize] = value;
+        }
+        co.pc = next;
+        if next == proglen {
+            co.state = CoState::Halted;
+        }
+
+        match action {
+            Action::None => {}
+            // a yield that is also the last op halts rather than waits
+            Action::Yield(_, _) if co.state == CoState::Halted => {}
+            Action::Yield(cond, wake_reg) => {
+                co.wake_reg = wake_reg;
+                match cond {
+                    YIELD_NOW => {
+                        co.wake(frame);
+                        // let the next coroutine on this core have a turn
+                        core.current = (current + 1) % core.coroutines.len();
+                    }
+                    YIELD_FRAME => co.state = CoState::Frame,
+                    _ => co.state = CoState::Suspended,
+                }
+            }
+            Action::Spawn(id, target, arg) => {
+                let mut spawned = Coroutine::new(id, target);
+                // the spawn argument is handed over in tp
+                spawned.regs[4] = arg;
+                if target == proglen {
+                    spawned.state = CoState::Halted;
+                }
+                match core.find(id) {
+                    Some(idx) => core.coroutines[idx] = spawned,
+                    None => core.coroutines.push(spawned),
+                }
+            }
+            Action::Kill(idx) => core.remove(idx),
+            Action::Resume(idx) => core.coroutines[idx].wake(frame),
+            Action::Configure(count) => {
+                // newly enabled cores start from the top of the program
+                let ncores = self.cores.len();
+                self.cores.truncate(count);
+                for id in ncores..count {
+                    self.cores.push(Core::new(id, 0, self.clock));
+                }
+                self.next_core %= self.cores.len();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn boot(src: &str) -> Vm {
+        Vm::new(parse(src).unwrap())
+    }
+
+    #[test]
+    fn test_runs_to_halt() {
+        // sum 1..=10 into x5
+        let mut vm = boot("li x6, 10\nLOOP:\nadd x5, x5, x6\nsubi x6, x6, 1\nbne x6, zero, LOOP\n");
+        let stats = vm.run_frame().unwrap();
+        assert!(vm.is_halted());
+        assert_eq!(stats.ops, vec![31]);
+        assert_eq!(vm.cores[0].coroutines[0].state, CoState::Halted);
+        assert_eq!(vm.cores[0].coroutines[0].regs[5], 55.0);
+        assert_eq!(vm.frame, 1);
+
+        // nothing to run at all
+        for mut vm in [
+            boot("// just a comment\n"),
+            Vm::with_entry(parse("nop\n").unwrap(), 1),
+        ] {
+            assert!(vm.is_halted());
+            vm.run_frame().unwrap();
+            assert_eq!(vm.cores[0].coroutines[0].state, CoState::Halted);
+        }
+    }
+
+    #[test]
+    fn test_frame_yield() {
+        let mut vm = boot("LOOP:\naddi x5, x5, 1\nyield x6, 1\njal zero, LOOP\n");
+        assert_eq!(vm.run_frame().unwrap().ops, vec![2]);
+        assert_eq!(vm.run_frame().unwrap().ops, vec![3]);
+        assert_eq!(vm.run_frame().unwrap().ops, vec![3]);
+        let co = vm.cores[0].coroutine().unwrap();
+        assert_eq!(co.regs[5], 3.0);
+        assert_eq!(co.regs[6], 3.0);
+        assert!(!vm.is_halted());
+    }
+
+    #[test]
+    fn test_multicore() {
+        let mut vm = boot(
+            "li x1, 3
+            crcfg zero, x1
+            crid x5
+            store x5, x5, 0x200
+            crct x6
+            store x6, x5, 0x210
+            yield zero, 1
+            ",
+        );
+        let stats = vm.run_frame().unwrap();
+        assert_eq!(vm.cores.len(), 3);
+        assert_eq!(&vm.memory[0x200..0x203], &[0.0, 1.0, 2.0]);
+        assert_eq!(&vm.memory[0x210..0x213], &[3.0, 3.0, 3.0]);
+        assert_eq!(stats.ops, vec![7, 7, 7]);
+        vm.run_frame().unwrap();
+        assert!(vm.is_halted());
+    }
+
+    #[test]
+    fn test_coroutines() {
+        let mut vm = boot(
+            "li x5, 7
+            li x6, 40
+            spawn x5, x6, WORKER
+            yield zero, 0
+            xres x7, x5, 0
+            yield zero, 0
+            jal zero, END
+            WORKER:
+            addi x8, tp, 2
+            store x8, zero, 0x300
+            yield x9, 2
+            store x9, zero, 0x301
+            xkill x10, zero, 7
+            END:
+            ",
+        );
+        vm.run_frame().unwrap();
+        assert!(vm.is_halted());
+        assert_eq!(vm.memory[0x300], 42.0);
+        assert_eq!(vm.memory[0x301], 0.0);
+    }
+
+    #[test]
+    fn test_cas() {
+        let mut vm = boot("li x5, 0x100\nli x7, 9\ncas x6, x5, x7\ncas x6, x5, x7\n");
+        vm.run_frame().unwrap();
+        assert_eq!(vm.memory[0x100], 9.0);
+        assert_eq!(vm.cores.len(), 1);
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut vm = boot("LOOP:\njal zero, LOOP\n");
+        vm.set_clock(50);
+        assert_eq!(vm.run_frame().unwrap().ops, vec![50]);
+        assert_eq!(vm.run_frame().unwrap().ops, vec![50]);
+        assert_eq!(vm.cores[0].cycles, 100);
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut vm = boot("nop\njal zero, 5\n");
+        let err = vm.run_frame().unwrap_err();
+        assert_eq!(err.pc, 1);
+        assert_eq!(err.fault, Fault::PcOutOfBounds(6.0));
+
+        let mut vm = boot("li x5, -1\nload x6, x5, 0\n");
+        assert_eq!(vm.run_frame().unwrap_err().fault, Fault::BadAddress(-1.0));
+
+        let mut vm = boot("yield zero, 7\n");
+        assert_eq!(vm.run_frame().unwrap_err().fault, Fault::BadYield(7.0));
+    }
+}
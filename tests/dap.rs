@@ -0,0 +1,280 @@
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+// A scripted DAP client driving the asmjr-dap binary over its stdio
+
+struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    seq: i64,
+    // events that arrived while waiting for a response
+    events: VecDeque<Value>,
+}
+
+impl Client {
+    fn start() -> Client {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_asmjr-dap"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        Client {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+            seq: 0,
+            events: VecDeque::new(),
+        }
+    }
+
+    fn read(&mut self) -> Value {
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            assert!(
+                self.stdout.read_line(&mut header).unwrap() > 0,
+                "adapter exited"
+            );
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length:") {
+                length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        self.stdout.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    // Send a request and wait for its response body, which must succeed
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        let response = self.try_request(command, arguments);
+        assert_eq!(
+            response["success"], true,
+            "{} failed: {}",
+            command, response
+        );
+        response["body"].clone()
+    }
+
+    fn try_request(&mut self, command: &str, arguments: Value) -> Value {
+        self.seq += 1;
+        let body = json!({
+            "seq": self.seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        })
+        .to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.stdin.flush().unwrap();
+        loop {
+            let message = self.read();
+            if message["type"] == "response" && message["request_seq"] == self.seq {
+                assert_eq!(message["command"], command);
+                return message;
+            }
+            self.events.push_back(message);
+        }
+    }
+
+    // The next event, which must be the given one
+    fn event(&mut self, name: &str) -> Value {
+        let event = match self.events.pop_front() {
+            Some(event) => event,
+            None => self.read(),
+        };
+        assert_eq!(event["event"], name, "unexpected {}", event);
+        event["body"].clone()
+    }
+
+    fn stopped(&mut self, reason: &str) -> i64 {
+        let body = self.event("stopped");
+        assert_eq!(body["reason"], reason, "{}", body);
+        body["threadId"].as_i64().unwrap()
+    }
+
+    fn top_frame(&mut self, thread: i64) -> Value {
+        let trace = self.request("stackTrace", json!({ "threadId": thread }));
+        trace["stackFrames"][0].clone()
+    }
+
+    fn variables(&mut self, reference: &Value) -> Vec<Value> {
+        let body = self.request("variables", json!({ "variablesReference": reference }));
+        body["variables"].as_array().unwrap().clone()
+    }
+
+    fn finish(mut self) {
+        self.request("disconnect", json!({}));
+        assert!(self.child.wait().unwrap().success());
+    }
+}
+
+fn find<'a>(variables: &'a [Value], name: &str) -> &'a Value {
+    variables
+        .iter()
+        .find(|v| v["name"] == name)
+        .unwrap_or_else(|| panic!("no variable {}", name))
+}
+
+fn launch(client: &mut Client, program: &str, stop_on_entry: bool) {
+    let capabilities = client.request("initialize", json!({"adapterID": "asmjr"}));
+    assert_eq!(capabilities["supportsConfigurationDoneRequest"], true);
+    client.request(
+        "launch",
+        json!({"program": program, "stopOnEntry": stop_on_entry}),
+    );
+    client.event("initialized");
+}
+
+#[test]
+fn test_breakpoints_and_variables() {
+    let program = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/dvdlogo.asm");
+    let mut client = Client::start();
+    launch(&mut client, program, false);
+
+    // line 72 is a blank line, so that breakpoint moves to FRAMELOOP's first op
+    let body = client.request(
+        "setBreakpoints",
+        json!({"source": {"path": program}, "breakpoints": [{"line": 72}, {"line": 500}]}),
+    );
+    let breakpoints = body["breakpoints"].as_array().unwrap();
+    assert_eq!(breakpoints[0]["verified"], true);
+    assert_eq!(breakpoints[0]["line"], 74);
+    assert_eq!(breakpoints[1]["verified"], false);
+    client.request("configurationDone", json!({}));
+
+    let thread = client.stopped("breakpoint");
+    let frame = client.top_frame(thread);
+    assert_eq!(frame["name"], "FRAMELOOP");
+    assert_eq!(frame["line"], 74);
+    assert_eq!(frame["source"]["name"], "dvdlogo.asm");
+
+    // one thread per core, since nothing spawns coroutines
+    let threads = client.request("threads", json!({}));
+    let names: Vec<&str> = threads["threads"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|t| t["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            "core 0 coroutine 0",
+            "core 1 coroutine 0",
+            "core 2 coroutine 0"
+        ]
+    );
+
+    let scopes = client.request("scopes", json!({"frameId": frame["id"]}));
+    let scopes = scopes["scopes"].as_array().unwrap().clone();
+    let registers = client.variables(&scopes[0]["variablesReference"]);
+    assert_eq!(registers.len(), 256);
+    // core 1 gets there first, since core 0 does the setup
+    assert_eq!(find(&registers, "x15 (cursprite, t10)")["value"], "524");
+    let memmap = client.variables(&scopes[2]["variablesReference"]);
+    assert_eq!(find(&memmap, "$VIDEO_SPRITE_COUNT")["value"], "3");
+
+    client.request("next", json!({ "threadId": thread }));
+    client.stopped("step");
+    assert_eq!(client.top_frame(thread)["line"], 75);
+    let theta = client.request(
+        "evaluate",
+        json!({"expression": "theta", "frameId": frame["id"]}),
+    );
+    assert_eq!(theta["result"], "0.0246");
+
+    client.request(
+        "setVariable",
+        json!({"variablesReference": scopes[0]["variablesReference"], "name": "theta", "value": "2"}),
+    );
+    let theta = client.request(
+        "evaluate",
+        json!({"expression": "theta", "frameId": frame["id"]}),
+    );
+    assert_eq!(theta["result"], "2");
+
+    // the breakpoint is hit again by the next core to get there
+    client.request("continue", json!({ "threadId": thread }));
+    let next = client.stopped("breakpoint");
+    assert_ne!(next, thread);
+    client.finish();
+}
+
+const SUBROUTINE: &str = "li x5, 1
+jal ra, SUB
+store x5, zero, 0x200
+jal zero, END
+SUB:
+addi x5, x5, 1
+jalr zero, ra, 0
+END:
+";
+
+#[test]
+fn test_stepping_and_watchpoints() {
+    let path = std::env::temp_dir().join(format!("asmjr-dap-{}.asm", std::process::id()));
+    std::fs::write(&path, SUBROUTINE).unwrap();
+    let mut client = Client::start();
+    launch(&mut client, path.to_str().unwrap(), true);
+    client.request("configurationDone", json!({}));
+
+    let thread = client.stopped("entry");
+    client.request("stepIn", json!({ "threadId": thread }));
+    client.stopped("step");
+    client.request("stepIn", json!({ "threadId": thread }));
+    client.stopped("step");
+    // inside SUB, with the caller shown from ra
+    let trace = client.request("stackTrace", json!({ "threadId": thread }));
+    let frames = trace["stackFrames"].as_array().unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0]["name"], "SUB");
+    assert_eq!(frames[0]["line"], 6);
+    assert_eq!(frames[1]["name"], "pc 2 (ra)");
+    assert_eq!(frames[1]["line"], 3);
+
+    client.request("stepOut", json!({ "threadId": thread }));
+    client.stopped("step");
+    assert_eq!(client.top_frame(thread)["line"], 3);
+
+    let info = client.request(
+        "dataBreakpointInfo",
+        json!({"variablesReference": 1, "name": "0x200"}),
+    );
+    assert_eq!(info["dataId"], "512");
+    let body = client.request(
+        "setDataBreakpoints",
+        json!({"breakpoints": [{"dataId": info["dataId"]}]}),
+    );
+    assert_eq!(body["breakpoints"][0]["verified"], true);
+
+    client.request("continue", json!({ "threadId": thread }));
+    let stopped = client.event("stopped");
+    assert_eq!(stopped["reason"], "data breakpoint");
+    assert_eq!(stopped["text"], "0x0200: 0 -> 2");
+
+    client.request("continue", json!({ "threadId": thread }));
+    assert_eq!(client.event("exited")["exitCode"], 0);
+    client.event("terminated");
+    client.finish();
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_bad_launch() {
+    let mut client = Client::start();
+    client.request("initialize", json!({}));
+    let response = client.try_request("launch", json!({"program": "/nonexistent.asm"}));
+    assert_eq!(response["success"], false);
+    assert!(response["message"]
+        .as_str()
+        .unwrap()
+        .contains("Failed to read"));
+    client.finish();
+}